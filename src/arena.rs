@@ -0,0 +1,110 @@
+/// A handle into an `Arena`. The generation tells apart the values that
+/// reuse a slot, so a handle to a freed value never sees its successor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Handle {
+    index: u32,
+    generation: u32,
+}
+
+impl Handle {
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+}
+
+#[derive(Debug)]
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+/// A slab of values with `O(1)` access that reuses freed slots.
+#[derive(Debug)]
+pub struct Arena<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>,
+    len: usize,
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Arena<T> {
+    pub fn new() -> Self {
+        Arena { slots: Vec::new(), free: Vec::new(), len: 0 }
+    }
+
+    pub fn insert(&mut self, value: T) -> Handle {
+        self.len += 1;
+        if let Some(index) = self.free.pop() {
+            let slot = &mut self.slots[index as usize];
+            slot.value = Some(value);
+            return Handle { index, generation: slot.generation }
+        }
+        let index = u32::try_from(self.slots.len()).expect("arena is full");
+        self.slots.push(Slot { generation: 0, value: Some(value) });
+        Handle { index, generation: 0 }
+    }
+
+    pub fn get(&self, handle: Handle) -> Option<&T> {
+        match self.slots.get(handle.index as usize) {
+            Some(slot) if slot.generation == handle.generation => slot.value.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, handle: Handle) -> Option<&mut T> {
+        match self.slots.get_mut(handle.index as usize) {
+            Some(slot) if slot.generation == handle.generation => slot.value.as_mut(),
+            _ => None,
+        }
+    }
+
+    pub fn remove(&mut self, handle: Handle) -> Option<T> {
+        let slot = self.slots.get_mut(handle.index as usize)?;
+        if slot.generation != handle.generation {
+            return None
+        }
+        let value = slot.value.take()?;
+        // a slot whose generation would wrap around is retired instead of reused
+        if let Some(generation) = slot.generation.checked_add(1) {
+            slot.generation = generation;
+            self.free.push(handle.index);
+        }
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn contains(&self, handle: Handle) -> bool {
+        self.get(handle).is_some()
+    }
+
+    /// The number of live values.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of slots, live or free; every handle's `index` is below it.
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// The handle of the live value in slot `index`, if any.
+    pub fn handle_at(&self, index: usize) -> Option<Handle> {
+        let slot = self.slots.get(index)?;
+        slot.value.as_ref().map(|_| Handle { index: index as u32, generation: slot.generation })
+    }
+
+    pub fn handles(&self) -> impl Iterator<Item = Handle> + '_ {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.value.as_ref().map(|_| Handle { index: index as u32, generation: slot.generation })
+        })
+    }
+}
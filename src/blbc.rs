@@ -0,0 +1,741 @@
+//! The `.blbc` format of precompiled programs.
+//!
+//! A file is a header followed by the top-level prototype, with the ones
+//! nested in it inline. Numbers are little-endian, strings and lists are
+//! prefixed with their length as a `u32`:
+//!
+//! ```text
+//! magic     b"BLBC"
+//! version   u16, see VERSION
+//! flags     u8, bit 0 set when the spans of instructions are included
+//! source    u64, source_hash of the code it was compiled from
+//! checksum  u64, the same hash of everything after the header
+//! ```
+
+use std::{error, fmt, rc::Rc};
+
+use num_bigint::BigInt;
+
+use crate::{
+    bytecode::{BinaryOp, Op, Pattern, Proto, UnaryOp},
+    int::Int,
+    lexer::Span,
+    resolver::Globals,
+    value::{DictKey, Value},
+};
+
+
+pub const MAGIC: &[u8; 4] = b"BLBC";
+/// Bumped whenever the layout or the meaning of the instructions changes,
+/// as files are only read by the version that wrote them.
+pub const VERSION: u16 = 4;
+
+const DEBUG_SPANS: u8 = 1;
+const HEADER_LEN: usize = 4 + 2 + 1 + 8 + 8;
+/// How deep functions and patterns may nest, so that a corrupted file
+/// can't exhaust the native stack.
+const MAX_NESTING: usize = 1000;
+/// How many variables a scope may have, as scopes grow to fit the
+/// largest slot that's assigned.
+const MAX_SLOTS: usize = 1 << 16;
+
+const UNARY_OPS: [UnaryOp; 3] = [UnaryOp::Plus, UnaryOp::Minus, UnaryOp::Not];
+const BINARY_OPS: [BinaryOp; 13] = [
+    BinaryOp::Add, BinaryOp::Sub, BinaryOp::Mul, BinaryOp::Div, BinaryOp::Mod, BinaryOp::Pow,
+    BinaryOp::Greater, BinaryOp::GreaterEq, BinaryOp::Lesser, BinaryOp::LesserEq,
+    BinaryOp::Eq, BinaryOp::NotEq, BinaryOp::Range,
+];
+
+/// A compiled program, as stored in a `.blbc` file.
+#[derive(Debug, Clone)]
+pub struct Program {
+    /// The `source_hash` of the code it was compiled from.
+    pub source_hash: u64,
+    pub proto: Proto,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatError {
+    /// The input doesn't start like a `.blbc` file.
+    NotBytecode,
+    /// The file was written by another version of BlueBat.
+    UnsupportedVersion(u16),
+    Corrupted(String),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::NotBytecode => write!(f, "not a BlueBat bytecode file"),
+            FormatError::UnsupportedVersion(version) =>
+                write!(f, "bytecode version {} isn't supported, expected version {}", version, VERSION),
+            FormatError::Corrupted(message) => write!(f, "corrupted bytecode: {}", message),
+        }
+    }
+}
+
+impl error::Error for FormatError {}
+
+fn corrupted(message: impl Into<String>) -> FormatError {
+    FormatError::Corrupted(message.into())
+}
+
+/// FNV-1a, which unlike the hashers of `std` stays the same across builds.
+pub fn source_hash(source: &str) -> u64 {
+    hash(source.as_bytes())
+}
+
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100_0000_01b3))
+}
+
+impl Program {
+    pub fn has_debug_spans(&self) -> bool {
+        !self.proto.spans.is_empty()
+    }
+
+    /// Drops the spans of all instructions, leaving errors without a location.
+    pub fn strip_debug_spans(&mut self) {
+        fn strip(proto: &mut Proto) {
+            proto.spans.clear();
+            proto.operand_spans.clear();
+            for proto in &mut proto.protos {
+                strip(Rc::make_mut(proto));
+            }
+        }
+        strip(&mut self.proto);
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let debug = self.has_debug_spans();
+        let mut body = Writer { bytes: Vec::new(), debug };
+        body.proto(&self.proto);
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + body.bytes.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.push(if debug { DEBUG_SPANS } else { 0 });
+        bytes.extend_from_slice(&self.source_hash.to_le_bytes());
+        bytes.extend_from_slice(&hash(&body.bytes).to_le_bytes());
+        bytes.extend_from_slice(&body.bytes);
+        bytes
+    }
+
+    /// Reads a program, checking that every operand refers to something
+    /// that exists and that the code keeps its stacks and scopes balanced,
+    /// so that running it can't go out of bounds.
+    pub fn decode(bytes: &[u8]) -> Result<Program, FormatError> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(FormatError::NotBytecode)
+        }
+        let mut header = Reader { bytes: &bytes[MAGIC.len()..], debug: false, nesting: 0 };
+        let version = header.u16()?;
+        if version != VERSION {
+            return Err(FormatError::UnsupportedVersion(version))
+        }
+        let flags = header.u8()?;
+        let source_hash = header.u64()?;
+        let checksum = header.u64()?;
+        if hash(header.bytes) != checksum {
+            return Err(corrupted("checksum mismatch"))
+        }
+
+        let mut body = Reader { bytes: header.bytes, debug: flags & DEBUG_SPANS != 0, nesting: 0 };
+        let proto = body.proto()?;
+        if !body.bytes.is_empty() {
+            return Err(corrupted("trailing bytes after the program"))
+        }
+        verify(&proto, 0)?;
+        Ok(Program { source_hash, proto })
+    }
+
+    /// Points the instructions that address globals at the slots `globals`
+    /// has for their names, adding the ones it's missing.
+    pub fn link(self, globals: &mut Globals) -> Rc<Proto> {
+        fn link(proto: &mut Proto, globals: &mut Globals) {
+            for (at, name) in &proto.globals {
+                let count = globals.len();
+                let slot = *globals.entry(proto.names[*name as usize].to_string()).or_insert(count) as u32;
+                match &mut proto.code[*at as usize] {
+                    Op::GetVar { index, .. } | Op::SetVar { index, .. } | Op::PlaceVar { index, .. } | Op::DeclareVar(index) => *index = slot,
+                    op => unreachable!("{:?} doesn't address a variable", op),
+                }
+            }
+            for proto in &mut proto.protos {
+                link(Rc::make_mut(proto), globals);
+            }
+        }
+        let mut proto = self.proto;
+        link(&mut proto, globals);
+        Rc::new(proto)
+    }
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+    debug: bool,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len as u32);
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.len(value.len());
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    /// Integers of any size, as their two's complement bytes.
+    fn int(&mut self, value: &Int) {
+        let bytes = value.to_big().to_signed_bytes_le();
+        self.len(bytes.len());
+        self.bytes.extend_from_slice(&bytes);
+    }
+
+    fn span(&mut self, span: Span) {
+        for part in [span.start, span.end, span.line, span.col] {
+            self.u32(part);
+        }
+    }
+
+    fn proto(&mut self, proto: &Proto) {
+        self.len(proto.params.len());
+        for param in &proto.params {
+            self.string(param);
+        }
+        match &proto.rest {
+            Some(rest) => {
+                self.u8(1);
+                self.string(rest);
+            },
+            None => self.u8(0),
+        }
+        match proto.locals {
+            Some(count) => {
+                self.u8(1);
+                self.u32(count);
+            },
+            None => self.u8(0),
+        }
+        self.len(proto.constants.len());
+        for constant in &proto.constants {
+            self.constant(constant);
+        }
+        self.len(proto.names.len());
+        for name in &proto.names {
+            self.string(name);
+        }
+        self.len(proto.keys.len());
+        for key in &proto.keys {
+            self.key(key);
+        }
+        self.len(proto.patterns.len());
+        for pattern in &proto.patterns {
+            self.pattern(pattern);
+        }
+        self.len(proto.protos.len());
+        for proto in &proto.protos {
+            self.proto(proto);
+        }
+        self.len(proto.globals.len());
+        for (at, name) in &proto.globals {
+            self.u32(*at);
+            self.u32(*name);
+        }
+        self.len(proto.code.len());
+        for op in &proto.code {
+            self.op(*op);
+        }
+        if self.debug {
+            for pc in 0..proto.code.len() {
+                self.span(proto.span(pc).unwrap_or_default());
+            }
+            self.len(proto.operand_spans.len());
+            for (at, left, right) in &proto.operand_spans {
+                self.u32(*at);
+                self.span(*left);
+                self.span(*right);
+            }
+        }
+    }
+
+    fn constant(&mut self, value: &Value) {
+        match value {
+            Value::Null => self.u8(0),
+            Value::Bool(false) => self.u8(1),
+            Value::Bool(true) => self.u8(2),
+            Value::Number(n) => {
+                self.u8(3);
+                self.f64(*n);
+            },
+            Value::String(s) => {
+                self.u8(4);
+                self.string(s);
+            },
+            Value::TypeName(name) => {
+                self.u8(5);
+                self.string(name);
+            },
+            Value::Int(n) => {
+                self.u8(6);
+                self.int(n);
+            },
+            value => unreachable!("{} in a constant pool", value.type_name()),
+        }
+    }
+
+    fn key(&mut self, key: &DictKey) {
+        match key {
+            DictKey::Number(n) => {
+                self.u8(0);
+                self.f64(*n);
+            },
+            DictKey::String(s) => {
+                self.u8(1);
+                self.string(s);
+            },
+            DictKey::Int(n) => {
+                self.u8(2);
+                self.int(n);
+            },
+        }
+    }
+
+    fn pattern(&mut self, pattern: &Pattern) {
+        match pattern {
+            Pattern::Target(n) => {
+                self.u8(0);
+                self.u32(*n);
+            },
+            Pattern::Array(elements) => {
+                self.u8(1);
+                self.len(elements.len());
+                for (spread, pattern) in elements {
+                    self.u8(*spread as u8);
+                    self.pattern(pattern);
+                }
+            },
+            Pattern::Dict(entries) => {
+                self.u8(2);
+                self.len(entries.len());
+                for (key, pattern) in entries {
+                    self.key(key);
+                    self.pattern(pattern);
+                }
+            },
+        }
+    }
+
+    fn op(&mut self, op: Op) {
+        let unary = |op| UNARY_OPS.iter().position(|known| *known == op).unwrap() as u32;
+        let binary = |op| BINARY_OPS.iter().position(|known| *known == op).unwrap() as u32;
+        let (code, operands): (u8, &[u32]) = match op {
+            Op::Const(index) => (0, &[index]),
+            Op::Pop => (1, &[]),
+            Op::PopN(n) => (2, &[n]),
+            Op::Dup => (3, &[]),
+            Op::Unwind(n) => (4, &[n]),
+            Op::GetVar { depth, index, name } => (5, &[depth, index, name]),
+            Op::SetVar { depth, index } => (6, &[depth, index]),
+            Op::DeclareVar(index) => (7, &[index]),
+            Op::Unary(op) => (8, &[unary(op)]),
+            Op::Binary(op) => (9, &[binary(op)]),
+            Op::Cast => (10, &[]),
+            Op::Jump(to) => (11, &[to]),
+            Op::JumpIfFalse(to) => (12, &[to]),
+            Op::JumpIfTrue(to) => (13, &[to]),
+            Op::EnterScope => (14, &[]),
+            Op::LeaveScope => (15, &[]),
+            Op::Array(count) => (16, &[count]),
+            Op::Append => (17, &[]),
+            Op::Extend => (18, &[]),
+            Op::Dict { start, count } => (19, &[start, count]),
+            Op::PlaceVar { depth, index, name } => (20, &[depth, index, name]),
+            Op::PlaceValue => (21, &[]),
+            Op::PlaceIndex { assign } => (22, &[assign as u32]),
+            Op::PlaceMember { name, assign } => (23, &[name, assign as u32]),
+            Op::Load => (24, &[]),
+            Op::Store => (25, &[]),
+            Op::Compound(op) => (26, &[binary(op)]),
+            Op::Destructure(index) => (27, &[index]),
+            Op::IterStart => (28, &[]),
+            Op::IterNext(to) => (29, &[to]),
+            Op::Closure(index) => (30, &[index]),
+            Op::Call { argc, name } => (31, &[argc, name]),
+            Op::CallSpread { name } => (32, &[name]),
+            Op::Return => (33, &[]),
+            Op::TailCall { argc, name } => (34, &[argc, name]),
+            Op::GetLocal { index, name } => (35, &[index, name]),
+            Op::SetLocal(index) => (36, &[index]),
+            Op::DeclareLocal(index) => (37, &[index]),
+            Op::ClearLocals { start, count } => (38, &[start, count]),
+            Op::PlaceLocal { index, name } => (39, &[index, name]),
+        };
+        self.u8(code);
+        for operand in operands {
+            self.u32(*operand);
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    debug: bool,
+    nesting: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], FormatError> {
+        if self.bytes.len() < N {
+            return Err(corrupted("unexpected end of input"))
+        }
+        let (taken, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(taken.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, FormatError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, FormatError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, FormatError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, FormatError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn f64(&mut self) -> Result<f64, FormatError> {
+        Ok(f64::from_le_bytes(self.take()?))
+    }
+
+    fn bool(&mut self) -> Result<bool, FormatError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(corrupted(format!("invalid flag {}", other))),
+        }
+    }
+
+    /// Reads `len` items with `item`, which consumes at least a byte each,
+    /// so a corrupted length runs out of input rather than memory.
+    fn list<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T, FormatError>) -> Result<Vec<T>, FormatError> {
+        let len = self.u32()?;
+        let mut items = Vec::new();
+        for _ in 0..len {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    /// Takes as many bytes as the length before them says.
+    fn bytes(&mut self) -> Result<&'a [u8], FormatError> {
+        let len = self.u32()? as usize;
+        if self.bytes.len() < len {
+            return Err(corrupted("unexpected end of input"))
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn string(&mut self) -> Result<String, FormatError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| corrupted("invalid UTF-8 in a string"))
+    }
+
+    fn int(&mut self) -> Result<Int, FormatError> {
+        Ok(Int::from(BigInt::from_signed_bytes_le(self.bytes()?)))
+    }
+
+    fn span(&mut self) -> Result<Span, FormatError> {
+        Ok(Span { start: self.u32()?, end: self.u32()?, line: self.u32()?, col: self.u32()? })
+    }
+
+    fn nested<T>(&mut self, read: impl FnOnce(&mut Self) -> Result<T, FormatError>) -> Result<T, FormatError> {
+        if self.nesting == MAX_NESTING {
+            return Err(corrupted("nested too deeply"))
+        }
+        self.nesting += 1;
+        let result = read(self);
+        self.nesting -= 1;
+        result
+    }
+
+    fn proto(&mut self) -> Result<Proto, FormatError> {
+        let params = self.list(Self::string)?;
+        let rest = match self.bool()? {
+            true => Some(self.string()?),
+            false => None,
+        };
+        let locals = match self.bool()? {
+            true => Some(self.u32()?),
+            false => None,
+        };
+        let constants = self.list(Self::constant)?;
+        let names = self.list(|reader| reader.string().map(Rc::from))?;
+        let keys = self.list(Self::key)?;
+        let patterns = self.list(|this| this.nested(Self::pattern))?;
+        let protos = self.list(|this| this.nested(Self::proto).map(Rc::new))?;
+        let globals = self.list(|this| Ok((this.u32()?, this.u32()?)))?;
+        let code = self.list(Self::op)?;
+        let (spans, operand_spans) = match self.debug {
+            true => (
+                (0..code.len()).map(|_| self.span()).collect::<Result<_, _>>()?,
+                self.list(|this| Ok((this.u32()?, this.span()?, this.span()?)))?,
+            ),
+            false => (Vec::new(), Vec::new()),
+        };
+        let proto = Proto { params, rest, locals, code, spans, operand_spans, constants, names, keys, patterns, protos, globals };
+        check(&proto)?;
+        Ok(proto)
+    }
+
+    fn constant(&mut self) -> Result<Value, FormatError> {
+        Ok(match self.u8()? {
+            0 => Value::Null,
+            1 => Value::Bool(false),
+            2 => Value::Bool(true),
+            3 => Value::Number(self.f64()?),
+            4 => Value::String(self.string()?),
+            5 => Value::TypeName(self.string()?),
+            6 => Value::Int(self.int()?),
+            tag => return Err(corrupted(format!("unknown constant tag {}", tag))),
+        })
+    }
+
+    fn key(&mut self) -> Result<DictKey, FormatError> {
+        match self.u8()? {
+            0 => match self.f64()? {
+                n if n.is_nan() => Err(corrupted("NaN dictionary key")),
+                n => Ok(DictKey::Number(n)),
+            },
+            1 => Ok(DictKey::String(self.string()?)),
+            2 => Ok(DictKey::Int(self.int()?)),
+            tag => Err(corrupted(format!("unknown key tag {}", tag))),
+        }
+    }
+
+    fn pattern(&mut self) -> Result<Pattern, FormatError> {
+        Ok(match self.u8()? {
+            0 => Pattern::Target(self.u32()?),
+            1 => Pattern::Array(self.list(|this| Ok((this.bool()?, this.nested(Self::pattern)?)))?),
+            2 => Pattern::Dict(self.list(|this| Ok((this.key()?, this.nested(Self::pattern)?)))?),
+            tag => return Err(corrupted(format!("unknown pattern tag {}", tag))),
+        })
+    }
+
+    fn op(&mut self) -> Result<Op, FormatError> {
+        let unary = |index: u32| UNARY_OPS.get(index as usize).copied().ok_or_else(|| corrupted(format!("unknown unary operator {}", index)));
+        let binary = |index: u32| BINARY_OPS.get(index as usize).copied().ok_or_else(|| corrupted(format!("unknown binary operator {}", index)));
+        Ok(match self.u8()? {
+            0 => Op::Const(self.u32()?),
+            1 => Op::Pop,
+            2 => Op::PopN(self.u32()?),
+            3 => Op::Dup,
+            4 => Op::Unwind(self.u32()?),
+            5 => Op::GetVar { depth: self.u32()?, index: self.u32()?, name: self.u32()? },
+            6 => Op::SetVar { depth: self.u32()?, index: self.u32()? },
+            7 => Op::DeclareVar(self.u32()?),
+            8 => Op::Unary(unary(self.u32()?)?),
+            9 => Op::Binary(binary(self.u32()?)?),
+            10 => Op::Cast,
+            11 => Op::Jump(self.u32()?),
+            12 => Op::JumpIfFalse(self.u32()?),
+            13 => Op::JumpIfTrue(self.u32()?),
+            14 => Op::EnterScope,
+            15 => Op::LeaveScope,
+            16 => Op::Array(self.u32()?),
+            17 => Op::Append,
+            18 => Op::Extend,
+            19 => Op::Dict { start: self.u32()?, count: self.u32()? },
+            20 => Op::PlaceVar { depth: self.u32()?, index: self.u32()?, name: self.u32()? },
+            21 => Op::PlaceValue,
+            22 => Op::PlaceIndex { assign: self.u32()? != 0 },
+            23 => Op::PlaceMember { name: self.u32()?, assign: self.u32()? != 0 },
+            24 => Op::Load,
+            25 => Op::Store,
+            26 => Op::Compound(binary(self.u32()?)?),
+            27 => Op::Destructure(self.u32()?),
+            28 => Op::IterStart,
+            29 => Op::IterNext(self.u32()?),
+            30 => Op::Closure(self.u32()?),
+            31 => Op::Call { argc: self.u32()?, name: self.u32()? },
+            32 => Op::CallSpread { name: self.u32()? },
+            33 => Op::Return,
+            34 => Op::TailCall { argc: self.u32()?, name: self.u32()? },
+            35 => Op::GetLocal { index: self.u32()?, name: self.u32()? },
+            36 => Op::SetLocal(self.u32()?),
+            37 => Op::DeclareLocal(self.u32()?),
+            38 => Op::ClearLocals { start: self.u32()?, count: self.u32()? },
+            39 => Op::PlaceLocal { index: self.u32()?, name: self.u32()? },
+            code => return Err(corrupted(format!("unknown opcode {}", code))),
+        })
+    }
+}
+
+/// Checks that the operands of `proto` index into its tables, and that
+/// its code can't run off its end.
+fn check(proto: &Proto) -> Result<(), FormatError> {
+    let within = |index: u32, len: usize, what: &str| match (index as usize) < len {
+        true => Ok(()),
+        false => Err(corrupted(format!("{} {} out of range", what, index))),
+    };
+    if proto.code.last() != Some(&Op::Return) {
+        return Err(corrupted("code doesn't end in a return"))
+    }
+    // the variables of the frame, if the function keeps them there
+    let locals = proto.locals.unwrap_or(0) as usize;
+    if let Some(count) = proto.locals {
+        let least = proto.params.len() + proto.rest.is_some() as usize;
+        if (count as usize) < least || count as usize > MAX_SLOTS {
+            return Err(corrupted(format!("{} variables in the frame of a function with {} parameters", count, least)))
+        }
+    }
+    for op in &proto.code {
+        let in_frame = matches!(op, Op::GetLocal { .. } | Op::SetLocal(_) | Op::DeclareLocal(_) | Op::ClearLocals { .. } | Op::PlaceLocal { .. });
+        let in_scope = matches!(op, Op::DeclareVar(_) | Op::EnterScope | Op::LeaveScope | Op::Closure(_));
+        if in_frame && proto.locals.is_none() || in_scope && proto.locals.is_some() {
+            return Err(corrupted(format!("{:?} in a function that keeps its variables {}", op, if in_frame { "in scopes" } else { "in its frame" })))
+        }
+        match *op {
+            Op::GetLocal { index, name } | Op::PlaceLocal { index, name } => {
+                within(index, locals, "local")?;
+                within(name, proto.names.len(), "name")?;
+            },
+            Op::SetLocal(index) | Op::DeclareLocal(index) => within(index, locals, "local")?,
+            Op::ClearLocals { start, count } if start as usize + count as usize > locals =>
+                return Err(corrupted(format!("locals {}..{} out of range", start, start as u64 + count as u64))),
+            Op::Const(index) => within(index, proto.constants.len(), "constant")?,
+            Op::GetVar { index, name, .. } | Op::PlaceVar { index, name, .. } => {
+                within(index, MAX_SLOTS, "variable slot")?;
+                within(name, proto.names.len(), "name")?;
+            },
+            Op::SetVar { index, .. } | Op::DeclareVar(index) => within(index, MAX_SLOTS, "variable slot")?,
+            Op::PlaceMember { name, .. } | Op::Call { name, .. } | Op::CallSpread { name } | Op::TailCall { name, .. } => within(name, proto.names.len(), "name")?,
+            Op::Jump(to) | Op::JumpIfFalse(to) | Op::JumpIfTrue(to) | Op::IterNext(to) => within(to, proto.code.len(), "jump target")?,
+            Op::Dict { start, count } if start as usize + count as usize > proto.keys.len() =>
+                return Err(corrupted(format!("keys {}..{} out of range", start, start as u64 + count as u64))),
+            Op::Destructure(index) => {
+                within(index, proto.patterns.len(), "pattern")?;
+                let pattern = &proto.patterns[index as usize];
+                check_targets(pattern, pattern.targets())?;
+            },
+            Op::Closure(index) => within(index, proto.protos.len(), "function")?,
+            _ => (),
+        }
+    }
+    for (at, name) in &proto.globals {
+        within(*name, proto.names.len(), "name")?;
+        within(*at, proto.code.len(), "instruction")?;
+        if !matches!(proto.code[*at as usize], Op::GetVar { .. } | Op::SetVar { .. } | Op::PlaceVar { .. } | Op::DeclareVar(_)) {
+            return Err(corrupted(format!("instruction {} doesn't address a variable", at)))
+        }
+    }
+    for (at, _, _) in &proto.operand_spans {
+        within(*at, proto.code.len(), "instruction")?;
+    }
+    if !proto.operand_spans.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+        return Err(corrupted("operand spans out of order"))
+    }
+    Ok(())
+}
+
+fn check_targets(pattern: &Pattern, count: usize) -> Result<(), FormatError> {
+    match pattern {
+        Pattern::Target(n) if *n as usize >= count => Err(corrupted(format!("target {} out of range", n))),
+        Pattern::Target(_) => Ok(()),
+        Pattern::Array(elements) => elements.iter().try_for_each(|(_, pattern)| check_targets(pattern, count)),
+        Pattern::Dict(entries) => entries.iter().try_for_each(|(_, pattern)| check_targets(pattern, count)),
+    }
+}
+
+/// The heights of the stacks of a frame and the scopes it entered before
+/// an instruction, which have to be the same whichever way it's reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Heights {
+    values: usize,
+    places: usize,
+    scopes: usize,
+}
+
+/// Follows every path through the code of `proto`, which runs `outer`
+/// scopes below the global one, and then the functions it creates.
+fn verify(proto: &Proto, outer: usize) -> Result<(), FormatError> {
+    // it runs in the scope it was created in rather than a new one
+    let outer = match proto.locals {
+        Some(_) => outer.checked_sub(1).ok_or_else(|| corrupted("the top level keeps its variables in a frame"))?,
+        None => outer,
+    };
+    let mut seen: Vec<Option<Heights>> = vec![None; proto.code.len()];
+    // how deep the scopes functions are created in are, at the least
+    let mut closures: Vec<Option<usize>> = vec![None; proto.protos.len()];
+    let mut pending = vec![(0, Heights::default())];
+    while let Some((pc, heights)) = pending.pop() {
+        match seen[pc] {
+            Some(before) if before == heights => continue,
+            Some(_) => return Err(corrupted(format!("unbalanced stack at instruction {}", pc))),
+            None => seen[pc] = Some(heights),
+        }
+
+        let op = proto.code[pc];
+        let (pops, pushes) = proto.stack_effect(op);
+        let (place_pops, place_pushes) = op.place_effect();
+        if heights.values < pops || heights.places < place_pops {
+            return Err(corrupted(format!("stack underflow at instruction {}", pc)))
+        }
+        let mut next = Heights {
+            values: heights.values - pops + pushes,
+            places: heights.places - place_pops + place_pushes,
+            scopes: heights.scopes,
+        };
+        match op {
+            Op::GetVar { depth, .. } | Op::SetVar { depth, .. } | Op::PlaceVar { depth, .. } if depth as usize > heights.scopes + outer =>
+                return Err(corrupted(format!("variable out of scope at instruction {}", pc))),
+            Op::EnterScope => next.scopes += 1,
+            Op::LeaveScope => match heights.scopes.checked_sub(1) {
+                Some(scopes) => next.scopes = scopes,
+                None => return Err(corrupted(format!("leaving a scope that wasn't entered at instruction {}", pc))),
+            },
+            Op::Closure(index) => {
+                let depth = heights.scopes + outer + 1;
+                let least = &mut closures[index as usize];
+                *least = Some(least.map_or(depth, |least| least.min(depth)));
+            },
+            _ => (),
+        }
+
+        match op {
+            Op::Return => (),
+            Op::Jump(to) => pending.push((to as usize, next)),
+            Op::JumpIfFalse(to) | Op::JumpIfTrue(to) => pending.extend([(to as usize, next), (pc + 1, next)]),
+            // it jumps without pushing an item
+            Op::IterNext(to) => pending.extend([(to as usize, heights), (pc + 1, next)]),
+            _ => pending.push((pc + 1, next)),
+        }
+    }
+
+    for (proto, outer) in proto.protos.iter().zip(closures) {
+        if let Some(outer) = outer {
+            verify(proto, outer)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,288 @@
+use std::{fmt::Write, rc::Rc};
+
+use crate::{lexer::Span, value::{DictKey, Value}};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Plus,
+    Minus,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Greater,
+    GreaterEq,
+    Lesser,
+    LesserEq,
+    Eq,
+    NotEq,
+    Range,
+}
+
+/// One instruction of the stack machine in `vm`.
+///
+/// Besides the operand stack, the machine has a stack of places, the ids
+/// of values that are about to be read, assigned or called as methods.
+/// Variables are addressed by `depth` scopes up and `index` in that scope,
+/// counting only the scopes the compiler kept, or for functions that keep
+/// their variables in their frame, by `index` among those. `name` operands
+/// index into `Proto::names`, jump targets into `Proto::code`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Const(u32),
+    Pop,
+    /// Pops `n` values.
+    PopN(u32),
+    Dup,
+    /// Drops the `n` values under the top one.
+    Unwind(u32),
+    GetVar { depth: u32, index: u32, name: u32 },
+    /// Assigns the top value to a variable, binding it if it isn't yet.
+    SetVar { depth: u32, index: u32 },
+    /// Binds the top value to a new variable in the current scope, as `:=` does.
+    DeclareVar(u32),
+    GetLocal { index: u32, name: u32 },
+    SetLocal(u32),
+    /// Binds the top value to a new variable of the frame, even if the
+    /// old one was moved to the heap.
+    DeclareLocal(u32),
+    /// Unsets `count` variables of the frame, for a scope that starts
+    /// afresh, such as the body of a loop.
+    ClearLocals { start: u32, count: u32 },
+    Unary(UnaryOp),
+    Binary(BinaryOp),
+    /// `as`.
+    Cast,
+    Jump(u32),
+    /// Pops a boolean and jumps if it's false.
+    JumpIfFalse(u32),
+    JumpIfTrue(u32),
+    EnterScope,
+    LeaveScope,
+    /// Pops `count` values into a new array.
+    Array(u32),
+    /// Pops a value and adds it to the array under it.
+    Append,
+    /// Pops an array and adds its elements to the array under it.
+    Extend,
+    /// Pops `count` values into a new dictionary, with the keys
+    /// `Proto::keys[start..start + count]`.
+    Dict { start: u32, count: u32 },
+    PlaceVar { depth: u32, index: u32, name: u32 },
+    /// Moves a variable of the frame to the heap, if it isn't there yet,
+    /// and pushes its place.
+    PlaceLocal { index: u32, name: u32 },
+    /// Pops a value onto the heap as a place of its own.
+    PlaceValue,
+    /// Pops an index and the place of what's indexed, and pushes the place
+    /// of the element. With `assign` set, a missing dictionary key is
+    /// inserted.
+    PlaceIndex { assign: bool },
+    PlaceMember { name: u32, assign: bool },
+    /// Pops a place and pushes its value.
+    Load,
+    /// Pops a place and assigns the top value to it.
+    Store,
+    /// Pops a place and a value, and updates the place with `op` applied
+    /// to both, as `+=` and friends do.
+    Compound(BinaryOp),
+    /// Pops a value and pushes what `Proto::patterns[n]` takes out of it,
+    /// the last target first.
+    Destructure(u32),
+    /// Pops an iterable and pushes what `IterNext` walks through: the
+    /// iterable and the next index.
+    IterStart,
+    /// Pushes the next item of the loop under the two values on top, or
+    /// jumps when there's none left.
+    IterNext(u32),
+    /// Creates a function from `Proto::protos[n]` closing over the current scope.
+    Closure(u32),
+    /// Calls the value under `argc` arguments.
+    Call { argc: u32, name: u32 },
+    /// Calls the value under an array of arguments.
+    CallSpread { name: u32 },
+    /// Calls the value under `argc` arguments like `Call`, except that a
+    /// compiled function takes over the current frame. Comes before a
+    /// `Return`, with the function's scopes left.
+    TailCall { argc: u32, name: u32 },
+    Return,
+}
+
+/// What a destructuring assignment takes apart.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    /// The n-th variable or place assigned to.
+    Target(u32),
+    /// The elements, each with whether it's a `..` spread.
+    Array(Vec<(bool, Pattern)>),
+    Dict(Vec<(DictKey, Pattern)>),
+}
+
+impl Op {
+    /// How many places the instruction takes off the place stack, and how
+    /// many it leaves there.
+    pub fn place_effect(self) -> (usize, usize) {
+        match self {
+            Op::PlaceVar { .. } | Op::PlaceLocal { .. } | Op::PlaceValue => (0, 1),
+            Op::PlaceIndex { .. } | Op::PlaceMember { .. } => (1, 1),
+            Op::Load | Op::Store | Op::Compound(_) => (1, 0),
+            _ => (0, 0),
+        }
+    }
+}
+
+impl Pattern {
+    pub fn targets(&self) -> usize {
+        match self {
+            Pattern::Target(_) => 1,
+            Pattern::Array(elements) => elements.iter().map(|(_, pattern)| pattern.targets()).sum(),
+            Pattern::Dict(entries) => entries.iter().map(|(_, pattern)| pattern.targets()).sum(),
+        }
+    }
+}
+
+/// A compiled function, or the top level of a program.
+#[derive(Debug, Clone, Default)]
+pub struct Proto {
+    /// The parameters, which take the first slots of the function's scope.
+    pub params: Vec<String>,
+    /// The name of the rest parameter, whose slot follows the others.
+    pub rest: Option<String>,
+    /// How many variables the function keeps in its frame instead of in
+    /// scopes, which it does when it creates no functions that could
+    /// capture them. `None` for the top level and functions that do.
+    pub locals: Option<u32>,
+    pub code: Vec<Op>,
+    /// The source span of each instruction, for errors. May be empty.
+    pub spans: Vec<Span>,
+    /// The spans of both operands of `Binary` instructions, by position.
+    pub operand_spans: Vec<(u32, Span, Span)>,
+    pub constants: Vec<Value>,
+    /// Shared so that call frames can name the callee without copying.
+    pub names: Vec<Rc<str>>,
+    pub keys: Vec<DictKey>,
+    pub patterns: Vec<Pattern>,
+    pub protos: Vec<Rc<Proto>>,
+    /// The instructions that address a global slot, with the name of the
+    /// variable, so that the code can be pointed at another interpreter's
+    /// globals.
+    pub globals: Vec<(u32, u32)>,
+}
+
+impl Proto {
+    pub fn span(&self, pc: usize) -> Option<Span> {
+        self.spans.get(pc).copied()
+    }
+
+    pub fn operand_spans(&self, pc: usize) -> Option<(Span, Span)> {
+        let found = self.operand_spans.binary_search_by_key(&(pc as u32), |(at, _, _)| *at).ok()?;
+        let (_, left, right) = self.operand_spans[found];
+        Some((left, right))
+    }
+
+    /// How many values `op` needs on top of the operand stack, and how
+    /// many it leaves in their place when it doesn't jump.
+    pub fn stack_effect(&self, op: Op) -> (usize, usize) {
+        match op {
+            Op::Const(_) | Op::GetVar { .. } | Op::GetLocal { .. } | Op::Closure(_) | Op::Load => (0, 1),
+            Op::Pop | Op::JumpIfFalse(_) | Op::JumpIfTrue(_) | Op::PlaceValue | Op::PlaceIndex { .. } | Op::Return => (1, 0),
+            Op::PopN(n) => (n as usize, 0),
+            Op::Unwind(n) => (n as usize + 1, 1),
+            Op::Dup => (1, 2),
+            Op::SetVar { .. } | Op::DeclareVar(_) | Op::SetLocal(_) | Op::DeclareLocal(_) | Op::Unary(_) | Op::Store | Op::Compound(_) => (1, 1),
+            Op::Jump(_) | Op::EnterScope | Op::LeaveScope | Op::ClearLocals { .. } | Op::PlaceVar { .. } | Op::PlaceLocal { .. } | Op::PlaceMember { .. } => (0, 0),
+            Op::Binary(_) | Op::Cast | Op::Append | Op::Extend | Op::CallSpread { .. } => (2, 1),
+            Op::Array(count) | Op::Dict { start: _, count } => (count as usize, 1),
+            Op::Destructure(n) => (1, self.patterns[n as usize].targets()),
+            Op::IterStart => (1, 2),
+            Op::IterNext(_) => (3, 4),
+            Op::Call { argc, name: _ } | Op::TailCall { argc, name: _ } => (argc as usize + 1, 1),
+        }
+    }
+
+    /// Lists the instructions of the function and the ones defined in it,
+    /// the nested ones named by their path of indices into `protos`.
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "<main>").unwrap();
+        self.disassemble_into("<main>", &mut out);
+        out
+    }
+
+    fn disassemble_into(&self, path: &str, out: &mut String) {
+        for (pc, op) in self.code.iter().enumerate() {
+            let at = self.span(pc).map_or(String::new(), |span| format!("{}:{}", span.line, span.col));
+            let (text, note) = self.describe(*op, path);
+            let line = format!("{:>6} {:>8}  {:<28}{}", pc, at, text, note);
+            writeln!(out, "{}", line.trim_end()).unwrap();
+        }
+        for (i, proto) in self.protos.iter().enumerate() {
+            let path = format!("{}.{}", path, i);
+            let params: Vec<String> = proto.params.iter().cloned().chain(proto.rest.iter().map(|rest| format!("..{}", rest))).collect();
+            let locals = proto.locals.map_or(String::new(), |count| format!(" ({} local(s))", count));
+            writeln!(out, "\n{} |{}|{}", path, params.join(", "), locals).unwrap();
+            proto.disassemble_into(&path, out);
+        }
+    }
+
+    /// An instruction with its operands, and what they refer to.
+    fn describe(&self, op: Op, path: &str) -> (String, String) {
+        let name = |index: u32| self.names.get(index as usize).map(|name| name.to_string()).unwrap_or_default();
+        match op {
+            Op::Const(index) => (format!("Const {}", index), match self.constants.get(index as usize) {
+                Some(Value::String(s)) => format!("{:?}", s),
+                Some(Value::TypeName(name)) => format!("#{}", name),
+                Some(Value::Bool(true)) => "True".to_string(),
+                Some(Value::Bool(false)) => "False".to_string(),
+                // floats keep their point to tell them from integers
+                Some(Value::Number(n)) => format!("{:?}", n),
+                Some(Value::Int(n)) => n.to_string(),
+                _ => "Null".to_string(),
+            }),
+            Op::PopN(n) => (format!("PopN {}", n), String::new()),
+            Op::Unwind(n) => (format!("Unwind {}", n), String::new()),
+            Op::GetVar { depth, index, name: n } => (format!("GetVar {} {}", depth, index), name(n)),
+            Op::PlaceVar { depth, index, name: n } => (format!("PlaceVar {} {}", depth, index), name(n)),
+            Op::SetVar { depth, index } => (format!("SetVar {} {}", depth, index), String::new()),
+            Op::DeclareVar(index) => (format!("DeclareVar {}", index), String::new()),
+            Op::GetLocal { index, name: n } => (format!("GetLocal {}", index), name(n)),
+            Op::PlaceLocal { index, name: n } => (format!("PlaceLocal {}", index), name(n)),
+            Op::SetLocal(index) => (format!("SetLocal {}", index), String::new()),
+            Op::DeclareLocal(index) => (format!("DeclareLocal {}", index), String::new()),
+            Op::ClearLocals { start, count } => (format!("ClearLocals {} {}", start, count), String::new()),
+            Op::Unary(op) => (format!("Unary {:?}", op), String::new()),
+            Op::Binary(op) => (format!("Binary {:?}", op), String::new()),
+            Op::Compound(op) => (format!("Compound {:?}", op), String::new()),
+            Op::Jump(to) => (format!("Jump {}", to), String::new()),
+            Op::JumpIfFalse(to) => (format!("JumpIfFalse {}", to), String::new()),
+            Op::JumpIfTrue(to) => (format!("JumpIfTrue {}", to), String::new()),
+            Op::IterNext(to) => (format!("IterNext {}", to), String::new()),
+            Op::Array(count) => (format!("Array {}", count), String::new()),
+            Op::Dict { start, count } => {
+                let keys = self.keys.iter().skip(start as usize).take(count as usize);
+                let keys: Vec<String> = keys.map(|key| match key {
+                    DictKey::Number(n) => format!("{:?}", n),
+                    DictKey::Int(n) => n.to_string(),
+                    DictKey::String(s) => format!("{:?}", s),
+                }).collect();
+                (format!("Dict {} {}", start, count), keys.join(", "))
+            },
+            Op::PlaceIndex { assign } => (format!("PlaceIndex{}", if assign { " assign" } else { "" }), String::new()),
+            Op::PlaceMember { name: n, assign } => (format!("PlaceMember{}", if assign { " assign" } else { "" }), name(n)),
+            Op::Destructure(index) => (format!("Destructure {}", index), String::new()),
+            Op::Closure(index) => (format!("Closure {}", index), format!("{}.{}", path, index)),
+            Op::Call { argc, name: n } => (format!("Call {}", argc), name(n)),
+            Op::CallSpread { name: n } => ("CallSpread".to_string(), name(n)),
+            Op::TailCall { argc, name: n } => (format!("TailCall {}", argc), name(n)),
+            _ => (format!("{:?}", op), String::new()),
+        }
+    }
+}
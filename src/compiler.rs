@@ -0,0 +1,621 @@
+use std::{collections::HashMap, mem, rc::Rc};
+
+use crate::{
+    bytecode::{BinaryOp, Op, Pattern, Proto, UnaryOp},
+    errors::{BaseError, ErrorCode},
+    interpreter::{STACK_RED_ZONE, STACK_SEGMENT_SIZE},
+    lexer::{Span, Token},
+    parser::{ASTNode, Node},
+    resolver::Slot,
+    value::Value,
+};
+
+
+/// Compiles a resolved program to run in the global scope.
+///
+/// Scopes the interpreter would create but that no variable lives in are
+/// left out, and the depths of the variables are counted without them.
+/// Functions that create no functions keep their variables, and those of
+/// the scopes in them, in their frame.
+pub fn compile(node: &Node) -> Result<Proto, BaseError> {
+    let mut compiler = Compiler { func: Function::default(), scopes: vec![ScopeKind::Kept] };
+    compiler.compile(node)?;
+    compiler.emit(Op::Return, node.span);
+    Ok(compiler.func.proto)
+}
+
+#[derive(Default)]
+struct Function {
+    proto: Proto,
+    names: HashMap<String, u32>,
+    /// Values on the operand stack at this point of the code.
+    height: usize,
+    /// Scopes entered in the function and not left yet.
+    open: usize,
+    /// Variables of the frame in use by the scopes open at this point.
+    locals: u32,
+    loops: Vec<Loop>,
+}
+
+struct Loop {
+    /// The stack height with the loop's last value on top.
+    height: usize,
+    open: usize,
+    continue_at: usize,
+    breaks: Vec<usize>,
+}
+
+/// How a scope the resolver counted exists when the code runs.
+#[derive(Debug, Clone, Copy)]
+enum ScopeKind {
+    /// Created by the call or an `EnterScope`.
+    Kept,
+    /// Left out, as no variable lives in it.
+    Skipped,
+    /// Its variables are the frame's, from this one on.
+    Locals(u32),
+}
+
+struct Compiler {
+    func: Function,
+    /// The scopes the resolver counted, innermost last.
+    scopes: Vec<ScopeKind>,
+}
+
+/// How many slots of a new scope the variables in `node`, which runs
+/// `level` scopes below it, use. 0 if no variable lives in it.
+fn slots_used(node: &Node, level: usize) -> u32 {
+    // this starts as deep down as the node being compiled
+    stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || slots_used_kind(node, level))
+}
+
+fn slots_used_kind(node: &Node, level: usize) -> u32 {
+    match &node.kind {
+        ASTNode::Var { slot, .. } => match slot {
+            Some(slot) if slot.depth == level => slot.index as u32 + 1,
+            _ => 0,
+        },
+        ASTNode::Block { code } => slots_used(code, level + 1),
+        ASTNode::Func { def } => slots_used(&def.code, level + 1),
+        ASTNode::If { conds, if_none } => {
+            let conds = conds.iter().map(|(cond, branch)| slots_used(cond, level).max(slots_used(branch, level + 1)));
+            conds.chain((**if_none).iter().map(|node| slots_used(node, level))).max().unwrap_or(0)
+        },
+        ASTNode::While { cond, code } => slots_used(cond, level).max(slots_used(code, level + 1)),
+        ASTNode::For { pattern, iter, code } => {
+            slots_used(iter, level).max(slots_used(pattern, level + 1)).max(slots_used(code, level + 1))
+        },
+        kind => kind.children().into_iter().map(|child| slots_used(child, level)).max().unwrap_or(0),
+    }
+}
+
+/// Whether `node` creates functions, which could capture the scopes it runs in.
+fn creates_functions(node: &Node) -> bool {
+    stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || match &node.kind {
+        ASTNode::Func { .. } => true,
+        kind => kind.children().into_iter().any(creates_functions),
+    })
+}
+
+fn binary_op(op: &Token) -> Option<BinaryOp> {
+    Some(match op {
+        Token::Plus | Token::PlusEq => BinaryOp::Add,
+        Token::Minus | Token::MinusEq => BinaryOp::Sub,
+        Token::Mult | Token::MultEq => BinaryOp::Mul,
+        Token::Div | Token::DivEq => BinaryOp::Div,
+        Token::Mod | Token::ModEq => BinaryOp::Mod,
+        Token::Pow | Token::PowEq => BinaryOp::Pow,
+        Token::Greater => BinaryOp::Greater,
+        Token::GreaterEq => BinaryOp::GreaterEq,
+        Token::Lesser => BinaryOp::Lesser,
+        Token::LesserEq => BinaryOp::LesserEq,
+        Token::Eq => BinaryOp::Eq,
+        Token::NotEq => BinaryOp::NotEq,
+        Token::Range => BinaryOp::Range,
+        _ => return None,
+    })
+}
+
+fn is_spread(node: &Node) -> bool {
+    matches!(node.kind, ASTNode::Unary { op: Token::Range, .. })
+}
+
+impl Compiler {
+    fn emit(&mut self, op: Op, span: Span) -> usize {
+        let func = &mut self.func;
+        let (pops, pushes) = func.proto.stack_effect(op);
+        func.height = func.height - pops + pushes;
+        func.proto.code.push(op);
+        func.proto.spans.push(span);
+        func.proto.code.len() - 1
+    }
+
+    fn here(&self) -> u32 {
+        self.func.proto.code.len() as u32
+    }
+
+    fn patch(&mut self, at: usize) {
+        let target = self.here();
+        match &mut self.func.proto.code[at] {
+            Op::Jump(to) | Op::JumpIfFalse(to) | Op::JumpIfTrue(to) | Op::IterNext(to) => *to = target,
+            op => unreachable!("patching {:?}", op),
+        }
+    }
+
+    fn constant(&mut self, value: Value) -> u32 {
+        let constants = &mut self.func.proto.constants;
+        let found = constants.iter().position(|constant| match (constant, &value) {
+            (Value::Null, Value::Null) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::String(a), Value::String(b)) | (Value::TypeName(a), Value::TypeName(b)) => a == b,
+            _ => false,
+        });
+        match found {
+            Some(index) => index as u32,
+            None => {
+                constants.push(value);
+                constants.len() as u32 - 1
+            },
+        }
+    }
+
+    fn name(&mut self, name: &str) -> u32 {
+        let func = &mut self.func;
+        *func.names.entry(name.to_string()).or_insert_with(|| {
+            func.proto.names.push(name.into());
+            func.proto.names.len() as u32 - 1
+        })
+    }
+
+    /// The depth of `slot` among the scopes that are created.
+    fn depth(&self, slot: Slot) -> u32 {
+        let crossed = &self.scopes[self.scopes.len() - slot.depth..];
+        crossed.iter().filter(|kind| matches!(kind, ScopeKind::Kept)).count() as u32
+    }
+
+    /// The variable of the frame `slot` is, if its scope is one of the frame's.
+    fn local(&self, slot: Slot) -> Option<u32> {
+        match self.scopes[self.scopes.len() - 1 - slot.depth] {
+            ScopeKind::Locals(start) => Some(start + slot.index as u32),
+            _ => None,
+        }
+    }
+
+    /// Records that the instruction at `at` addresses `slot`, if that's a global.
+    fn global(&mut self, at: usize, slot: Slot, name: &str) {
+        if slot.depth == self.scopes.len() - 1 {
+            let name = self.name(name);
+            self.func.proto.globals.push((at as u32, name));
+        }
+    }
+
+    fn slot(node: &Node, slot: Option<Slot>) -> Result<Slot, BaseError> {
+        slot.ok_or_else(|| BaseError::interpreter(ErrorCode::Internal, "Variable was never resolved").with_span(node.span))
+    }
+
+    /// Compiles `body` in a scope of its own with `slots` variables, which
+    /// is only created if a variable lives in it, and is part of the frame
+    /// if the function keeps its variables there.
+    fn scoped(&mut self, span: Span, slots: u32, body: impl FnOnce(&mut Self) -> Result<(), BaseError>) -> Result<(), BaseError> {
+        if slots == 0 {
+            self.scopes.push(ScopeKind::Skipped);
+            let result = body(self);
+            self.scopes.pop();
+            return result
+        }
+        if let Some(count) = self.func.proto.locals {
+            let start = self.func.locals;
+            self.func.locals += slots;
+            self.func.proto.locals = Some(count.max(self.func.locals));
+            self.scopes.push(ScopeKind::Locals(start));
+            self.emit(Op::ClearLocals { start, count: slots }, span);
+            let result = body(self);
+            self.scopes.pop();
+            self.func.locals = start;
+            return result
+        }
+        self.scopes.push(ScopeKind::Kept);
+        self.emit(Op::EnterScope, span);
+        self.func.open += 1;
+        let result = body(self);
+        self.emit(Op::LeaveScope, span);
+        self.func.open -= 1;
+        self.scopes.pop();
+        result
+    }
+
+    /// Leaves the scopes entered since `open` of them were open, on the
+    /// way out of a loop or function.
+    fn leave_scopes(&mut self, open: usize, span: Span) {
+        for _ in open..self.func.open {
+            self.emit(Op::LeaveScope, span);
+        }
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), BaseError> {
+        stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || self.compile_kind(node))
+    }
+
+    fn compile_kind(&mut self, node: &Node) -> Result<(), BaseError> {
+        let span = node.span;
+        match &node.kind {
+            ASTNode::Value { value } => {
+                let index = self.constant(value.clone());
+                self.emit(Op::Const(index), span);
+            },
+            ASTNode::Var { name, slot } => {
+                let slot = Self::slot(node, *slot)?;
+                let name_index = self.name(name);
+                if let Some(index) = self.local(slot) {
+                    self.emit(Op::GetLocal { index, name: name_index }, span);
+                } else {
+                    let at = self.emit(Op::GetVar { depth: self.depth(slot), index: slot.index as u32, name: name_index }, span);
+                    self.global(at, slot, name);
+                }
+            },
+            ASTNode::StatementList { statements } => {
+                if statements.is_empty() {
+                    let null = self.constant(Value::Null);
+                    self.emit(Op::Const(null), span);
+                }
+                for (i, statement) in statements.iter().enumerate() {
+                    if i > 0 {
+                        self.emit(Op::Pop, span);
+                    }
+                    self.compile(statement)?;
+                }
+            },
+            ASTNode::Unary { op, value } => {
+                self.compile(value)?;
+                let op = match op {
+                    Token::Plus => UnaryOp::Plus,
+                    Token::Minus => UnaryOp::Minus,
+                    Token::Not => UnaryOp::Not,
+                    _ => return Err(BaseError::interpreter(ErrorCode::Internal, "Non '+','-','!' unary operation").with_span(span)),
+                };
+                self.emit(Op::Unary(op), span);
+            },
+            ASTNode::Op { left, op, right } => self.operation(node, left, op, right)?,
+            ASTNode::If { conds, if_none } => {
+                let mut ends = Vec::new();
+                for (cond, branch) in conds {
+                    self.compile(cond)?;
+                    let next = self.emit(Op::JumpIfFalse(0), span);
+                    self.scoped(span, slots_used(branch, 0), |this| this.compile(branch))?;
+                    ends.push(self.emit(Op::Jump(0), span));
+                    self.func.height -= 1;
+                    self.patch(next);
+                }
+                match &**if_none {
+                    Some(node) => self.compile(node)?,
+                    None => {
+                        let null = self.constant(Value::Null);
+                        self.emit(Op::Const(null), span);
+                    },
+                }
+                for end in ends {
+                    self.patch(end);
+                }
+            },
+            ASTNode::While { cond, code } => {
+                let null = self.constant(Value::Null);
+                self.emit(Op::Const(null), span);
+                let top = self.here() as usize;
+                self.compile(cond)?;
+                let exit = self.emit(Op::JumpIfFalse(0), span);
+                let height = self.func.height;
+                self.loop_body(span, top, height, slots_used(code, 0), |this| this.compile(code))?;
+                self.patch(exit);
+            },
+            ASTNode::For { pattern, iter, code } => {
+                self.compile(iter)?;
+                self.emit(Op::IterStart, iter.span);
+                let null = self.constant(Value::Null);
+                self.emit(Op::Const(null), span);
+                let top = self.here() as usize;
+                let height = self.func.height;
+                let exit = self.emit(Op::IterNext(0), span);
+                let slots = slots_used(pattern, 0).max(slots_used(code, 0));
+                self.loop_body(span, top, height, slots, |this| {
+                    this.assign(pattern, span)?;
+                    this.emit(Op::Pop, span);
+                    this.compile(code)
+                })?;
+                self.patch(exit);
+                self.emit(Op::Unwind(2), span);
+            },
+            ASTNode::Break { value } => {
+                let height = self.func.height;
+                match &**value {
+                    Some(value) => self.compile(value)?,
+                    None => {
+                        let null = self.constant(Value::Null);
+                        self.emit(Op::Const(null), span);
+                    },
+                }
+                let Some(target) = self.func.loops.last() else {
+                    return Err(BaseError::interpreter(ErrorCode::InvalidControlFlow, "'break' or 'continue' outside of a loop").with_span(span))
+                };
+                let (loop_height, open) = (target.height, target.open);
+                self.emit(Op::Unwind((self.func.height - loop_height) as u32), span);
+                self.leave_scopes(open, span);
+                let jump = self.emit(Op::Jump(0), span);
+                self.func.loops.last_mut().unwrap().breaks.push(jump);
+                self.func.height = height + 1;
+            },
+            ASTNode::Continue => {
+                let height = self.func.height;
+                let Some(target) = self.func.loops.last() else {
+                    return Err(BaseError::interpreter(ErrorCode::InvalidControlFlow, "'break' or 'continue' outside of a loop").with_span(span))
+                };
+                let (loop_height, open, continue_at) = (target.height, target.open, target.continue_at);
+                self.emit(Op::PopN((height - loop_height) as u32), span);
+                self.leave_scopes(open, span);
+                self.emit(Op::Jump(continue_at as u32), span);
+                self.func.height = height + 1;
+            },
+            ASTNode::Return { value } => {
+                let height = self.func.height;
+                match &**value {
+                    Some(value) => self.compile(value)?,
+                    None => {
+                        let null = self.constant(Value::Null);
+                        self.emit(Op::Const(null), span);
+                    },
+                }
+                self.leave_scopes(0, span);
+                self.emit(Op::Return, span);
+                self.func.height = height + 1;
+            },
+            ASTNode::Block { code } => self.scoped(span, slots_used(code, 0), |this| this.compile(code))?,
+            ASTNode::Func { def } => {
+                let outer = mem::take(&mut self.func);
+                self.func.proto.params = def.arg_names.clone();
+                self.func.proto.rest = def.rest.clone();
+                if creates_functions(&def.code) {
+                    self.scopes.push(ScopeKind::Kept);
+                } else {
+                    let params = (def.arg_names.len() + def.rest.iter().len()) as u32;
+                    self.func.locals = params.max(slots_used(&def.code, 0));
+                    self.func.proto.locals = Some(self.func.locals);
+                    self.scopes.push(ScopeKind::Locals(0));
+                }
+                let result = self.compile(&def.code);
+                self.emit(Op::Return, def.code.span);
+                self.scopes.pop();
+                let func = mem::replace(&mut self.func, outer);
+                result?;
+                self.func.proto.protos.push(Rc::new(func.proto));
+                let index = self.func.proto.protos.len() as u32 - 1;
+                self.emit(Op::Closure(index), span);
+            },
+            ASTNode::Call { base, args, tail } => {
+                let height = self.func.height;
+                self.compile(base)?;
+                let name = match &base.kind {
+                    ASTNode::Var { name, .. } | ASTNode::Member { base: _, name } => self.name(name),
+                    _ => self.name("<anonymous>"),
+                };
+                if args.iter().any(is_spread) {
+                    self.elements(args, span)?;
+                    self.emit(Op::CallSpread { name }, span);
+                } else {
+                    for arg in args {
+                        self.compile(arg)?;
+                    }
+                    if *tail {
+                        self.leave_scopes(0, span);
+                        self.emit(Op::TailCall { argc: args.len() as u32, name }, span);
+                        self.emit(Op::Return, span);
+                        self.func.height = height + 1;
+                    } else {
+                        self.emit(Op::Call { argc: args.len() as u32, name }, span);
+                    }
+                }
+            },
+            ASTNode::Array { values } => {
+                if values.iter().any(is_spread) {
+                    self.elements(values, span)?;
+                } else {
+                    for value in values {
+                        self.compile(value)?;
+                    }
+                    self.emit(Op::Array(values.len() as u32), span);
+                }
+            },
+            ASTNode::Dict { entries } => {
+                for (_, value) in entries {
+                    self.compile(value)?;
+                }
+                // after the values, whose own dictionaries take keys too
+                let start = self.func.proto.keys.len() as u32;
+                self.func.proto.keys.extend(entries.iter().map(|(key, _)| key.clone()));
+                self.emit(Op::Dict { start, count: entries.len() as u32 }, span);
+            },
+            ASTNode::Index { .. } | ASTNode::Member { .. } => {
+                self.place(node, false)?;
+                self.emit(Op::Load, span);
+            },
+        }
+        Ok(())
+    }
+
+    /// Compiles the body of a loop, which runs in a scope of its own every
+    /// iteration. Its value replaces the loop's last one, which is on top
+    /// of the stack at `height`.
+    fn loop_body(&mut self, span: Span, top: usize, height: usize, slots: u32, body: impl FnOnce(&mut Self) -> Result<(), BaseError>) -> Result<(), BaseError> {
+        self.func.loops.push(Loop { height, open: self.func.open, continue_at: top, breaks: Vec::new() });
+        let result = self.scoped(span, slots, |this| {
+            body(this)?;
+            this.emit(Op::Unwind(1), span);
+            Ok(())
+        });
+        self.emit(Op::Jump(top as u32), span);
+        let target = self.func.loops.pop().unwrap();
+        for jump in target.breaks {
+            self.patch(jump);
+        }
+        result
+    }
+
+    /// Compiles elements that may be spread into an array.
+    fn elements(&mut self, values: &[Node], span: Span) -> Result<(), BaseError> {
+        self.emit(Op::Array(0), span);
+        for value in values {
+            match &value.kind {
+                ASTNode::Unary { op: Token::Range, value } => {
+                    self.compile(value)?;
+                    self.emit(Op::Extend, value.span);
+                },
+                _ => {
+                    self.compile(value)?;
+                    self.emit(Op::Append, value.span);
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn operation(&mut self, node: &Node, left: &Node, op: &Token, right: &Node) -> Result<(), BaseError> {
+        let span = node.span;
+        match op {
+            Token::PlusEq | Token::MinusEq | Token::MultEq | Token::DivEq | Token::ModEq | Token::PowEq => {
+                self.compile(right)?;
+                self.place(left, false)?;
+                self.emit(Op::Compound(binary_op(op).unwrap()), span);
+            },
+            Token::Assign => {
+                self.compile(right)?;
+                self.assign(left, span)?;
+            },
+            Token::LocalAssign => {
+                self.compile(right)?;
+                match &left.kind {
+                    ASTNode::Var { name, slot } => {
+                        let slot = Self::slot(left, *slot)?;
+                        if let Some(index) = self.local(slot) {
+                            self.emit(Op::DeclareLocal(index), span);
+                        } else {
+                            let at = self.emit(Op::DeclareVar(slot.index as u32), span);
+                            self.global(at, slot, name);
+                        }
+                    },
+                    _ => return Err(BaseError::interpreter(ErrorCode::InvalidAssignment, "Expected variable name").with_span(span)),
+                }
+            },
+            Token::And | Token::Or => {
+                let short = if let Token::And = op { Op::JumpIfFalse(0) } else { Op::JumpIfTrue(0) };
+                self.compile(left)?;
+                let first = self.emit(short, span);
+                self.compile(right)?;
+                let second = self.emit(short, span);
+                let (long, short) = (self.constant(Value::Bool(*op == Token::And)), self.constant(Value::Bool(*op == Token::Or)));
+                self.emit(Op::Const(long), span);
+                let end = self.emit(Op::Jump(0), span);
+                self.func.height -= 1;
+                self.patch(first);
+                self.patch(second);
+                self.emit(Op::Const(short), span);
+                self.patch(end);
+            },
+            Token::As => {
+                self.compile(left)?;
+                self.compile(right)?;
+                self.emit(Op::Cast, span);
+            },
+            _ => {
+                let Some(binary) = binary_op(op) else {
+                    return Err(BaseError::interpreter(ErrorCode::Internal, "Unknown operator").with_span(span))
+                };
+                self.compile(left)?;
+                self.compile(right)?;
+                let at = self.emit(Op::Binary(binary), span);
+                self.func.proto.operand_spans.push((at as u32, left.span, right.span));
+            },
+        }
+        Ok(())
+    }
+
+    /// Assigns the value on top of the stack to `left`, leaving it there.
+    fn assign(&mut self, left: &Node, span: Span) -> Result<(), BaseError> {
+        match &left.kind {
+            ASTNode::Var { name, slot } => {
+                let slot = Self::slot(left, *slot)?;
+                if let Some(index) = self.local(slot) {
+                    self.emit(Op::SetLocal(index), span);
+                } else {
+                    let at = self.emit(Op::SetVar { depth: self.depth(slot), index: slot.index as u32 }, span);
+                    self.global(at, slot, name);
+                }
+            },
+            ASTNode::Array { .. } | ASTNode::Dict { .. } => {
+                let mut targets = Vec::new();
+                let pattern = Self::pattern(left, &mut targets);
+                self.func.proto.patterns.push(pattern);
+                let index = self.func.proto.patterns.len() as u32 - 1;
+                self.emit(Op::Dup, span);
+                self.emit(Op::Destructure(index), span);
+                for target in targets {
+                    self.assign(target, span)?;
+                    self.emit(Op::Pop, span);
+                }
+            },
+            _ => {
+                self.place(left, true)?;
+                self.emit(Op::Store, span);
+            },
+        }
+        Ok(())
+    }
+
+    fn pattern<'a>(node: &'a Node, targets: &mut Vec<&'a Node>) -> Pattern {
+        match &node.kind {
+            ASTNode::Array { values } => Pattern::Array(values.iter().map(|value| match &value.kind {
+                ASTNode::Unary { op: Token::Range, value } => (true, Self::pattern(value, targets)),
+                _ => (false, Self::pattern(value, targets)),
+            }).collect()),
+            ASTNode::Dict { entries } => Pattern::Dict(entries.iter().map(|(key, value)| {
+                (key.clone(), Self::pattern(value, targets))
+            }).collect()),
+            _ => {
+                targets.push(node);
+                Pattern::Target(targets.len() as u32 - 1)
+            },
+        }
+    }
+
+    /// Pushes the place `node` refers to. Indices are evaluated before
+    /// what they index, as the interpreter does.
+    fn place(&mut self, node: &Node, assign: bool) -> Result<(), BaseError> {
+        let span = node.span;
+        match &node.kind {
+            ASTNode::Var { name, slot } => {
+                let slot = Self::slot(node, *slot)?;
+                let name_index = self.name(name);
+                if let Some(index) = self.local(slot) {
+                    self.emit(Op::PlaceLocal { index, name: name_index }, span);
+                } else {
+                    let at = self.emit(Op::PlaceVar { depth: self.depth(slot), index: slot.index as u32, name: name_index }, span);
+                    self.global(at, slot, name);
+                }
+            },
+            ASTNode::Index { base, index } => {
+                self.compile(index)?;
+                self.place(base, false)?;
+                self.emit(Op::PlaceIndex { assign }, span);
+            },
+            ASTNode::Member { base, name } => {
+                self.place(base, false)?;
+                let name = self.name(name);
+                self.emit(Op::PlaceMember { name, assign }, span);
+            },
+            _ => {
+                self.compile(node)?;
+                self.emit(Op::PlaceValue, span);
+            },
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+use std::{collections::{BTreeMap, HashMap}, fmt, hash::BuildHasher};
+
+use num_bigint::BigInt;
+
+use crate::{
+    errors::{BaseError, ErrorCode},
+    int::Int,
+    interpreter::{Memory, RegIndex},
+    value::{DictKey, Value},
+};
+
+
+/// Why a value couldn't be converted, and where in a nested value it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConversionError {
+    message: String,
+    /// Indices and keys leading to the offending value, outermost first.
+    path: Vec<String>,
+}
+
+impl ConversionError {
+    pub fn new(message: impl Into<String>) -> Self {
+        ConversionError { message: message.into(), path: Vec::new() }
+    }
+    pub fn expected(expected: &str, found: &Value) -> Self {
+        Self::new(format!("expected {}, found {}", expected, found.type_name()))
+    }
+    /// Records that the error happened inside `segment`, e.g. `[2]`.
+    pub fn at(mut self, segment: String) -> Self {
+        self.path.insert(0, segment);
+        self
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if !self.path.is_empty() {
+            write!(f, " at {}", self.path.concat())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+impl From<ConversionError> for BaseError {
+    fn from(err: ConversionError) -> Self {
+        BaseError::interpreter(ErrorCode::InvalidConversion, format!("Couldn't convert value: {}", err))
+    }
+}
+
+pub(crate) fn index_segment(i: usize) -> String {
+    format!("[{}]", i)
+}
+
+pub(crate) fn element(id: RegIndex, memory: &Memory) -> Result<&Value, ConversionError> {
+    memory.get(id).map_err(|_| ConversionError::new("the value has already been collected"))
+}
+
+pub(crate) fn key_segment(key: &DictKey) -> String {
+    match key {
+        DictKey::Number(n) => format!("[{}]", n),
+        DictKey::Int(n) => format!("[{}]", n),
+        DictKey::String(s) => format!("[{:?}]", s),
+    }
+}
+
+
+/// Rust values that can be turned into BlueBat values, allocating any
+/// array or dictionary elements in `memory`.
+pub trait IntoValue {
+    fn into_value(self, memory: &mut Memory) -> Value;
+}
+
+/// Rust values that can be read back out of BlueBat values.
+pub trait FromValue: Sized {
+    fn from_value(value: &Value, memory: &Memory) -> Result<Self, ConversionError>;
+}
+
+
+impl IntoValue for Value {
+    fn into_value(self, _: &mut Memory) -> Value {
+        self
+    }
+}
+impl FromValue for Value {
+    fn from_value(value: &Value, _: &Memory) -> Result<Self, ConversionError> {
+        Ok(value.clone())
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self, _: &mut Memory) -> Value {
+        Value::Null
+    }
+}
+impl FromValue for () {
+    fn from_value(value: &Value, _: &Memory) -> Result<Self, ConversionError> {
+        match value {
+            Value::Null => Ok(()),
+            _ => Err(ConversionError::expected("Null", value)),
+        }
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self, _: &mut Memory) -> Value {
+        Value::Bool(self)
+    }
+}
+impl FromValue for bool {
+    fn from_value(value: &Value, _: &Memory) -> Result<Self, ConversionError> {
+        match value {
+            Value::Bool(b) => Ok(*b),
+            _ => Err(ConversionError::expected("a boolean", value)),
+        }
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self, _: &mut Memory) -> Value {
+        Value::Number(self)
+    }
+}
+impl FromValue for f64 {
+    fn from_value(value: &Value, _: &Memory) -> Result<Self, ConversionError> {
+        match value {
+            Value::Number(n) => Ok(*n),
+            Value::Int(n) => Ok(n.to_f64()),
+            _ => Err(ConversionError::expected("a number", value)),
+        }
+    }
+}
+
+impl IntoValue for f32 {
+    fn into_value(self, _: &mut Memory) -> Value {
+        Value::Number(self as f64)
+    }
+}
+impl FromValue for f32 {
+    fn from_value(value: &Value, memory: &Memory) -> Result<Self, ConversionError> {
+        f64::from_value(value, memory).map(|n| n as f32)
+    }
+}
+
+impl IntoValue for Int {
+    fn into_value(self, _: &mut Memory) -> Value {
+        Value::Int(self)
+    }
+}
+/// Whole floats are taken as integers too.
+impl FromValue for Int {
+    fn from_value(value: &Value, _: &Memory) -> Result<Self, ConversionError> {
+        match value {
+            Value::Int(n) => Ok(n.clone()),
+            Value::Number(n) if n.fract() == 0.0 => Ok(Int::from_f64(*n).unwrap()),
+            Value::Number(n) => Err(ConversionError::new(format!("expected an integer, found {}", n))),
+            _ => Err(ConversionError::expected("an integer", value)),
+        }
+    }
+}
+
+macro_rules! integer_conversions {
+    ( $($int:ty),* ) => { $(
+        impl IntoValue for $int {
+            fn into_value(self, _: &mut Memory) -> Value {
+                match i64::try_from(self) {
+                    Ok(n) => Value::Int(Int::Small(n)),
+                    Err(_) => Value::Int(Int::from(BigInt::from(self))),
+                }
+            }
+        }
+        impl FromValue for $int {
+            fn from_value(value: &Value, memory: &Memory) -> Result<Self, ConversionError> {
+                let n = Int::from_value(value, memory)?;
+                let converted = match &n {
+                    Int::Small(small) => <$int>::try_from(*small).ok(),
+                    Int::Big(big) => <$int>::try_from(big).ok(),
+                };
+                converted.ok_or_else(|| ConversionError::new(format!("{} is out of range for {}", n, stringify!($int))))
+            }
+        }
+    )* };
+}
+integer_conversions!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+impl IntoValue for String {
+    fn into_value(self, _: &mut Memory) -> Value {
+        Value::String(self)
+    }
+}
+impl IntoValue for &str {
+    fn into_value(self, _: &mut Memory) -> Value {
+        Value::String(self.to_string())
+    }
+}
+impl FromValue for String {
+    fn from_value(value: &Value, _: &Memory) -> Result<Self, ConversionError> {
+        match value {
+            Value::String(s) => Ok(s.clone()),
+            _ => Err(ConversionError::expected("a string", value)),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self, memory: &mut Memory) -> Value {
+        match self {
+            Some(value) => value.into_value(memory),
+            None => Value::Null,
+        }
+    }
+}
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value, memory: &Memory) -> Result<Self, ConversionError> {
+        match value {
+            Value::Null => Ok(None),
+            _ => T::from_value(value, memory).map(Some),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self, memory: &mut Memory) -> Value {
+        let ids = self.into_iter()
+            .map(|element| {
+                let value = element.into_value(memory);
+                memory.add(value)
+            })
+            .collect();
+        Value::Array(ids)
+    }
+}
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: &Value, memory: &Memory) -> Result<Self, ConversionError> {
+        match value {
+            Value::Array(arr) => arr.iter()
+                .enumerate()
+                .map(|(i, id)| element(*id, memory).and_then(|value| T::from_value(value, memory)).map_err(|err| err.at(index_segment(i))))
+                .collect(),
+            _ => Err(ConversionError::expected("an array", value)),
+        }
+    }
+}
+
+fn string_dict<T: IntoValue>(entries: impl IntoIterator<Item = (String, T)>, memory: &mut Memory) -> Value {
+    let mut map = BTreeMap::new();
+    for (key, element) in entries {
+        let value = element.into_value(memory);
+        map.insert(DictKey::String(key), memory.add(value));
+    }
+    Value::Dict(map)
+}
+
+fn string_entries<T: FromValue>(value: &Value, memory: &Memory) -> Result<Vec<(String, T)>, ConversionError> {
+    match value {
+        Value::Dict(map) => map.iter()
+            .map(|(key, id)| match key {
+                DictKey::String(s) => element(*id, memory).and_then(|value| T::from_value(value, memory))
+                    .map(|element| (s.clone(), element))
+                    .map_err(|err| err.at(key_segment(key))),
+                DictKey::Number(_) | DictKey::Int(_) => Err(ConversionError::new(format!("expected string keys, found the number key {}", key.to_value().to_str(memory)))),
+            })
+            .collect(),
+        _ => Err(ConversionError::expected("a dictionary", value)),
+    }
+}
+
+impl<T: IntoValue, S> IntoValue for HashMap<String, T, S> {
+    fn into_value(self, memory: &mut Memory) -> Value {
+        string_dict(self, memory)
+    }
+}
+impl<T: FromValue, S: BuildHasher + Default> FromValue for HashMap<String, T, S> {
+    fn from_value(value: &Value, memory: &Memory) -> Result<Self, ConversionError> {
+        Ok(string_entries(value, memory)?.into_iter().collect())
+    }
+}
+
+impl<T: IntoValue> IntoValue for BTreeMap<String, T> {
+    fn into_value(self, memory: &mut Memory) -> Value {
+        string_dict(self, memory)
+    }
+}
+impl<T: FromValue> FromValue for BTreeMap<String, T> {
+    fn from_value(value: &Value, memory: &Memory) -> Result<Self, ConversionError> {
+        Ok(string_entries(value, memory)?.into_iter().collect())
+    }
+}
+
+/// Tuples are arrays of exactly their length.
+macro_rules! tuple_conversions {
+    ( $( ($len:literal: $($name:ident $index:tt),*) )* ) => { $(
+        impl<$($name: IntoValue),*> IntoValue for ($($name,)*) {
+            fn into_value(self, memory: &mut Memory) -> Value {
+                let ids = vec![$({
+                    let value = self.$index.into_value(memory);
+                    memory.add(value)
+                }),*];
+                Value::Array(ids)
+            }
+        }
+        impl<$($name: FromValue),*> FromValue for ($($name,)*) {
+            fn from_value(value: &Value, memory: &Memory) -> Result<Self, ConversionError> {
+                match value {
+                    Value::Array(arr) if arr.len() == $len => Ok(($(
+                        element(arr[$index], memory).and_then(|value| $name::from_value(value, memory)).map_err(|err| err.at(index_segment($index)))?,
+                    )*)),
+                    Value::Array(arr) => Err(ConversionError::new(
+                        format!("expected an array of {} values, found {}", $len, arr.len())
+                    )),
+                    _ => Err(ConversionError::expected(concat!("an array of ", $len, " values"), value)),
+                }
+            }
+        }
+    )* };
+}
+tuple_conversions! {
+    (1: A 0)
+    (2: A 0, B 1)
+    (3: A 0, B 1, C 2)
+    (4: A 0, B 1, C 2, D 3)
+    (5: A 0, B 1, C 2, D 3, E 4)
+    (6: A 0, B 1, C 2, D 3, E 4, F 5)
+}
@@ -0,0 +1,413 @@
+use std::{cell::RefCell, fs, path::Path, rc::Rc, sync::{atomic::AtomicBool, Arc}, time::Duration};
+
+use crate::{
+    blbc::{self, Program},
+    compiler,
+    convert::{ConversionError, FromValue, IntoValue},
+    errors::{BaseError, Error, ScriptError},
+    gc::{GcPolicy, GcStats},
+    interpreter::{self, Memory, ScopeList, ValueResult},
+    lexer,
+    limits::Limits,
+    native::{self, Arity, NativeContext, NativeFunction, ScriptIo, SharedIo, StdIo},
+    parser,
+    resolver,
+    value::Value,
+    vm,
+};
+
+
+/// What runs the scripts. Both share the globals and the heap, and
+/// functions created by one can be called by the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// Walks the syntax tree; the reference for how scripts behave.
+    #[default]
+    TreeWalker,
+    /// Compiles to bytecode for the VM in `vm`. Limits count instructions
+    /// rather than nodes as steps.
+    Vm,
+}
+
+/// A BlueBat interpreter with its own globals and heap.
+///
+/// Arrays, dictionaries and functions returned from it refer into its heap,
+/// so they are only meaningful to the interpreter they came from, and only
+/// while a global still holds on to them.
+pub struct Interpreter {
+    memory: Memory,
+    scopes: ScopeList,
+    io: SharedIo,
+    backend: Backend,
+    /// Errors raised in functions called from Rust are reported against the
+    /// last evaluated source, as spans don't say which source they're from.
+    last_source: (String, String),
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        let mut interp = Interpreter {
+            memory: Memory::new(),
+            scopes: ScopeList::new(),
+            io: Rc::new(RefCell::new(Box::new(StdIo))),
+            backend: Backend::default(),
+            last_source: (String::new(), String::new()),
+        };
+        for native in native::builtins(&interp.io) {
+            interp.register_native(native);
+        }
+        let args = interp.array(Vec::new());
+        interp.set_global("args", args);
+        interp
+    }
+
+    /// Redirects the output of `print` and `println` and the input of `input`.
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use bluebat::{Interpreter, StreamIo};
+    ///
+    /// struct Captured(std::rc::Rc<std::cell::RefCell<String>>);
+    /// impl bluebat::ScriptIo for Captured {
+    ///     fn write(&mut self, text: &str) -> std::io::Result<()> {
+    ///         self.0.borrow_mut().push_str(text);
+    ///         Ok(())
+    ///     }
+    ///     fn read_line(&mut self) -> std::io::Result<Option<String>> {
+    ///         Ok(None)
+    ///     }
+    /// }
+    ///
+    /// let output = std::rc::Rc::new(std::cell::RefCell::new(String::new()));
+    /// let mut interp = Interpreter::new();
+    /// interp.set_io(Captured(output.clone()));
+    /// interp.eval("println(input('name? ') == Null)\n").unwrap();
+    /// assert_eq!(*output.borrow(), "name? True\n");
+    ///
+    /// interp.set_io(StreamIo { input: Cursor::new("Ada\n"), output: Vec::new() });
+    /// let name = interp.eval("input('')\n").unwrap();
+    /// assert_eq!(interp.display(&name), "Ada");
+    /// ```
+    pub fn set_io(&mut self, io: impl ScriptIo + 'static) {
+        *self.io.borrow_mut() = Box::new(io);
+    }
+
+    /// Chooses what runs the following `eval`s, e.g.
+    ///
+    /// ```
+    /// use bluebat::{Backend, Interpreter};
+    ///
+    /// let code = "fib = |x| if (x < 2) x else fib(x - 1) + fib(x - 2)\nfib(15)\n";
+    /// let results: Vec<String> = [Backend::TreeWalker, Backend::Vm].into_iter().map(|backend| {
+    ///     let mut interp = Interpreter::new();
+    ///     interp.set_backend(backend);
+    ///     let result = interp.eval(code).unwrap();
+    ///     interp.display(&result)
+    /// }).collect();
+    /// assert_eq!(results, ["610", "610"]);
+    /// ```
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Bounds every following `eval` and `call_function`, e.g.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use bluebat::{Error, Interpreter, Limits};
+    ///
+    /// let mut interp = Interpreter::new();
+    /// interp.set_limits(Limits { timeout: Some(Duration::from_millis(50)), ..Limits::default() });
+    /// assert!(matches!(interp.eval("while True 1\n"), Err(Error::Script(_))));
+    /// ```
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.scopes.budget.limits = limits;
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.scopes.budget.limits
+    }
+
+    /// A flag that stops the running script with an error once set, from
+    /// any thread. It stays set, failing later runs as well, until the host
+    /// clears it again.
+    ///
+    /// ```
+    /// use std::sync::atomic::Ordering;
+    /// use bluebat::{Backend, Interpreter};
+    ///
+    /// for backend in [Backend::TreeWalker, Backend::Vm] {
+    ///     let mut interp = Interpreter::new();
+    ///     interp.set_backend(backend);
+    ///     interp.eval("two = || 1 + 1\n").unwrap();
+    ///     let cancel = interp.cancel_flag();
+    ///     cancel.store(true, Ordering::Relaxed);
+    ///     assert!(interp.eval("1 + 1\n").unwrap_err().to_string().contains("cancelled"));
+    ///     assert!(interp.call_function("two", vec![]).is_err());
+    ///     cancel.store(false, Ordering::Relaxed);
+    ///     let two = interp.call_function("two", vec![]).unwrap();
+    ///     assert_eq!(interp.display(&two), "2");
+    /// }
+    /// ```
+    pub fn cancel_flag(&self) -> Arc<AtomicBool> {
+        self.scopes.budget.cancelled.clone()
+    }
+
+    pub fn set_gc_policy(&mut self, policy: GcPolicy) {
+        self.memory.policy = policy;
+    }
+
+    pub fn gc_policy(&self) -> GcPolicy {
+        self.memory.policy
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.memory.stats(&self.scopes)
+    }
+
+    /// Frees everything the globals no longer reach, including values
+    /// previously returned to the host.
+    pub fn collect_garbage(&mut self) {
+        self.memory.collect(&mut self.scopes, 0);
+    }
+
+    /// Collects for at most about `budget`, continuing the cycle an earlier
+    /// slice or the script left off, and returns whether it finished. Meant
+    /// to be called between frames together with `GcPolicy::Manual` or
+    /// `GcPolicy::Incremental`.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use bluebat::{GcPolicy, Interpreter};
+    ///
+    /// let mut interp = Interpreter::new();
+    /// interp.set_gc_policy(GcPolicy::Manual);
+    /// interp.eval("for i in 0..1000 {\n  [i]\n}\n").unwrap();
+    /// while !interp.collect_slice(Duration::from_micros(100)) {}
+    /// assert_eq!(interp.gc_stats().collections, 1);
+    /// ```
+    pub fn collect_slice(&mut self, budget: Duration) -> bool {
+        self.memory.collect_slice(&mut self.scopes, 0, budget)
+    }
+
+    /// Runs `code` in the global scope and returns the value of its last statement.
+    ///
+    /// Variables are resolved before `code` runs, against the globals that
+    /// earlier calls defined. So a variable that isn't defined anywhere is
+    /// a parse error rather than a runtime one, and a function assigning a
+    /// name that no scope around it has yet keeps that name local, even
+    /// after a later call defines a global of the same name.
+    ///
+    /// ```
+    /// use bluebat::{errors::ErrorCode, BaseError, Error, Interpreter};
+    ///
+    /// let mut interp = Interpreter::new();
+    /// interp.eval("set_y = || {\n  y = 5\n}\n").unwrap();
+    /// let y = interp.eval("y = 0\nset_y()\ny\n").unwrap();
+    /// assert_eq!(interp.display(&y), "0");
+    ///
+    /// let Err(Error::Script(err)) = interp.eval("println('never')\nz\n") else { panic!() };
+    /// assert!(matches!(&err.error, BaseError::ParseError(d) if d.code == ErrorCode::UnknownVariable));
+    /// ```
+    pub fn eval(&mut self, code: &str) -> Result<Value, Error> {
+        self.eval_named(code, "<eval>")
+    }
+
+    /// Like `eval`, with `source_name` standing in for a file name in diagnostics.
+    pub fn eval_named(&mut self, code: &str, source_name: &str) -> Result<Value, Error> {
+        self.last_source = (source_name.to_string(), code.to_string());
+
+        let result = lexer::lex(code)
+            .and_then(|tokens| parser::parse(&tokens))
+            .and_then(|(mut node, _)| {
+                resolver::resolve(&mut node, &mut self.scopes.globals)?;
+                match self.backend {
+                    Backend::TreeWalker => interpreter::start_execute(&node, &mut self.scopes, &mut self.memory),
+                    Backend::Vm => {
+                        let proto = compiler::compile(&node)?;
+                        vm::start_run(Rc::new(proto), &mut self.scopes, &mut self.memory)
+                    },
+                }
+            });
+        result.map_err(|err| self.script_error(err))
+    }
+
+    /// Runs a script, or a program compiled to a `.blbc` file.
+    pub fn run_file(&mut self, path: impl AsRef<Path>) -> Result<Value, Error> {
+        let path = path.as_ref();
+        if path.extension().is_some_and(|extension| extension == "blbc") {
+            let bytes = fs::read(path)
+                .map_err(|error| Error::Io { path: path.to_path_buf(), error })?;
+            let program = Program::decode(&bytes)
+                .map_err(|error| Error::Bytecode { source_name: path.display().to_string(), error })?;
+            // diagnostics quote the source next to it, as long as it's unchanged
+            let source_path = path.with_extension("blb");
+            return match fs::read_to_string(&source_path) {
+                Ok(source) if blbc::source_hash(&source) == program.source_hash =>
+                    self.run_program(program, &source_path.display().to_string(), Some(&source)),
+                _ => self.run_program(program, &path.display().to_string(), None),
+            }
+        }
+        let code = fs::read_to_string(path)
+            .map_err(|error| Error::Io { path: path.to_path_buf(), error })?;
+        self.eval_named(&code, &path.display().to_string())
+    }
+
+    /// Compiles `code` for the VM without running it, e.g. to save it with
+    /// `Program::encode`. Globals are linked by name when it's run, so the
+    /// program can be run by any interpreter.
+    ///
+    /// ```
+    /// use bluebat::{blbc::Program, Interpreter};
+    ///
+    /// let bytes = Interpreter::new().compile("double = |x| x * 2\ndouble(21)\n", "<eval>").unwrap().encode();
+    /// let mut interp = Interpreter::new();
+    /// let answer = interp.run_program(Program::decode(&bytes).unwrap(), "<eval>", None).unwrap();
+    /// assert_eq!(interp.display(&answer), "42");
+    /// ```
+    pub fn compile(&mut self, code: &str, source_name: &str) -> Result<Program, Error> {
+        self.last_source = (source_name.to_string(), code.to_string());
+
+        let mut globals = self.scopes.globals.clone();
+        let result = lexer::lex(code)
+            .and_then(|tokens| parser::parse(&tokens))
+            .and_then(|(mut node, _)| {
+                resolver::resolve(&mut node, &mut globals)?;
+                compiler::compile(&node)
+            });
+        result
+            .map(|proto| Program { source_hash: blbc::source_hash(code), proto })
+            .map_err(|err| self.script_error(err))
+    }
+
+    /// Runs a compiled program in the global scope on the VM, whatever the
+    /// backend. `source` is only used to quote lines in diagnostics, and
+    /// only if it's what the program was compiled from.
+    pub fn run_program(&mut self, program: Program, source_name: &str, source: Option<&str>) -> Result<Value, Error> {
+        let source = source.filter(|source| blbc::source_hash(source) == program.source_hash);
+        self.last_source = (source_name.to_string(), source.unwrap_or_default().to_string());
+
+        let proto = program.link(&mut self.scopes.globals);
+        vm::start_run(proto, &mut self.scopes, &mut self.memory).map_err(|err| self.script_error(err))
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let id = self.scopes.get_global(name)?;
+        self.memory.get(id).ok().cloned()
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.scopes.set_global(name, &mut self.memory, &value);
+    }
+
+    /// Exposes a Rust function to scripts as the global `name`.
+    ///
+    /// ```
+    /// use bluebat::{Arity, Int, Interpreter, Value};
+    ///
+    /// let mut interp = Interpreter::new();
+    /// interp.register("sum", Arity::AtLeast(0), |_, args| {
+    ///     let mut total = Int::from(0);
+    ///     for arg in &args {
+    ///         if let Value::Int(n) = arg { total = total.add(n) }
+    ///     }
+    ///     Ok(Value::Int(total))
+    /// });
+    /// let six = interp.eval("sum(1, 2, 3)\n").unwrap();
+    /// assert_eq!(interp.display(&six), "6");
+    /// ```
+    pub fn register(
+        &mut self,
+        name: &str,
+        arity: Arity,
+        func: impl Fn(&mut NativeContext, Vec<Value>) -> ValueResult + 'static,
+    ) {
+        self.register_native(NativeFunction::new(name, arity, func));
+    }
+
+    pub fn register_native(&mut self, native: NativeFunction) {
+        let name = native.name.clone();
+        self.set_global(&name, Value::Builtin(Rc::new(native)));
+    }
+
+    /// Calls the function or builtin stored in the global `name`.
+    ///
+    /// A call a function ends on takes over its frame, so recursing that
+    /// way doesn't run into the call depth limit:
+    ///
+    /// ```
+    /// use bluebat::{Interpreter, Value};
+    ///
+    /// let mut interp = Interpreter::new();
+    /// interp.eval("even = |n| if (n == 0) True else odd(n - 1)\nodd = |n| if (n == 0) False else even(n - 1)\n").unwrap();
+    /// let even = interp.call_function("even", vec![Value::Number(1_000_000.0)]).unwrap();
+    /// assert_eq!(interp.display(&even), "True");
+    /// ```
+    pub fn call_function(&mut self, name: &str, args: Vec<Value>) -> Result<Value, Error> {
+        let function = self.get_global(name)
+            .ok_or_else(|| Error::UnknownGlobal(name.to_string()))?;
+        interpreter::call_value(function, args, &mut self.scopes, &mut self.memory)
+            .map_err(|err| self.script_error(err))
+    }
+
+    /// Moves `values` onto the heap as the elements of a new array.
+    pub fn array(&mut self, values: Vec<Value>) -> Value {
+        values.into_value(&mut self.memory)
+    }
+
+    /// Converts a Rust value, allocating any elements it has on the heap.
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use bluebat::Interpreter;
+    ///
+    /// let mut interp = Interpreter::new();
+    /// let scores = interp.to_value(HashMap::from([("ada".to_string(), vec![1.0, 2.0])]));
+    /// interp.set_global("scores", scores);
+    /// let total = interp.eval("scores.ada[0] + scores.ada[1]\n").unwrap();
+    /// assert_eq!(interp.from_value::<f64>(&total), Ok(3.0));
+    ///
+    /// let pair = interp.eval("[1, 'two']\n").unwrap();
+    /// assert_eq!(interp.from_value::<(u8, String)>(&pair), Ok((1, "two".to_string())));
+    /// let err = interp.from_value::<Vec<f64>>(&pair).unwrap_err();
+    /// assert_eq!(err.to_string(), "expected a number, found a string at [1]");
+    /// ```
+    pub fn to_value(&mut self, value: impl IntoValue) -> Value {
+        value.into_value(&mut self.memory)
+    }
+
+    pub fn from_value<T: FromValue>(&self, value: &Value) -> Result<T, ConversionError> {
+        T::from_value(value, &self.memory)
+    }
+
+    /// Converts any `Serialize` type, see the `serde_bridge` module.
+    #[cfg(feature = "serde")]
+    pub fn serialize<T: serde::Serialize + ?Sized>(&mut self, value: &T) -> Result<Value, ConversionError> {
+        crate::serde_bridge::to_value(value, &mut self.memory)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn deserialize<T: serde::de::DeserializeOwned>(&self, value: &Value) -> Result<T, ConversionError> {
+        crate::serde_bridge::from_value(value, &self.memory)
+    }
+
+    /// Formats `value` the way `print` would.
+    pub fn display(&self, value: &Value) -> String {
+        value.to_str(&self.memory)
+    }
+
+    fn script_error(&self, error: BaseError) -> Error {
+        let (source_name, source) = self.last_source.clone();
+        Error::Script(Box::new(ScriptError { error, source_name, source }))
+    }
+}
@@ -1,9 +1,347 @@
+use std::{error, fmt, io, path::PathBuf, rc::Rc};
 
+use crate::{blbc::FormatError, lexer::Span};
 
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    UnexpectedCharacter,
+    UnterminatedComment,
+    UnexpectedToken,
+    InvalidControlFlow,
+    TypeMismatch,
+    UnknownVariable,
+    IndexOutOfBounds,
+    ArgumentCount,
+    InvalidConversion,
+    Destructure,
+    InvalidCall,
+    InvalidAssignment,
+    KeyNotFound,
+    UnknownMember,
+    Io,
+    LimitExceeded,
+    Cancelled,
+    DanglingReference,
+    DivisionByZero,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::UnexpectedCharacter => "E001",
+            ErrorCode::UnterminatedComment => "E002",
+            ErrorCode::UnexpectedToken => "E101",
+            ErrorCode::InvalidControlFlow => "E102",
+            ErrorCode::TypeMismatch => "E201",
+            ErrorCode::UnknownVariable => "E202",
+            ErrorCode::IndexOutOfBounds => "E203",
+            ErrorCode::ArgumentCount => "E204",
+            ErrorCode::InvalidConversion => "E205",
+            ErrorCode::Destructure => "E206",
+            ErrorCode::InvalidCall => "E207",
+            ErrorCode::InvalidAssignment => "E208",
+            ErrorCode::KeyNotFound => "E209",
+            ErrorCode::UnknownMember => "E210",
+            ErrorCode::Io => "E211",
+            ErrorCode::LimitExceeded => "E212",
+            ErrorCode::Cancelled => "E213",
+            ErrorCode::DanglingReference => "E214",
+            ErrorCode::DivisionByZero => "E215",
+            ErrorCode::Internal => "E999",
+        }
+    }
+}
+
+/// A secondary location shown alongside the primary span of a diagnostic.
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+/// One active call in the interpreter, outermost first in a traceback.
+#[derive(Debug, Clone)]
+pub struct CallFrame {
+    /// The variable the callee was called through, or `<anonymous>`.
+    pub function: Rc<str>,
+    pub call_site: Span,
+    pub builtin: Option<String>,
+    /// How many calls this one replaced by being made in tail position.
+    pub tail_calls: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub code: ErrorCode,
+    pub message: String,
+    pub span: Option<Span>,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub traceback: Vec<CallFrame>,
+}
+
+impl Diagnostic {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Diagnostic { code, message: message.into(), span: None, labels: Vec::new(), notes: Vec::new(), traceback: Vec::new() }
+    }
+}
 
+/// The kind of a diagnostic is the stage of the pipeline that produced it.
+#[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum BaseError {
-    ParseError(String),
-    InterpreterError(String),    
+    LexError(Box<Diagnostic>),
+    ParseError(Box<Diagnostic>),
+    InterpreterError(Box<Diagnostic>),
 }
 
+impl BaseError {
+    pub fn lex(code: ErrorCode, message: impl Into<String>, span: Span) -> Self {
+        BaseError::LexError(Box::new(Diagnostic::new(code, message))).with_span(span)
+    }
+    pub fn parse(code: ErrorCode, message: impl Into<String>, span: Span) -> Self {
+        BaseError::ParseError(Box::new(Diagnostic::new(code, message))).with_span(span)
+    }
+    pub fn interpreter(code: ErrorCode, message: impl Into<String>) -> Self {
+        BaseError::InterpreterError(Box::new(Diagnostic::new(code, message)))
+    }
+
+    pub fn diagnostic(&self) -> &Diagnostic {
+        match self {
+            BaseError::LexError(d) | BaseError::ParseError(d) | BaseError::InterpreterError(d) => d,
+        }
+    }
+    pub fn diagnostic_mut(&mut self) -> &mut Diagnostic {
+        match self {
+            BaseError::LexError(d) | BaseError::ParseError(d) | BaseError::InterpreterError(d) => d,
+        }
+    }
+
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            BaseError::LexError(_) => "syntax error",
+            BaseError::ParseError(_) => "parse error",
+            BaseError::InterpreterError(_) => "runtime error",
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.diagnostic_mut().span = Some(span);
+        self
+    }
+    /// Sets the primary span only if nothing more specific was recorded yet.
+    pub fn or_span(mut self, span: Span) -> Self {
+        let diagnostic = self.diagnostic_mut();
+        if diagnostic.span.is_none() {
+            diagnostic.span = Some(span);
+        }
+        self
+    }
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.diagnostic_mut().labels.push(Label { span, message: message.into() });
+        self
+    }
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.diagnostic_mut().notes.push(note.into());
+        self
+    }
+
+    /// Renders the diagnostic rustc-style, quoting the offending source
+    /// lines unless `source` is empty, as it is for precompiled programs
+    /// whose source is gone.
+    pub fn render(&self, source: &str, file_name: &str, color: bool) -> String {
+        let paint = |code: &str, text: &str| if color {
+            format!("\x1B[{}m{}\x1B[0m", code, text)
+        } else {
+            text.to_string()
+        };
+        let diagnostic = self.diagnostic();
+
+        let mut out = format!(
+            "{}: {}\n",
+            paint("1;31", &format!("{}[{}]", self.kind_name(), diagnostic.code.as_str())),
+            paint("1", &diagnostic.message),
+        );
+
+        let mut marks: Vec<(Span, &str, bool)> = Vec::new();
+        if let Some(span) = diagnostic.span {
+            marks.push((span, "", true));
+        }
+        for label in &diagnostic.labels {
+            marks.push((label.span, &label.message, false));
+        }
+        marks.sort_by_key(|(span, _, _)| (span.line, span.col));
+
+        let gutter = marks
+            .iter()
+            .map(|(span, _, _)| span.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let bar = paint("1;34", &format!("{} |", " ".repeat(gutter)));
+
+        if let Some(span) = diagnostic.span.or(marks.first().map(|m| m.0)) {
+            out += &format!(
+                "{}{} {}:{}:{}\n",
+                " ".repeat(gutter),
+                paint("1;34", "-->"),
+                file_name, span.line, span.col,
+            );
+        }
+        if source.is_empty() {
+            marks.clear();
+        }
+        if !marks.is_empty() {
+            out += &format!("{}\n", bar);
+        }
+        let mut last_line = None;
+        for (span, message, primary) in &marks {
+            let start = (span.start as usize).min(source.len());
+            let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+            let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+            let line_text = source[line_start..line_end].trim_end_matches('\r');
+
+            if last_line != Some(span.line) {
+                out += &format!(
+                    "{} {}\n",
+                    paint("1;34", &format!("{:>w$} |", span.line, w = gutter)),
+                    line_text,
+                );
+                last_line = Some(span.line);
+            }
+
+            // spans running past the end of their first line are underlined up to the line end
+            let end = (span.end as usize).clamp(start, line_end);
+            let prefix = source[line_start..start].chars().count();
+            let width = source[start..end].chars().count().max(1);
+            let (marker, style) = if *primary { ("^", "1;31") } else { ("-", "1;34") };
+            out += &format!(
+                "{} {}{}\n",
+                bar,
+                " ".repeat(prefix),
+                paint(style, format!("{} {}", marker.repeat(width), message).trim_end()),
+            );
+        }
+        for note in &diagnostic.notes {
+            out += &format!("{} {} {}: {}\n", " ".repeat(gutter), paint("1;34", "="), paint("1", "note"), note);
+        }
+        if !diagnostic.traceback.is_empty() {
+            out += &format!("{}\n", paint("1", "traceback (most recent call last):"));
+        }
+        let mut frames = diagnostic.traceback.iter().peekable();
+        while let Some(frame) = frames.next() {
+            let mut repeated = 0;
+            while frames.peek().is_some_and(|next| next.function == frame.function && next.call_site == frame.call_site) {
+                frames.next();
+                repeated += 1;
+            }
+
+            if frame.tail_calls > 0 {
+                out += &format!("  [{} frame(s) replaced by tail calls]\n", frame.tail_calls);
+            }
+            let callee = match &frame.builtin {
+                Some(name) if **name != *frame.function => format!("{} (builtin {})", frame.function, name),
+                Some(_) => format!("{} (builtin)", frame.function),
+                None => frame.function.to_string(),
+            };
+            // call sites are on line 0 in code compiled without spans
+            let line_text = match frame.call_site.line {
+                0 => {
+                    out += &format!("  in {}\n", paint("1", &callee));
+                    None
+                },
+                line => {
+                    out += &format!(
+                        "  in {} called at {}:{}:{}\n",
+                        paint("1", &callee),
+                        file_name, frame.call_site.line, frame.call_site.col,
+                    );
+                    source.lines().nth(line as usize - 1)
+                },
+            };
+            if let Some(line_text) = line_text {
+                out += &format!("      {}\n", line_text.trim());
+            }
+            if repeated > 0 {
+                out += &format!("  [previous frame repeated {} more time(s)]\n", repeated);
+            }
+        }
+        out
+    }
+}
+
+impl fmt::Display for BaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let diagnostic = self.diagnostic();
+        write!(f, "{}[{}]: {}", self.kind_name(), diagnostic.code.as_str(), diagnostic.message)?;
+        if let Some(span) = diagnostic.span {
+            write!(f, " (at line {}, column {})", span.line, span.col)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for BaseError {}
+
+
+/// A diagnostic together with the source it points into, so that it can
+/// still be rendered after the interpreter moved on.
+#[derive(Debug, Clone)]
+pub struct ScriptError {
+    pub error: BaseError,
+    pub source_name: String,
+    pub source: String,
+}
+
+impl ScriptError {
+    pub fn render(&self, color: bool) -> String {
+        self.error.render(&self.source, &self.source_name, color)
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.source_name, self.error)
+    }
+}
+
+impl error::Error for ScriptError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Everything that can go wrong when driving the interpreter from Rust.
+#[derive(Debug)]
+pub enum Error {
+    /// The script failed to lex, parse or run.
+    Script(Box<ScriptError>),
+    Io { path: PathBuf, error: io::Error },
+    /// `call_function` was given a name that isn't a global.
+    UnknownGlobal(String),
+    /// A precompiled program couldn't be loaded.
+    Bytecode { source_name: String, error: FormatError },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Script(err) => err.fmt(f),
+            Error::Io { path, error } => write!(f, "couldn't read '{}': {}", path.display(), error),
+            Error::UnknownGlobal(name) => write!(f, "unknown global '{}'", name),
+            Error::Bytecode { source_name, error } => write!(f, "{}: {}", source_name, error),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Script(err) => Some(err),
+            Error::Io { error, .. } => Some(error),
+            Error::UnknownGlobal(_) => None,
+            Error::Bytecode { error, .. } => Some(error),
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use std::{collections::{BTreeMap, HashSet}, mem, time::{Duration, Instant}};
+
+use crate::{arena::Arena, convert::IntoValue, interpreter::{Memory, RegIndex, ScopeId, ScopeList}, value::Value, vm::{Local, Stack}};
+
+
+/// How often a time-bounded slice looks at the clock, in units of work.
+const CLOCK_INTERVAL: usize = 256;
+
+/// When the interpreter collects garbage on its own. `collect()` and the
+/// memory limit collect regardless.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GcPolicy {
+    /// Collect once this many more values are live than after the last collection.
+    Fixed(usize),
+    /// Collect once the live values have grown by `factor` since the last
+    /// collection, and by at least `min` values.
+    Growth { factor: f64, min: usize },
+    /// Start a cycle like `Fixed(step)`, but spread it over the evaluation
+    /// steps that follow, doing `work` units of it in each. A unit is about
+    /// one value looked at, so `work` has to outpace allocation or the heap
+    /// grows until the cycle ends.
+    Incremental { step: usize, work: usize },
+    /// Never collect on its own, e.g. because the host runs slices.
+    Manual,
+}
+
+impl Default for GcPolicy {
+    fn default() -> Self {
+        GcPolicy::Fixed(50_000)
+    }
+}
+
+impl GcPolicy {
+    pub fn should_collect(&self, live: usize, live_after_last: usize) -> bool {
+        match *self {
+            GcPolicy::Fixed(step) | GcPolicy::Incremental { step, work: _ } => live > live_after_last + step,
+            GcPolicy::Growth { factor, min } => {
+                let step = (live_after_last as f64 * factor) as usize;
+                live > live_after_last + step.max(min)
+            },
+            GcPolicy::Manual => false,
+        }
+    }
+}
+
+/// What the collector has done so far.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GcStats {
+    /// Finished cycles.
+    pub collections: u64,
+    /// Values freed over all collections.
+    pub freed: u64,
+    /// Values live right now.
+    pub live: usize,
+    /// How long the last collection or slice held up the script.
+    pub last_pause: Duration,
+    pub total_pause: Duration,
+    /// Scopes live right now.
+    pub scopes: usize,
+    /// Scopes freed as soon as their block or call ended.
+    pub scopes_released: u64,
+    /// Scopes freed by collections, having been captured by closures.
+    pub scopes_freed: u64,
+}
+
+/// A dictionary as `gc_stats()` returns it: integer counts, and the
+/// pauses in milliseconds as floats.
+impl IntoValue for GcStats {
+    fn into_value(self, memory: &mut Memory) -> Value {
+        let fields = BTreeMap::from([
+            ("collections".to_string(), self.collections.into_value(memory)),
+            ("freed".to_string(), self.freed.into_value(memory)),
+            ("live".to_string(), self.live.into_value(memory)),
+            ("last_pause_ms".to_string(), Value::Number(self.last_pause.as_secs_f64() * 1000.0)),
+            ("total_pause_ms".to_string(), Value::Number(self.total_pause.as_secs_f64() * 1000.0)),
+            ("scopes".to_string(), self.scopes.into_value(memory)),
+            ("scopes_released".to_string(), self.scopes_released.into_value(memory)),
+            ("scopes_freed".to_string(), self.scopes_freed.into_value(memory)),
+        ]);
+        fields.into_value(memory)
+    }
+}
+
+/// Something reachable that still has to be looked into.
+#[derive(Debug)]
+enum Gray {
+    Value(RegIndex),
+    Scope(ScopeId),
+}
+
+#[derive(Debug, Default)]
+enum Phase {
+    #[default]
+    Idle,
+    Marking { worklist: Vec<Gray>, scopes: HashSet<ScopeId> },
+    Sweeping { cursor: usize },
+}
+
+/// The state of a collection cycle that may be spread over many slices.
+///
+/// Marking can be interrupted because values allocated during it are gray
+/// and `Memory::set` grays black values again when they change, so nothing
+/// reachable ends up pointed to only by black values.
+#[derive(Debug, Default)]
+pub(crate) struct Collector {
+    phase: Phase,
+    /// Marks by slot index; slots allocated while sweeping are marked.
+    marked: Vec<bool>,
+    freed: u64,
+}
+
+impl Collector {
+    pub(crate) fn allocated(&mut self, id: RegIndex) {
+        match &mut self.phase {
+            Phase::Idle => (),
+            Phase::Marking { worklist, .. } => worklist.push(Gray::Value(id)),
+            Phase::Sweeping { .. } => self.mark(id),
+        }
+    }
+
+    /// The write barrier.
+    pub(crate) fn written(&mut self, id: RegIndex) {
+        if let Phase::Marking { worklist, .. } = &mut self.phase {
+            if self.marked.get(id.index()).copied().unwrap_or(false) {
+                self.marked[id.index()] = false;
+                worklist.push(Gray::Value(id));
+            }
+        }
+    }
+
+    pub(crate) fn in_cycle(&self) -> bool {
+        !matches!(self.phase, Phase::Idle)
+    }
+
+    fn is_marked(&self, id: RegIndex) -> bool {
+        self.marked.get(id.index()).copied().unwrap_or(false)
+    }
+
+    fn mark(&mut self, id: RegIndex) {
+        if id.index() >= self.marked.len() {
+            self.marked.resize(id.index() + 1, false);
+        }
+        self.marked[id.index()] = true;
+    }
+}
+
+fn roots(protected: &[Vec<RegIndex>], stack: &Stack, scope_id: ScopeId, worklist: &mut Vec<Gray>) {
+    worklist.push(Gray::Scope(scope_id));
+    worklist.extend(protected.iter().flatten().map(|id| Gray::Value(*id)));
+    worklist.extend(stack.places.iter().map(|place| Gray::Value(place.id)));
+    for value in &stack.values {
+        references(value, worklist);
+    }
+    for local in &stack.locals {
+        match local {
+            Local::Value(value) => references(value, worklist),
+            Local::Boxed(id) => worklist.push(Gray::Value(*id)),
+            Local::Unset => (),
+        }
+    }
+}
+
+/// Grays what `value` points to.
+fn references(value: &Value, worklist: &mut Vec<Gray>) {
+    match value {
+        Value::Function { scope_id, .. } | Value::Closure { scope_id, .. } => worklist.push(Gray::Scope(*scope_id)),
+        Value::Method { receiver, name: _ } => worklist.push(Gray::Value(*receiver)),
+        Value::Array(arr) => worklist.extend(arr.iter().map(|i| Gray::Value(*i))),
+        Value::Dict(map) => worklist.extend(map.values().map(|i| Gray::Value(*i))),
+        _ => (),
+    }
+}
+
+/// Looks into one gray item, returning how much work that was.
+fn scan(gray: Gray, register: &Arena<Value>, scopes: &ScopeList, collector: &mut Collector, worklist: &mut Vec<Gray>, marked_scopes: &mut HashSet<ScopeId>) -> usize {
+    let before = worklist.len();
+    match gray {
+        Gray::Value(id) => {
+            let Some(value) = register.get(id) else { return 1 };
+            if collector.is_marked(id) { return 1 }
+            collector.mark(id);
+            references(value, worklist);
+        },
+        Gray::Scope(id) => {
+            let Some(scope) = scopes.register.get(&id) else { return 1 };
+            if !marked_scopes.insert(id) { return 1 }
+            worklist.extend(scope.vars.iter().flatten().map(|i| Gray::Value(*i)));
+            worklist.extend(scope.parent_id.into_iter().chain(scope.caller_id).map(Gray::Scope));
+        },
+    }
+    1 + worklist.len() - before
+}
+
+impl Memory {
+    /// Frees every value and scope that can't be reached from `scope_id`
+    /// or from a protected value, all at once.
+    pub fn collect(&mut self, scopes: &mut ScopeList, scope_id: ScopeId) {
+        // an unfinished cycle can be dropped: marking frees nothing, and a
+        // half-swept heap is as good a start as any
+        if let Phase::Sweeping { .. } = self.gc.phase {
+            self.stats.freed += self.gc.freed;
+        }
+        self.gc.phase = Phase::Idle;
+        self.run_cycle(scopes, scope_id, usize::MAX, None);
+    }
+
+    /// Does up to `work` units of a collection cycle, starting one if none
+    /// is running. Returns whether the cycle finished.
+    pub fn collect_step(&mut self, scopes: &mut ScopeList, scope_id: ScopeId, work: usize) -> bool {
+        self.run_cycle(scopes, scope_id, work, None)
+    }
+
+    /// Like `collect_step`, but bounded by time instead of work.
+    pub fn collect_slice(&mut self, scopes: &mut ScopeList, scope_id: ScopeId, budget: Duration) -> bool {
+        self.run_cycle(scopes, scope_id, usize::MAX, Some(Instant::now() + budget))
+    }
+
+    /// Collects, or continues collecting, if the policy says so. `steps` is
+    /// how many evaluation steps were taken since the last call, which an
+    /// incremental cycle does its work for.
+    pub fn maybe_collect(&mut self, scopes: &mut ScopeList, scope_id: ScopeId, steps: u64) {
+        match self.policy {
+            GcPolicy::Incremental { step: _, work } if self.gc.in_cycle() => {
+                self.collect_step(scopes, scope_id, work.saturating_mul(steps as usize));
+            },
+            GcPolicy::Incremental { step: _, work } if self.policy.should_collect(self.register.len(), self.last_amount) => {
+                self.collect_step(scopes, scope_id, work.saturating_mul(steps as usize));
+            },
+            _ if self.policy.should_collect(self.register.len(), self.last_amount) => self.collect(scopes, scope_id),
+            _ => (),
+        }
+    }
+
+    pub fn stats(&self, scopes: &ScopeList) -> GcStats {
+        GcStats {
+            live: self.register.len(),
+            scopes: scopes.register.len(),
+            scopes_released: scopes.released,
+            ..self.stats.clone()
+        }
+    }
+
+    fn run_cycle(&mut self, scopes: &mut ScopeList, scope_id: ScopeId, work: usize, deadline: Option<Instant>) -> bool {
+        let start = Instant::now();
+        let Memory { register, protected, gc, stack, .. } = self;
+
+        if let Phase::Idle = gc.phase {
+            gc.marked = vec![false; register.capacity()];
+            gc.freed = 0;
+            let mut worklist = Vec::new();
+            roots(protected, stack, scope_id, &mut worklist);
+            gc.phase = Phase::Marking { worklist, scopes: HashSet::new() };
+        }
+
+        let mut done = 0;
+        let mut finished = false;
+        for round in 0.. {
+            if done >= work || deadline.is_some_and(|deadline| round % CLOCK_INTERVAL == 0 && Instant::now() >= deadline) {
+                break
+            }
+            match mem::take(&mut gc.phase) {
+                Phase::Idle => unreachable!(),
+                Phase::Marking { mut worklist, scopes: mut marked_scopes } => {
+                    if let Some(gray) = worklist.pop() {
+                        done += scan(gray, register, scopes, gc, &mut worklist, &mut marked_scopes);
+                        gc.phase = Phase::Marking { worklist, scopes: marked_scopes };
+                        continue
+                    }
+                    // The roots have moved on since the cycle started. Catching up
+                    // with them can't be interrupted, or the script could move on
+                    // to a scope that was created after and is never marked.
+                    roots(protected, stack, scope_id, &mut worklist);
+                    while let Some(gray) = worklist.pop() {
+                        done += scan(gray, register, scopes, gc, &mut worklist, &mut marked_scopes);
+                    }
+                    let before = scopes.register.len();
+                    scopes.register.retain(|id, _| marked_scopes.contains(id));
+                    self.stats.scopes_freed += (before - scopes.register.len()) as u64;
+                    gc.phase = Phase::Sweeping { cursor: 0 };
+                },
+                Phase::Sweeping { cursor } => {
+                    if cursor >= register.capacity() {
+                        finished = true;
+                        break
+                    }
+                    if let Some(id) = register.handle_at(cursor) {
+                        if !gc.is_marked(id) {
+                            register.remove(id);
+                            gc.freed += 1;
+                        }
+                    }
+                    done += 1;
+                    gc.phase = Phase::Sweeping { cursor: cursor + 1 };
+                },
+            }
+        }
+
+        let pause = start.elapsed();
+        self.stats.last_pause = pause;
+        self.stats.total_pause += pause;
+        if finished {
+            self.gc.marked = Vec::new();
+            self.last_amount = self.register.len();
+            self.stats.collections += 1;
+            self.stats.freed += self.gc.freed;
+        }
+        finished
+    }
+}
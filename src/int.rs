@@ -0,0 +1,241 @@
+//! Integers that grow past 64 bits instead of overflowing.
+
+use std::{borrow::Cow, cmp::Ordering, fmt, str::FromStr};
+
+use num_bigint::{BigInt, ParseBigIntError};
+use num_traits::{FromPrimitive, Signed, ToPrimitive};
+
+
+/// The most bits `^` will build an integer of, about 315,000 digits.
+pub const MAX_POW_BITS: u64 = 1 << 20;
+
+/// An integer, kept in an `i64` while it fits. Arithmetic that overflows
+/// carries on with a `BigInt`, and results that fit again go back to an
+/// `i64`, so that equal integers always look the same.
+///
+/// Integer literals are `Int`s and literals with a point are floats. When
+/// both sides of an operator are integers:
+///
+/// - `+`, `-`, `*` and `%` give an integer;
+/// - `^` gives an integer for exponents from 0 up to `u32::MAX`, and a
+///   float otherwise;
+/// - `/` always gives a float, so `7 / 2` is `3.5` and `1 / 0` is infinite.
+///   `7.div(2)` divides rounding towards zero and fails on zero, as `%` does.
+///
+/// With a float on either side, both are turned into floats first.
+/// Comparisons and `==` are exact across the two, and an integer and a
+/// float that are equal are the same dictionary key.
+///
+/// ```
+/// use bluebat::Value;
+///
+/// let mut interp = bluebat::Interpreter::new();
+/// let big = interp.eval("2 ^ 64 + 7.div(2)\n").unwrap();
+/// assert_eq!(interp.display(&big), "18446744073709551619");
+///
+/// for (source, expected) in [
+///     ("7 + 2", "an integer"), ("7 % 2", "an integer"), ("2 ^ 3", "an integer"),
+///     ("7 / 2", "a number"), ("6 / 2", "a number"), ("2 ^ -1", "a number"),
+///     ("7 + 2.0", "a number"), ("7.0 * 2", "a number"), ("7.div(2)", "an integer"),
+/// ] {
+///     assert_eq!(interp.eval(&format!("{}\n", source)).unwrap().type_name(), expected, "{}", source);
+/// }
+/// assert!(matches!(interp.eval("7 / 2\n").unwrap(), Value::Number(n) if n == 3.5));
+/// assert!(matches!(interp.eval("1 / 0\n").unwrap(), Value::Number(n) if n == f64::INFINITY));
+/// assert!(matches!(interp.eval("2 ^ 53 + 1 > 2.0 ^ 53\n").unwrap(), Value::Bool(true)));
+/// assert!(matches!(interp.eval("{1: 'a'}[1.0]\n").unwrap(), Value::String(s) if s == "a"));
+/// assert!(interp.eval("7.div(0)\n").unwrap_err().to_string().contains("[E215]"));
+/// assert!(interp.eval("7.div(2.0)\n").unwrap_err().to_string().contains("[E201]"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Int {
+    Small(i64),
+    Big(BigInt),
+}
+
+impl From<i64> for Int {
+    fn from(n: i64) -> Self {
+        Int::Small(n)
+    }
+}
+
+impl From<BigInt> for Int {
+    fn from(n: BigInt) -> Self {
+        match n.to_i64() {
+            Some(n) => Int::Small(n),
+            None => Int::Big(n),
+        }
+    }
+}
+
+impl FromStr for Int {
+    type Err = ParseBigIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<i64>() {
+            Ok(n) => Ok(Int::Small(n)),
+            Err(_) => s.parse::<BigInt>().map(Int::from),
+        }
+    }
+}
+
+impl fmt::Display for Int {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Int::Small(n) => write!(f, "{}", n),
+            Int::Big(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+impl Ord for Int {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Int::Small(a), Int::Small(b)) => a.cmp(b),
+            _ => self.to_big().cmp(&other.to_big()),
+        }
+    }
+}
+impl PartialOrd for Int {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Int {
+    pub fn to_big(&self) -> Cow<'_, BigInt> {
+        match self {
+            Int::Small(n) => Cow::Owned(BigInt::from(*n)),
+            Int::Big(n) => Cow::Borrowed(n),
+        }
+    }
+
+    /// The nearest float, or an infinity past the range of floats.
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Int::Small(n) => *n as f64,
+            Int::Big(n) => n.to_f64().unwrap_or(if n.is_negative() { f64::NEG_INFINITY } else { f64::INFINITY }),
+        }
+    }
+
+    /// The whole part of `n`, unless it's NaN or infinite.
+    pub fn from_f64(n: f64) -> Option<Int> {
+        match n.trunc() {
+            n if n.abs() < i64::MAX as f64 => Some(Int::Small(n as i64)),
+            n => BigInt::from_f64(n).map(Int::from),
+        }
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        match self {
+            Int::Small(n) => Some(*n),
+            Int::Big(_) => None,
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        matches!(self, Int::Small(0))
+    }
+
+    pub fn is_negative(&self) -> bool {
+        match self {
+            Int::Small(n) => *n < 0,
+            Int::Big(n) => n.is_negative(),
+        }
+    }
+
+    /// Applies `small` to two `i64`s, or `big` if either doesn't fit or
+    /// `small` overflows.
+    fn combine(&self, other: &Int, small: fn(i64, i64) -> Option<i64>, big: fn(&BigInt, &BigInt) -> BigInt) -> Int {
+        if let (Int::Small(a), Int::Small(b)) = (self, other) {
+            if let Some(n) = small(*a, *b) {
+                return Int::Small(n)
+            }
+        }
+        Int::from(big(&self.to_big(), &other.to_big()))
+    }
+
+    pub fn add(&self, other: &Int) -> Int {
+        self.combine(other, i64::checked_add, |a, b| a + b)
+    }
+
+    pub fn sub(&self, other: &Int) -> Int {
+        self.combine(other, i64::checked_sub, |a, b| a - b)
+    }
+
+    pub fn mul(&self, other: &Int) -> Int {
+        self.combine(other, i64::checked_mul, |a, b| a * b)
+    }
+
+    /// Division rounding towards zero, `None` when dividing by zero.
+    pub fn div(&self, other: &Int) -> Option<Int> {
+        match other.is_zero() {
+            true => None,
+            false => Some(self.combine(other, i64::checked_div, |a, b| a / b)),
+        }
+    }
+
+    /// The remainder of `div`, with the sign of `self`.
+    pub fn rem(&self, other: &Int) -> Option<Int> {
+        match other.is_zero() {
+            true => None,
+            false => Some(self.combine(other, i64::checked_rem, |a, b| a % b)),
+        }
+    }
+
+    /// Bits in the magnitude, 0 for zero.
+    pub fn bits(&self) -> u64 {
+        match self {
+            Int::Small(n) => 64 - n.unsigned_abs().leading_zeros() as u64,
+            Int::Big(n) => n.bits(),
+        }
+    }
+
+    /// `None` when the result could take more than [`MAX_POW_BITS`] bits,
+    /// judging by the size of `self` before doing any of the work.
+    pub fn pow(&self, exponent: u32) -> Option<Int> {
+        if let Int::Small(n) = self {
+            if let Some(n) = n.checked_pow(exponent) {
+                return Some(Int::Small(n))
+            }
+        }
+        match self.bits().saturating_mul(exponent as u64) {
+            bits if bits > MAX_POW_BITS => None,
+            _ => Some(Int::from(self.to_big().pow(exponent))),
+        }
+    }
+
+    pub fn neg(&self) -> Int {
+        match self {
+            Int::Small(n) if *n != i64::MIN => Int::Small(-n),
+            _ => Int::from(-self.to_big().into_owned()),
+        }
+    }
+
+    pub fn abs(&self) -> Int {
+        match self.is_negative() {
+            true => self.neg(),
+            false => self.clone(),
+        }
+    }
+
+    /// Compares exactly with a float, even past where floats have gaps
+    /// between integers. `None` for NaN.
+    pub fn cmp_f64(&self, other: f64) -> Option<Ordering> {
+        match self {
+            // floats hold these exactly
+            Int::Small(n) if n.unsigned_abs() <= 1 << 53 => return (*n as f64).partial_cmp(&other),
+            _ if other.is_nan() => return None,
+            _ => (),
+        }
+        if other.is_infinite() {
+            return Some(if other > 0.0 { Ordering::Less } else { Ordering::Greater })
+        }
+        let whole = BigInt::from_f64(other.floor())?;
+        match self.to_big().as_ref().cmp(&whole) {
+            // the integer is the floor, so it's less if there's a fraction
+            Ordering::Equal if other.fract() != 0.0 => Some(Ordering::Less),
+            ordering => Some(ordering),
+        }
+    }
+}
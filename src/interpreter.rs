@@ -1,102 +1,154 @@
-use std::{collections::{HashMap, HashSet}, hash::Hash, io::{self, Write}};
+use std::{collections::{BTreeMap, HashMap}, hash::{BuildHasherDefault, Hasher}, rc::Rc};
 
-use crate::{errors::{BaseError}, lexer::Token, parser::ASTNode, value::Value};
+use crate::{arena::{Arena, Handle}, errors::{BaseError, CallFrame, ErrorCode}, gc::{Collector, GcPolicy, GcStats}, lexer::Token, limits::{limit_error, Budget}, methods, native::NativeContext, parser::{ASTNode, FuncDef, Node}, resolver::{Globals, Slot}, value::{DictKey, Value}, vm::{self, Stack}};
 
-pub type RegIndex = usize;
+/// A handle to a value in `Memory`.
+pub type RegIndex = Handle;
+pub type ScopeId = usize;
+
+/// Stack that has to be left when entering a node before `execute` moves
+/// on to a fresh segment, so deep recursion can't overflow the native stack.
+pub(crate) const STACK_RED_ZONE: usize = 128 * 1024;
+pub(crate) const STACK_SEGMENT_SIZE: usize = 2 * 1024 * 1024;
 
 pub type ValueResult = Result<Value, BaseError>;
 
-fn derive_scope(scope_id: RegIndex, caller_id: RegIndex, scopes: &mut ScopeList) -> RegIndex {
+/// Anything that unwinds `execute`: errors, and the `break`, `continue`
+/// and `return` control signals, which are caught by loops and calls.
+#[derive(Debug)]
+pub enum Signal {
+    Error(BaseError),
+    Break(Value),
+    Continue,
+    Return(Value),
+    /// A call in tail position, which the function it returns from makes
+    /// in its place, reusing its frame.
+    TailCall(Box<TailCall>),
+}
+
+#[derive(Debug)]
+pub struct TailCall {
+    def: Rc<FuncDef>,
+    def_scope: ScopeId,
+    args: Vec<Value>,
+    frame: CallFrame,
+}
+
+impl From<BaseError> for Signal {
+    fn from(err: BaseError) -> Self {
+        Signal::Error(err)
+    }
+}
+
+type ExecResult = Result<Value, Signal>;
+
+/// How many variable lists `ScopeList::release` keeps around for reuse.
+const MAX_SPARE_VARS: usize = 64;
+
+pub(crate) fn derive_scope(scope_id: ScopeId, caller_id: ScopeId, scopes: &mut ScopeList) -> ScopeId {
     scopes.counter += 1;
-    scopes.register.insert( scopes.counter, Scope {parent_id: Some(scope_id), caller_id: Some(caller_id), vars: HashMap::new() } );
+    let vars = scopes.spare_vars.pop().unwrap_or_default();
+    scopes.register.insert( scopes.counter, Scope {parent_id: Some(scope_id), caller_id: Some(caller_id), vars, captured: false } );
     scopes.counter
 }
 
 #[derive(Debug)]
 pub struct Scope {
-    parent_id: Option<RegIndex>,
-    caller_id: Option<RegIndex>,
-    vars: HashMap<String, RegIndex>,
+    pub(crate) parent_id: Option<ScopeId>,
+    pub(crate) caller_id: Option<ScopeId>,
+    /// By the slot indices the resolver handed out; `None` until assigned.
+    pub(crate) vars: Vec<Option<RegIndex>>,
+    /// Whether a closure may still look variables up in here.
+    captured: bool,
 }
 
 #[derive(Debug)]
 pub struct Memory {
-    counter: RegIndex,
-    register: HashMap<RegIndex, Value>,
-    protected: Vec<Vec<RegIndex>>,
-    last_amount: usize,
+    pub(crate) register: Arena<Value>,
+    pub(crate) protected: Vec<Vec<RegIndex>>,
+    /// How many values survived the last collection.
+    pub(crate) last_amount: usize,
+    pub policy: GcPolicy,
+    pub(crate) stats: GcStats,
+    pub(crate) gc: Collector,
+    /// The operands of the bytecode VM.
+    pub(crate) stack: Stack,
 }
 
-#[derive(Debug)]
-pub struct ScopeList {
-    counter: RegIndex,
-    pub register: HashMap<RegIndex, Scope>,
-}
+/// Hashes scope ids as they are. They're handed out in order, so they
+/// spread over the buckets without any mixing, which keeps the lookups
+/// on every variable access and call cheap.
+#[derive(Debug, Default)]
+pub struct ScopeIdHasher(u64);
 
-#[derive(Debug)]
-pub struct CollectTracker {
-    marked_scopes: HashSet<RegIndex>,
-    marked_values: HashSet<RegIndex>,
-}
+impl Hasher for ScopeIdHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
 
-impl CollectTracker {
-    fn new(memory: &Memory, scopes: &ScopeList) -> Self {
-        let mut marked_scopes = HashSet::new();
-        let mut marked_values = HashSet::new();
-        for (i, _) in &memory.register {
-            marked_values.insert(*i);
-        }
-        for (i, _) in &scopes.register {
-            marked_scopes.insert(*i);
-        }
-        Self {
-            marked_values,
-            marked_scopes,
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 << 8) | *byte as u64;
         }
     }
+
+    fn write_usize(&mut self, n: usize) {
+        self.0 = n as u64;
+    }
 }
 
-fn get_value_references(
-    value: &Value,
-    memory: &Memory,
-    scopes: &ScopeList,
-    value_ids: &mut Vec<RegIndex>,
-    scope_ids: &mut Vec<RegIndex>
-) {
-    match value {
-        Value::Function { arg_names: _, code: _, scope_id } => {
-            if !scope_ids.contains(scope_id) {
-                scope_ids.push(*scope_id);
-            }
-        },
-        Value::Array (arr) => {
-            for i in arr {
-                if !value_ids.contains(i) {
-                    value_ids.push(*i);
-                    get_value_references(memory.register.get(i).unwrap(), memory, scopes, value_ids, scope_ids);
-                }
-            }
-        }
-        _ => (),
+pub type ScopeMap<T> = HashMap<ScopeId, T, BuildHasherDefault<ScopeIdHasher>>;
+
+#[derive(Debug)]
+pub struct ScopeList {
+    counter: ScopeId,
+    pub register: ScopeMap<Scope>,
+    /// The slots of the global scope by name, see `resolver::resolve`.
+    pub(crate) globals: Globals,
+    /// Scopes freed by `release` rather than by a collection.
+    pub(crate) released: u64,
+    /// Emptied variable lists of released scopes, for new scopes to reuse.
+    spare_vars: Vec<Vec<Option<RegIndex>>>,
+    pub call_stack: Vec<CallFrame>,
+    pub budget: Budget,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Memory {
     pub fn new() -> Self {
-        return Memory {counter: 0, register: HashMap::new(), protected: Vec::new(), last_amount: 0};
+        Memory {register: Arena::new(), protected: Vec::new(), last_amount: 0, policy: GcPolicy::default(), stats: GcStats::default(), gc: Collector::default(), stack: Stack::default()}
     }
 
     pub fn add(&mut self, value: Value) -> RegIndex {
-        self.counter += 1;
-        self.register.insert(self.counter, value);
-        self.counter
+        let id = self.register.insert(value);
+        self.gc.allocated(id);
+        id
     }
-    pub fn set(&mut self, value: Value, id: RegIndex) {
-        self.register.insert(id, value);
+    /// Every change to a stored value goes through here, so that a
+    /// collection in progress notices it.
+    pub fn set(&mut self, value: Value, id: RegIndex) -> Result<(), BaseError> {
+        match self.register.get_mut(id) {
+            Some(slot) => { *slot = value; self.gc.written(id); Ok(()) },
+            None => Err(dangling()),
+        }
     }
     
-    pub fn get(&self, id: RegIndex) -> &Value {
-        self.register.get(&id).unwrap()
+    /// Fails for a handle whose value has been collected.
+    pub fn get(&self, id: RegIndex) -> Result<&Value, BaseError> {
+        self.register.get(id).ok_or_else(dangling)
+    }
+
+    /// The number of live values.
+    pub fn len(&self) -> usize {
+        self.register.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.register.is_empty()
     }
     
 
@@ -104,157 +156,130 @@ impl Memory {
         self.protected.push(Vec::new());
     }
     pub fn pop_protected(&mut self) {
-        //println!("{:?}",self.protected);
         self.protected.pop();
     }
     pub fn protect(&mut self, value: Value) -> Value {
-        self.add(value);
+        self.protect_id(value.clone());
+        value
+    }
+    /// Stops protecting all but the first `len` values the current node protected.
+    pub fn truncate_protected(&mut self, len: usize) {
         self.protected
             .last_mut()
             .unwrap()
-            .push(self.counter);
-        self.get(self.counter).clone()
+            .truncate(len);
     }
     pub fn protect_id(&mut self, value: Value) -> RegIndex {
-        self.add(value);
+        let id = self.add(value);
         self.protected
             .last_mut()
             .unwrap()
-            .push(self.counter);
-        self.counter
-    }
-
-
-
-    pub fn collect(&mut self, scopes: &mut ScopeList, scope_id: RegIndex) {
-        let mut tracker = CollectTracker::new(self, scopes);
-        
-        //println!("\n\n{:#?}\nscope_id: {},\n{:#?}\n{:#?}",self,scope_id,scopes,tracker);
-        io::stdout().flush().unwrap();
-
-        self.mark(scopes, scope_id, &mut tracker);
-
-        for vec in &self.protected {
-            for var_id in vec {
-                if tracker.marked_values.contains(&var_id) {
-                    tracker.marked_values.remove(var_id);
-                    let mut value_ids: Vec<RegIndex> = Vec::new();
-                    let mut scope_ids: Vec<RegIndex> = Vec::new();
-                    get_value_references(
-                        self.register.get(&var_id).unwrap(),
-                        self, scopes,
-                        &mut value_ids,
-                        &mut scope_ids,
-                    );
-                    for i in scope_ids {
-                        if tracker.marked_scopes.contains(&i) {
-                            self.mark(scopes, i, &mut tracker);
-                        }
-                    }
-                    for i in value_ids {
-                        tracker.marked_values.remove(&i);
-                    }
-                }
-            }
-        }
-        //println!("{:#?}",tracker);
-        for i in tracker.marked_scopes {
-            scopes.register.remove(&i);
-        }
-        for i in tracker.marked_values {
-            self.register.remove(&i);
-        }
-        self.last_amount = self.register.len()
+            .push(id);
+        id
     }
+}
 
-    pub fn mark(&self, scopes: &mut ScopeList, scope_id: RegIndex, tracker: &mut CollectTracker) {
-        let mut var_check_ids = Vec::new();
-        tracker.marked_scopes.remove(&scope_id);
-        for (_, var_id) in &scopes.register.get(&scope_id).unwrap().vars {
-            var_check_ids.push(*var_id);
-        }
-        for var_id in var_check_ids {
-            if tracker.marked_values.contains(&var_id) {
-                tracker.marked_values.remove(&var_id);
-                let mut value_ids: Vec<RegIndex> = Vec::new();
-                let mut scope_ids: Vec<RegIndex> = Vec::new();
-                get_value_references(
-                    self.register.get(&var_id).unwrap(),
-                    self, scopes,
-                    &mut value_ids,
-                    &mut scope_ids,
-                );
-                for i in scope_ids {
-                    if tracker.marked_scopes.contains(&i) {
-                        self.mark(scopes, i, tracker);
-                    }
-                }
-                for i in value_ids {
-                    tracker.marked_values.remove(&i);
-                }
-            }
-        }
-        let parent_id = scopes.register.get(&scope_id).unwrap().parent_id;
-        match parent_id {
-            Some(id) => if tracker.marked_scopes.contains(&id) { self.mark(scopes, id, tracker) },
-            None => (),
-        }
-        let caller_id = scopes.register.get(&scope_id).unwrap().caller_id;
-        match caller_id {
-            Some(id) => if tracker.marked_scopes.contains(&id) { self.mark(scopes, id, tracker) },
-            None => (),
-        }
+impl Default for ScopeList {
+    fn default() -> Self {
+        Self::new()
     }
-
 }
 
 impl ScopeList {
     pub fn new() -> Self {
-        let mut register = HashMap::new();
+        let mut register = ScopeMap::default();
         register.insert(0, Scope::new());
-        return ScopeList {counter: 0, register }
+        ScopeList {counter: 0, register, globals: Globals::new(), released: 0, spare_vars: Vec::new(), call_stack: Vec::new(), budget: Budget::default() }
     }
 
-    pub fn get_var_id(&self, name: String, scope_id: RegIndex) -> Option<RegIndex> {
-        if let Some(value) = self.register.get(&scope_id).unwrap().vars.get(&name) {
-            return Some(*value);
-        } else {
-            match self.register.get(&scope_id).unwrap().parent_id {
-                Some(id) => self.get_var_id(name, id),
-                None => None,
-            }
+    /// The scope `depth` parents up from `scope_id`.
+    fn ancestor(&self, mut scope_id: ScopeId, depth: usize) -> ScopeId {
+        for _ in 0..depth {
+            scope_id = self.register[&scope_id].parent_id.unwrap();
         }
+        scope_id
     }
 
-    pub fn set_var(&mut self, name: String, scope_id: RegIndex, memory: &mut Memory, value: &Value, first_call: bool) -> bool {
-        if let Some(id) = self.register.get(&scope_id).unwrap().vars.get(&name) {
-            memory.set(value.clone(), *id);
-            return true
+    pub fn get_var(&self, scope_id: ScopeId, slot: Slot) -> Option<RegIndex> {
+        let scope = &self.register[&self.ancestor(scope_id, slot.depth)];
+        scope.vars.get(slot.index).copied().flatten()
+    }
+
+    /// Stores `value` in the variable at `slot`, binding it if it isn't yet.
+    pub fn set_var(&mut self, scope_id: ScopeId, slot: Slot, memory: &mut Memory, value: &Value) -> Result<(), BaseError> {
+        let scope_id = self.ancestor(scope_id, slot.depth);
+        match self.get_var(scope_id, Slot { depth: 0, index: slot.index }) {
+            Some(id) => memory.set(value.clone(), id),
+            None => { self.set_var_local(scope_id, slot.index, memory, value); Ok(()) },
         }
-        if let Some(parent_id) = self.register.get(&scope_id).unwrap().parent_id {
-            let success = self.set_var(name.clone(), parent_id, memory, value, false);
-            if success {return true;}
+    }
+
+    /// Binds the variable at `index` in `scope_id` to a new value, even if it already has one.
+    pub fn set_var_local(&mut self, scope_id: ScopeId, index: usize, memory: &mut Memory, value: &Value) -> RegIndex {
+        let id = memory.add(value.clone());
+        self.bind(scope_id, index, id);
+        id
+    }
+
+    pub(crate) fn bind(&mut self, scope_id: ScopeId, index: usize, id: RegIndex) {
+        let vars = &mut self.register.get_mut(&scope_id).unwrap().vars;
+        if index >= vars.len() {
+            vars.resize(index + 1, None);
         }
-        if first_call {
-            memory.add(value.clone());
-            self.register.get_mut(&scope_id).unwrap().vars.insert(name, memory.counter);
-            return true
+        vars[index] = Some(id);
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<RegIndex> {
+        let index = *self.globals.get(name)?;
+        self.get_var(0, Slot { depth: 0, index })
+    }
+
+    pub fn set_global(&mut self, name: &str, memory: &mut Memory, value: &Value) {
+        let len = self.globals.len();
+        let index = *self.globals.entry(name.to_string()).or_insert(len);
+        if self.set_var(0, Slot { depth: 0, index }, memory, value).is_err() {
+            // the old value was collected, so bind the name afresh
+            self.set_var_local(0, index, memory, value);
+        }
+    }
+
+    /// Frees a scope from `derive_scope` once its block, iteration or call
+    /// is done, unless a closure captured it. Its values are left to the
+    /// collector, as other values may point to them.
+    pub fn release(&mut self, scope_id: ScopeId) {
+        if self.register.get(&scope_id).is_some_and(|scope| !scope.captured) {
+            let mut vars = self.register.remove(&scope_id).unwrap().vars;
+            self.released += 1;
+            if self.spare_vars.len() < MAX_SPARE_VARS {
+                vars.clear();
+                self.spare_vars.push(vars);
+            }
         }
-        return false
     }
 
-    pub fn set_var_local(&mut self, name: String, scope_id: RegIndex, memory: &mut Memory, value: &Value) -> bool {
-        memory.add(value.clone());
-        self.register.get_mut(&scope_id).unwrap().vars.insert(name, memory.counter);
-        return true
+    /// Keeps the scope a closure is created in, and the scopes it sees
+    /// through, from being released.
+    pub(crate) fn capture(&mut self, scope_id: ScopeId) {
+        let mut next = Some(scope_id);
+        while let Some(scope) = next.and_then(|id| self.register.get_mut(&id)) {
+            if scope.captured { break }
+            scope.captured = true;
+            next = scope.parent_id;
+        }
     }
 
 }
 
 
+impl Default for Scope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Scope {
     pub fn new() -> Self {
-        return Scope {parent_id: None, caller_id: None, vars: HashMap::new()};
+        Scope {parent_id: None, caller_id: None, vars: Vec::new(), captured: true}
     }
 }
 
@@ -290,65 +315,162 @@ macro_rules! protecute_id {
 
 
 
+fn dangling() -> BaseError {
+    BaseError::interpreter(ErrorCode::DanglingReference, "Use of a value that has already been collected")
+}
+
 macro_rules! error_out {
-    ( $message:expr ) => {
-        { return Err(BaseError::InterpreterError($message.to_string())); }
+    ( $code:ident, $message:expr ) => {
+        { return Err(BaseError::interpreter(ErrorCode::$code, $message).into()); }
+    }
+}
+
+fn top_level(result: ExecResult) -> ValueResult {
+    match result {
+        Ok(value) => Ok(value),
+        Err(Signal::Error(err)) => Err(err),
+        // the parser rejects these outside of loops and functions
+        Err(Signal::Break(_) | Signal::Continue) => Err(BaseError::interpreter(ErrorCode::InvalidControlFlow, "'break' or 'continue' outside of a loop")),
+        Err(Signal::Return(_) | Signal::TailCall(_)) => Err(BaseError::interpreter(ErrorCode::InvalidControlFlow, "'return' outside of a function")),
     }
 }
 
-pub fn start_execute(node: &ASTNode, scopes: &mut ScopeList, memory: &mut Memory) -> ValueResult {
+pub fn start_execute(node: &Node, scopes: &mut ScopeList, memory: &mut Memory) -> ValueResult {
+
+    memory.protected.clear();
+    scopes.call_stack.clear();
+    scopes.budget.start();
+    top_level(execute(node, 0, memory, scopes))
+
+}
+
+/// Calls a function or builtin from outside of any script, as if from the global scope.
+pub fn call_value(base: Value, args: Vec<Value>, scopes: &mut ScopeList, memory: &mut Memory) -> ValueResult {
 
     memory.protected.clear();
-    execute(node, 0, memory, scopes)
+    scopes.call_stack.clear();
+    scopes.budget.start();
+    memory.new_protected();
+    top_level(call(base, args, 0, memory, scopes))
+
+}
+
+/// Calls a function or builtin from the VM, in the scope the VM is in.
+pub(crate) fn call_function(base: Value, args: Vec<Value>, scope_id: ScopeId, memory: &mut Memory, scopes: &mut ScopeList) -> ValueResult {
+    top_level(call(base, args, scope_id, memory, scopes))
+}
 
+/// Accounts for `steps` evaluation steps, and collects garbage when the
+/// policy or the memory limit asks for it.
+pub(crate) fn tick(steps: u64, scope_id: ScopeId, memory: &mut Memory, scopes: &mut ScopeList) -> Result<(), BaseError> {
+    scopes.budget.step(steps)?;
+    memory.maybe_collect(scopes, scope_id, steps);
+    if scopes.budget.memory_exceeded(memory.len()) {
+        memory.collect(scopes, scope_id);
+        if scopes.budget.memory_exceeded(memory.len()) {
+            return Err(limit_error(format!("Memory limit of {} values exceeded", scopes.budget.limits.max_memory.unwrap())))
+        }
+    }
+    Ok(())
 }
 
 #[derive(PartialEq, Eq, Hash, Debug)]
 enum VarExistence {
-    Name(String),
+    Unbound { name: String, slot: Slot },
     Id(RegIndex),
     IdErr{id: RegIndex, err: String},
 }
 
-fn get_value_id(node: &ASTNode, assign: bool, scope_id: RegIndex, memory: &mut Memory, scopes: &mut ScopeList) 
-    -> Result<VarExistence,BaseError>
+/// With `assign` set, indexing a dictionary with a missing key inserts
+/// that key so that plain assignment can create entries.
+fn get_value_id(node: &Node, assign: bool, scope_id: ScopeId, memory: &mut Memory, scopes: &mut ScopeList) 
+    -> Result<VarExistence,Signal>
 {
-    match node {
-        ASTNode::Var { name } => match scopes.get_var_id(name.clone(), scope_id) {
-            Some(id) => Ok(VarExistence::Id(id)),
-            None => Ok(VarExistence::Name(name.clone())),
+    match &node.kind {
+        ASTNode::Var { name, slot } => {
+            let Some(slot) = *slot else { error_out!(Internal, format!("Variable {} was never resolved", name)) };
+            match scopes.get_var(scope_id, slot) {
+                Some(id) => Ok(VarExistence::Id(id)),
+                None => Ok(VarExistence::Unbound { name: name.clone(), slot }),
+            }
         },
         ASTNode::Index { base, index } => {
-            let i = match protecute!(index, scope_id, memory, scopes) {
-                Value::Number(value) => value.floor(),
-                _ => error_out!("Cannot index with type")
-            } as isize;
-            let base_id = get_value_id(base, assign, scope_id, memory, scopes)?;
-            let base_value = match base_id {
-                VarExistence::Name(name) => error_out!(format!("Unknown variable {}", name)),
-                VarExistence::Id(id) => memory.get(id).clone(),
-                VarExistence::IdErr { id , err: _} => memory.register.get(&id).unwrap().clone(),
+            let index_value = protecute!(index, scope_id, memory, scopes);
+            let base_id = get_base_id(base, scope_id, memory, scopes)?;
+            let base_value = memory.get(base_id)?.clone();
+            if let Value::Dict(_) = base_value {
+                let key = DictKey::from_value(&index_value)?;
+                return match dict_entry(base_id, &key, assign, memory)? {
+                    Some(id) => Ok(VarExistence::Id(id)),
+                    None => error_out!(KeyNotFound, format!("Key '{}' not found in dictionary", key.to_value().to_str(memory))),
+                }
+            }
+            let i = match index_value.to_index() {
+                Some(i) => i,
+                None => error_out!(TypeMismatch, "Cannot index with type")
             };
-            //println!("{:#?}",base_value);
             match base_value {
 
                 Value::Array(arr) => if i >= arr.len() as isize || i < 0 {
-                    error_out!("Index out of bounds")
+                    error_out!(IndexOutOfBounds, "Index out of bounds")
                 } else { Ok(VarExistence::Id(arr[i as usize])) },
 
                 Value::String(s) => if i >= s.chars().count() as isize || i < 0 {
-                    error_out!("String index out of bounds")
+                    error_out!(IndexOutOfBounds, "String index out of bounds")
                 } else { Ok(VarExistence::IdErr{
                     id: memory.add( Value::String(s.chars().nth(i as usize).unwrap().to_string()) ),
                     err: "Can't assign to string index".to_string()
                 }   ) },
                 
-                _ => error_out!("Type cannot be indexed"),
+                _ => error_out!(TypeMismatch, "Type cannot be indexed"),
+            }
+        }
+        ASTNode::Member { base, name } => {
+            let base_id = get_base_id(base, scope_id, memory, scopes)?;
+            // fields shadow methods of the same name
+            if let Some(id) = dict_entry(base_id, &DictKey::String(name.clone()), false, memory)? {
+                return Ok(VarExistence::Id(id))
+            }
+            let base_value = memory.get(base_id)?;
+            if methods::find_method(base_value, name).is_some() {
+                let method = Value::Method { receiver: base_id, name: name.clone() };
+                return Ok(VarExistence::IdErr{
+                    id: memory.protect_id(method),
+                    err: format!("Can't assign to method '{}'", name),
+                })
+            }
+            match base_value {
+                Value::Dict(_) if assign => Ok(VarExistence::Id( dict_entry(base_id, &DictKey::String(name.clone()), true, memory)?.unwrap() )),
+                Value::Dict(_) => error_out!(KeyNotFound, format!("Key '{}' not found in dictionary", name)),
+                _ => error_out!(UnknownMember, format!("{} has no field or method '{}'", base_value.type_name(), name)),
             }
         }
         _ => Ok(VarExistence::Id( protecute_id!(node, scope_id, memory, scopes) )),
     }
 }
+
+/// Resolves the base of an index or member access to the id of its value.
+fn get_base_id(base: &Node, scope_id: ScopeId, memory: &mut Memory, scopes: &mut ScopeList) -> Result<RegIndex, Signal> {
+    match get_value_id(base, false, scope_id, memory, scopes)? {
+        VarExistence::Unbound { name, slot: _ } => error_out!(UnknownVariable, format!("Unknown variable {}", name)),
+        VarExistence::Id(id) => Ok(id),
+        VarExistence::IdErr { id , err: _} => Ok(id),
+    }
+}
+
+/// Looks up `key` in the dictionary at `dict_id`, inserting it as `Null`
+/// when it's missing and `insert` is set.
+pub(crate) fn dict_entry(dict_id: RegIndex, key: &DictKey, insert: bool, memory: &mut Memory) -> Result<Option<RegIndex>, BaseError> {
+    let mut map = match memory.get(dict_id)? {
+        Value::Dict(map) if map.contains_key(key) => return Ok(map.get(key).copied()),
+        Value::Dict(map) if insert => map.clone(),
+        _ => return Ok(None),
+    };
+    let id = memory.add(Value::Null);
+    map.insert(key.clone(), id);
+    memory.set(Value::Dict(map), dict_id)?;
+    Ok(Some(id))
+}
 #[derive(Debug)]
 enum DestructureValue {
     Single(RegIndex),
@@ -358,36 +480,33 @@ enum DestructureValue {
 
 type DestructureMap = HashMap<VarExistence, DestructureValue>;
 
-fn assign(left: &ASTNode, right_id: RegIndex, map: &mut DestructureMap, spread: bool, scope_id: RegIndex, memory: &mut Memory, scopes: &mut ScopeList) -> Result<usize, BaseError> {
+fn assign(left: &Node, right_id: RegIndex, map: &mut DestructureMap, spread: bool, scope_id: ScopeId, memory: &mut Memory, scopes: &mut ScopeList) -> Result<usize, Signal> {
     
-    match left {
+    match &left.kind {
         ASTNode::Array { values: l_values } => {
-            match memory.get(right_id).clone() {
+            match memory.get(right_id)?.clone() {
                 Value::Array(r_values) => {
                     let mut spreads = Vec::new();
                     let mut spread_values = Vec::new();
                     let mut spread_vars: usize = 0;
                     for i in l_values {
-                        match i {
+                        match &i.kind {
                             ASTNode::Unary { op: Token::Range, value: v } => { spreads.push(true); spread_values.push(&**v); spread_vars += 1 },
                             _ => { spreads.push(false) },
                         }
                     }
                     if spread_vars > 0 {
-                        //println!("{:?} {:?}     {:?}",l_values.len(),spreads.len(),r_values.len());
                         if l_values.len() - spread_vars > r_values.len() {
-                            error_out!(format!("Not enough values to destructure, expected at least {}", l_values.len() - spreads.len()))
+                            error_out!(Destructure, format!("Not enough values to destructure, expected at least {}", l_values.len() - spread_vars))
                         }
                         let mut spread_lengths = Vec::new();
                         let spread_amount = r_values.len() - (l_values.len() - spread_vars);
                         let modulo = spread_amount % spread_vars;
-                        //println!("{:?} {:?}",spread_amount,modulo);
                         for i in 0..spread_vars {
                             spread_lengths.push(
                                 spread_amount / spread_vars + if i < modulo {1} else {0}
                             )
                         }
-                        //println!("{:?}",spread_lengths);
                         let mut current_r_value: usize = 0;
                         let mut current_spread: usize = 0;
                         for (i, s) in spreads.iter().enumerate() {
@@ -418,7 +537,7 @@ fn assign(left: &ASTNode, right_id: RegIndex, map: &mut DestructureMap, spread:
 
                     } else {
                         if l_values.len() != r_values.len() {
-                            error_out!(format!("Inequal amount of values to destructure, expected {}", l_values.len()))
+                            error_out!(Destructure, format!("Inequal amount of values to destructure, expected {}", l_values.len()))
                         }
                         for (i, j) in l_values.iter().zip(r_values.iter()) {
                             assign(i, *j, map, spread, scope_id, memory, scopes)?;
@@ -427,11 +546,24 @@ fn assign(left: &ASTNode, right_id: RegIndex, map: &mut DestructureMap, spread:
                     
                     
                 },
-                _ => error_out!("Cannot destructure non-array")
+                _ => error_out!(Destructure, "Cannot destructure non-array")
+            }
+        },
+        ASTNode::Dict { entries } => {
+            match memory.get(right_id)?.clone() {
+                Value::Dict(r_map) => {
+                    for (key, pattern) in entries {
+                        match r_map.get(key) {
+                            Some(id) => { assign(pattern, *id, map, spread, scope_id, memory, scopes)?; },
+                            None => error_out!(Destructure, format!("Cannot destructure missing key '{}'", key.to_value().to_str(memory))),
+                        }
+                    }
+                },
+                _ => error_out!(Destructure, "Cannot destructure non-dictionary")
             }
         },
         _ => {
-            let left_id = get_value_id(left, false, scope_id, memory, scopes)?;
+            let left_id = get_value_id(left, true, scope_id, memory, scopes)?;
             if !spread {
                 map.insert(
                     left_id
@@ -450,60 +582,163 @@ fn assign(left: &ASTNode, right_id: RegIndex, map: &mut DestructureMap, spread:
 
 }
 
-fn execute(node: &ASTNode, scope_id: RegIndex, memory: &mut Memory, scopes: &mut ScopeList) -> ValueResult {
-    //println!("\n\n{:#?}\nscope_id: {},\n{:#?}\n{:#?}",memory,scope_id,scopes,node);
-    //println!("{:?}", memory.protected);
-    
-    if memory.register.len() > 50000 + memory.last_amount {
-        memory.collect(scopes, scope_id);
+/// Assigns the value at `right_id` to `left`, destructuring arrays and spreads.
+fn destructure(left: &Node, right_id: RegIndex, scope_id: ScopeId, memory: &mut Memory, scopes: &mut ScopeList) -> Result<(), Signal> {
+    let mut map = HashMap::new();
+    assign(left, right_id, &mut map, false, scope_id, memory, scopes)?;
+
+    for (var, d_val) in map {
+        let value = match d_val {
+            DestructureValue::Single(id) => memory.get(id)?.clone(),
+            DestructureValue::Spread(id_arr) => Value::Array(id_arr),
+        };
+        match var {
+            VarExistence::Id(id) => memory.set(value, id)?,
+            VarExistence::Unbound { name: _, slot } => scopes.set_var(scope_id, slot, memory, &value)?,
+            VarExistence::IdErr { id: _, err } => error_out!(InvalidAssignment, err),
+        }
+    }
+    Ok(())
+}
+
+/// Evaluates the operand of a `..` spread to the ids of the elements it splices in.
+fn spread(node: &Node, scope_id: ScopeId, memory: &mut Memory, scopes: &mut ScopeList) -> Result<Vec<RegIndex>, Signal> {
+    match protecute!(node, scope_id, memory, scopes) {
+        Value::Array(arr) => Ok(arr),
+        value => Err(
+            BaseError::interpreter(ErrorCode::TypeMismatch, format!("Cannot spread {}", value.type_name()))
+                .with_span(node.span)
+                .into()
+        ),
     }
+}
+
+fn call(base: Value, args: Vec<Value>, scope_id: ScopeId, memory: &mut Memory, scopes: &mut ScopeList) -> ExecResult {
+    let val = match base {
+        Value::Builtin(native) => {
+            native.call(&mut NativeContext { memory, scopes, scope_id }, args)?
+        }
+        Value::Function { mut def, scope_id: mut def_scope } => {
+            let mut args = args;
+            loop {
+                let arg_names = &def.arg_names;
+                match def.rest {
+                    None if args.len() != arg_names.len() =>
+                        error_out!(ArgumentCount, format!{"Expected {} argument(s)", arg_names.len()}),
+                    Some(_) if args.len() < arg_names.len() =>
+                        error_out!(ArgumentCount, format!{"Expected at least {} argument(s)", arg_names.len()}),
+                    _ => (),
+                }
+
+                let run_scope = derive_scope(def_scope, scope_id, scopes);
+                // the resolver gives the parameters the first slots, in order
+                for (i, arg) in args.iter().take(arg_names.len()).enumerate() {
+                    scopes.set_var_local(run_scope, i, memory, arg);
+                }
+                if def.rest.is_some() {
+                    let extra = args[arg_names.len()..].iter().map(|v| memory.add(v.clone())).collect();
+                    scopes.set_var_local(run_scope, arg_names.len(), memory, &Value::Array(extra));
+                }
+
+                let result = execute(&def.code, run_scope, memory, scopes);
+                scopes.release(run_scope);
+                match result {
+                    Ok(value) | Err(Signal::Return(value)) => break value,
+                    // nothing collects before the arguments are bound again
+                    Err(Signal::TailCall(tail)) => {
+                        if let Some(top) = scopes.call_stack.last_mut() {
+                            *top = CallFrame { tail_calls: top.tail_calls + 1, ..tail.frame };
+                        }
+                        (def, def_scope, args) = (tail.def, tail.def_scope, tail.args);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Value::Closure { proto, scope_id: def_scope } => vm::call_closure(proto, def_scope, args, scope_id, memory, scopes)?,
+        Value::Method { receiver, name } => {
+            let receiver_value = memory.get(receiver)?;
+            let method = match methods::find_method(receiver_value, &name) {
+                Some(method) => method,
+                None => error_out!(InvalidCall, format!("{} has no method '{}'", receiver_value.type_name(), name)),
+            };
+            if args.len() != method.arity {
+                error_out!(ArgumentCount, format!{"Expected {} argument(s)", method.arity})
+            }
+            (method.func)(receiver, &args, memory)?
+        }
+        _ => error_out!(InvalidCall, "Invalid base for call")
+    };
+    Ok(val)
+}
+
+fn execute(node: &Node, scope_id: ScopeId, memory: &mut Memory, scopes: &mut ScopeList) -> ExecResult {
+    let protected_depth = memory.protected.len();
+    stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || execute_kind(node, scope_id, memory, scopes)).map_err(|signal| {
+        // unwinding skips the `pop_protected` calls of the nodes in between
+        memory.protected.truncate(protected_depth);
+        match signal {
+            Signal::Error(err) => Signal::Error(err.or_span(node.span)),
+            signal => signal,
+        }
+    })
+}
+
+fn execute_kind(node: &Node, scope_id: ScopeId, memory: &mut Memory, scopes: &mut ScopeList) -> ExecResult {
+    tick(1, scope_id, memory, scopes)?;
 
-    //println!("{:?}",node);
     memory.new_protected();
 
-    let val = match node {
+    let val = match &node.kind {
         ASTNode::Unary { op, value } => {
             let value = protecute!(value, scope_id, memory, scopes);
             match op {
                 crate::lexer::Token::Plus => value.give()?,
                 crate::lexer::Token::Minus => value.neg()?,
                 crate::lexer::Token::Not => value.not()?,
-                crate::lexer::Token::Range => error_out!("spreads not added yet lol peepeepoopoo"),
-                _ => error_out!("Non '+','-','!' unary operation")
+                // the parser only lets spreads through where arrays and calls splice them
+                crate::lexer::Token::Range => error_out!(Internal, "Spread outside of an array or call arguments"),
+                _ => error_out!(Internal, "Non '+','-','!' unary operation")
             }
         },
         ASTNode::Op { left, op, right } => {
             match op {
-                Token::Plus | Token::Minus | Token::Mult | Token::Div | Token::Mod | Token::Pow | Token::Greater | Token::Lesser | Token::GreaterEq | Token::LesserEq | Token::Eq | Token::NotEq => {
+                Token::Plus | Token::Minus | Token::Mult | Token::Div | Token::Mod | Token::Pow | Token::Greater | Token::Lesser | Token::GreaterEq | Token::LesserEq | Token::Eq | Token::NotEq | Token::Range => {
+                    let (left_span, right_span) = (left.span, right.span);
                     let left = protecute!(left, scope_id, memory, scopes);
                     let right = protecute!(right, scope_id, memory, scopes);
 
-                    match op {
-                        Token::Plus => left.plus(&right)?,
-                        Token::Minus => left.minus(&right)?,
-                        Token::Mult => left.mult(&right)?,
-                        Token::Div => left.div(&right)?,
-                        Token::Mod => left.rem(&right)?,
-                        Token::Pow => left.pow(&right)?,
-                        Token::Greater => left.gr(&right)?,
-                        Token::GreaterEq => left.greq(&right)?,
-                        Token::Lesser => left.sm(&right)?,
-                        Token::LesserEq => left.smeq(&right)?,
-                        Token::Eq => left.eq(&right, memory)?,
-                        Token::NotEq => left.neq(&right)?,
+                    let result = match op {
+                        Token::Plus => left.plus(&right),
+                        Token::Minus => left.minus(&right),
+                        Token::Mult => left.mult(&right),
+                        Token::Div => left.div(&right),
+                        Token::Mod => left.rem(&right),
+                        Token::Pow => left.pow(&right),
+                        Token::Greater => left.gr(&right),
+                        Token::GreaterEq => left.greq(&right),
+                        Token::Lesser => left.sm(&right),
+                        Token::LesserEq => left.smeq(&right),
+                        Token::Eq => left.eq(&right, memory),
+                        Token::NotEq => left.neq(&right, memory),
+                        Token::Range => left.range(&right),
                         _ => unimplemented!(),
-                    }
+                    };
+                    result.map_err(|err| err
+                        .with_label(left_span, format!("this is {}", left.type_name()))
+                        .with_label(right_span, format!("this is {}", right.type_name()))
+                    )?
 
                 }
                 
                 Token::PlusEq | Token::MinusEq | Token::MultEq | Token::DivEq | Token::ModEq | Token::PowEq  => {
                     let right_eval = protecute!(right, scope_id, memory, scopes);
-                    let value_id = match get_value_id(left, true, scope_id, memory, scopes)? {
+                    let value_id = match get_value_id(left, false, scope_id, memory, scopes)? {
                         VarExistence::Id(id) => id,
-                        VarExistence::Name(name) => error_out!(format!("Unknown variable {}", name)),
-                        VarExistence::IdErr { id: _, err } => error_out!(err),
+                        VarExistence::Unbound { name, slot: _ } => error_out!(UnknownVariable, format!("Unknown variable {}", name)),
+                        VarExistence::IdErr { id: _, err } => error_out!(InvalidAssignment, err),
                     };
-                    let value = memory.register.get(&value_id).unwrap();
+                    let value = memory.get(value_id)?;
                     let new_value = match op {
                         Token::PlusEq => value.plus(&right_eval)?,
                         Token::MinusEq => value.minus(&right_eval)?,
@@ -513,35 +748,22 @@ fn execute(node: &ASTNode, scope_id: RegIndex, memory: &mut Memory, scopes: &mut
                         Token::PowEq => value.pow(&right_eval)?,
                         _ => unimplemented!(),
                     };
-                    memory.set(new_value.clone(), value_id);
+                    memory.set(new_value.clone(), value_id)?;
                     new_value
                 },
                 Token::Assign => {
-                    let mut map = HashMap::new();
                     let right_eval_id = protecute_id!(right, scope_id, memory, scopes);
-                    assign(left, right_eval_id, &mut map, false, scope_id, memory, scopes)?;
-
-                    for (var, d_val) in map {
-                        let value = match d_val {
-                            DestructureValue::Single(id) => memory.register.get(&id).unwrap().clone(),
-                            DestructureValue::Spread(id_arr) => Value::Array(id_arr),
-                        };
-                        match var {
-                            VarExistence::Id(id) => memory.set(value.clone(), id),
-                            VarExistence::Name(name) => {scopes.set_var(name, scope_id, memory, &value, true);},
-                            VarExistence::IdErr { id: _, err } => error_out!(err),
-                        }
-                    }
-                    memory.get(right_eval_id).clone()
+                    destructure(left, right_eval_id, scope_id, memory, scopes)?;
+                    memory.get(right_eval_id)?.clone()
                 }
                 Token::LocalAssign => {
                     let right_eval = protecute!(right, scope_id, memory, scopes);
-                    match (**left).clone() {
-                        ASTNode::Var { name } => {
-                            scopes.set_var_local(name, scope_id, memory, &right_eval);
+                    match left.kind.clone() {
+                        ASTNode::Var { name: _, slot: Some(slot) } => {
+                            scopes.set_var_local(scope_id, slot.index, memory, &right_eval);
                             right_eval
                         },
-                        _ => error_out!("Expected variable name")
+                        _ => error_out!(InvalidAssignment, "Expected variable name")
                     }
                 }
                 
@@ -577,11 +799,11 @@ fn execute(node: &ASTNode, scope_id: RegIndex, memory: &mut Memory, scopes: &mut
                 _ => todo!()
             }
         },
-        ASTNode::Var { name: _ } => {
+        ASTNode::Var { .. } => {
             match get_value_id(node, false, scope_id, memory, scopes)? {
-                VarExistence::Name(name) => error_out!(format!("Unknown variable {}", name)),
-                VarExistence::Id(id) => memory.register.get(&id).unwrap().clone(),
-                _ => error_out!("if you get this error lemme know wtf ur code was")
+                VarExistence::Unbound { name, slot: _ } => error_out!(UnknownVariable, format!("Unknown variable {}", name)),
+                VarExistence::Id(id) => memory.get(id)?.clone(),
+                _ => error_out!(Internal, "if you get this error lemme know wtf ur code was")
             }
         },
         ASTNode::StatementList { statements } => {
@@ -595,7 +817,10 @@ fn execute(node: &ASTNode, scope_id: RegIndex, memory: &mut Memory, scopes: &mut
             for i in conds {
                 if protecute!(&i.0, scope_id, memory, scopes).to_bool()? {
                     memory.pop_protected();
-                    return Ok( execute(&i.1, derive_scope(scope_id, scope_id, scopes), memory, scopes)? )
+                    let run_scope = derive_scope(scope_id, scope_id, scopes);
+                    let result = execute(&i.1, run_scope, memory, scopes);
+                    scopes.release(run_scope);
+                    return result
                 }
             }
 
@@ -607,142 +832,157 @@ fn execute(node: &ASTNode, scope_id: RegIndex, memory: &mut Memory, scopes: &mut
         ASTNode::While { cond, code } => {
             let mut last = Value::Null;
             loop {
-                if protecute!(cond, scope_id, memory, scopes).to_bool()? {
-                    last = protecute!(code, derive_scope(scope_id, scope_id, scopes), memory, scopes);
-                } else { memory.pop_protected(); return Ok( last ) ; }
+                // earlier iterations are only reachable through `last` by now
+                memory.truncate_protected(0);
+                last = memory.protect(last);
+                if !protecute!(cond, scope_id, memory, scopes).to_bool()? { break }
+                let run_scope = derive_scope(scope_id, scope_id, scopes);
+                let result = execute(code, run_scope, memory, scopes);
+                scopes.release(run_scope);
+                match result {
+                    Ok(value) => last = memory.protect(value),
+                    Err(Signal::Break(value)) => { last = value; break },
+                    Err(Signal::Continue) => (),
+                    Err(signal) => return Err(signal),
+                }
             }
+            last
+        },
+        ASTNode::For { pattern, iter, code } => {
+            let iterable = protecute!(iter, scope_id, memory, scopes);
+            let chars: Vec<char> = match &iterable {
+                Value::String(s) => s.chars().collect(),
+                Value::Array(_) | Value::Range(..) => Vec::new(),
+                _ => return Err(
+                    BaseError::interpreter(ErrorCode::TypeMismatch, format!("Cannot iterate over {}", iterable.type_name()))
+                        .with_span(iter.span)
+                        .into()
+                ),
+            };
+
+            let mut last = Value::Null;
+            for index in 0.. {
+                // keep the iterable, earlier iterations are only reachable through `last`
+                memory.truncate_protected(1);
+                last = memory.protect(last);
+                let item_id = match &iterable {
+                    Value::Array(arr) => match arr.get(index) {
+                        Some(id) => *id,
+                        None => break,
+                    },
+                    Value::String(_) => match chars.get(index) {
+                        Some(c) => memory.protect_id(Value::String(c.to_string())),
+                        None => break,
+                    },
+                    Value::Range(start, end) => {
+                        let n = start + index as f64;
+                        if n >= *end { break }
+                        memory.protect_id(Value::range_item(n))
+                    },
+                    _ => unreachable!(),
+                };
+
+                let run_scope = derive_scope(scope_id, scope_id, scopes);
+                destructure(pattern, item_id, run_scope, memory, scopes)?;
+                let result = execute(code, run_scope, memory, scopes);
+                scopes.release(run_scope);
+                match result {
+                    Ok(value) => last = memory.protect(value),
+                    Err(Signal::Break(value)) => { last = value; break },
+                    Err(Signal::Continue) => (),
+                    Err(signal) => return Err(signal),
+                }
+            }
+            last
+        },
+        ASTNode::Break { value } => {
+            let value = match &**value {
+                Some(node) => execute(node, scope_id, memory, scopes)?,
+                None => Value::Null,
+            };
+            return Err(Signal::Break(value))
+        },
+        ASTNode::Continue => return Err(Signal::Continue),
+        ASTNode::Return { value } => {
+            let value = match &**value {
+                Some(node) => execute(node, scope_id, memory, scopes)?,
+                None => Value::Null,
+            };
+            return Err(Signal::Return(value))
         },
         ASTNode::Value { value } => value.clone(),
-        ASTNode::Block { code } =>
-            protecute!(code, derive_scope(scope_id, scope_id, scopes), memory, scopes),
-        ASTNode::Func { code, arg_names } => {
-            Value::Function {arg_names: arg_names.clone(), code: code.clone(), scope_id}
+        ASTNode::Block { code } => {
+            let run_scope = derive_scope(scope_id, scope_id, scopes);
+            let result = execute(code, run_scope, memory, scopes);
+            scopes.release(run_scope);
+            memory.protect(result?)
+        },
+        ASTNode::Func { def } => {
+            scopes.capture(scope_id);
+            Value::Function {def: def.clone(), scope_id}
         }
-        ASTNode::Call { base, args } => {
-            match protecute!(base, scope_id, memory, scopes) {
-                Value::Builtin(name) => {
-                    match &name[..] {
-                        "sin" => {
-                            if args.len() != 1 {error_out!("Expected 1 argument")}
-                            let mut converted_args: Vec<Value> = Vec::new();
-                            for i in args {
-                                converted_args.push( protecute!(i, scope_id, memory, scopes) );
-                            }
-                            converted_args[0].sin()?
-                        }
-                        "cos" => {
-                            if args.len() != 1 {error_out!("Expected 1 argument")}
-                            let mut converted_args: Vec<Value> = Vec::new();
-                            for i in args {
-                                converted_args.push( protecute!(i, scope_id, memory, scopes) );
-                            }
-                            converted_args[0].cos()?
-                        }
-                        "tan" => {
-                            if args.len() != 1 {error_out!("Expected 1 argument")}
-                            let mut converted_args: Vec<Value> = Vec::new();
-                            for i in args {
-                                converted_args.push( protecute!(i, scope_id, memory, scopes) );
-                            }
-                            converted_args[0].tan()?
-                        }
-                        "print" => {
-                            if args.len() == 0 {
-                                print!("");
-                            } else {
-                                let mut strs = Vec::new();
-                                for i in args {
-                                    strs.push( protecute!(i, scope_id, memory, scopes).to_str(memory, &mut vec![]));
-                                }
-                                print!("{}",strs.join(""));
-                            }
-                            Value::Null
-                        }
-                        "println" => {
-                            if args.len() == 0 {
-                                println!("");
-                            } else {
-                                let mut strs = Vec::new();
-                                for i in args {
-                                    strs.push( protecute!(i, scope_id, memory, scopes).to_str(memory, &mut vec![]));
-                                }
-                                println!("{}",strs.join(""));
-                            }
-                            Value::Null
-                        }
-                        "memtest" => {
-                            println!("{:#?}",memory);
-                            println!("{:#?}",scopes);
-                            io::stdout().flush().unwrap();
-                            Value::Null
-                        }
-                        "collect" => {
-                            memory.collect(scopes, scope_id);
-                            Value::Null
-                        }
-                        "input" => {
-                            if args.len() != 1 {error_out!("Expected 1 argument")}
-                            let mut converted_args: Vec<Value> = Vec::new();
-                            for i in args {
-                                converted_args.push( protecute!(i, scope_id, memory, scopes) );
-                            }
-                            print!("{}", format!("{}",converted_args[0].to_str(memory, &mut vec![])));
-                            io::stdout().flush().unwrap();
-
-                            let mut input_str = String::new();
-                            io::stdin()
-                                .read_line(&mut input_str)
-                                .expect("Failed to read line");
-                            
-                            Value::String(
-                                input_str
-                                    .replace("\r", "")
-                                    .replace("\n", "")
-                            )
-                        }
-                        "len" => {
-                            if args.len() != 1 {error_out!("Expected 1 argument")}
-                            let mut converted_args: Vec<Value> = Vec::new();
-                            for i in args {
-                                converted_args.push( protecute!(i, scope_id, memory, scopes) );
-                            }
-                            converted_args[0].len()?
-                        }
-                        _ => unimplemented!(),
-                    }
+        ASTNode::Call { base, args, tail } => {
+            let base_value = protecute!(base, scope_id, memory, scopes);
+            let mut converted_args: Vec<Value> = Vec::new();
+            for i in args {
+                match &i.kind {
+                    ASTNode::Unary { op: Token::Range, value } => for id in spread(value, scope_id, memory, scopes)? {
+                        converted_args.push( memory.get(id)?.clone() );
+                    },
+                    _ => converted_args.push( protecute!(i, scope_id, memory, scopes) ),
                 }
-                Value::Function { arg_names, code, scope_id: def_scope } => {
-                    if args.len() != arg_names.len() {
-                        error_out!(format!{"Expected {} argument(s)", arg_names.len()})
-                    }
-                    let mut converted_args: Vec<Value> = Vec::new();
-                    for i in args {
-                        converted_args.push( protecute!(i, scope_id, memory, scopes) );
-                    }
-                    
-                    let run_scope = derive_scope(def_scope, scope_id, scopes);
-                    for (i, j) in arg_names.iter().zip(converted_args.iter()) {
-                        scopes.set_var(i.clone(), run_scope, memory, j, true);
-                    }
+            }
 
-                    execute(&code, run_scope, memory, scopes)?
+            let function = match &base.kind {
+                ASTNode::Var { name, .. } | ASTNode::Member { base: _, name } => name.as_str().into(),
+                _ => "<anonymous>".into(),
+            };
+            let builtin = match &base_value {
+                Value::Builtin(native) => Some(native.name.clone()),
+                Value::Method { receiver: _, name } => Some(name.clone()),
+                _ => None,
+            };
+            let frame = CallFrame { function, call_site: node.span, builtin, tail_calls: 0 };
+            if let (true, Value::Function { def, scope_id: def_scope }) = (*tail, &base_value) {
+                return Err(Signal::TailCall(Box::new(TailCall { def: def.clone(), def_scope: *def_scope, args: converted_args, frame })))
+            }
+            scopes.budget.check_call_depth(scopes.call_stack.len())?;
+            scopes.call_stack.push(frame);
+
+            let mut result = call(base_value, converted_args, scope_id, memory, scopes);
+            if let Err(Signal::Error(err)) = &mut result {
+                let diagnostic = err.diagnostic_mut();
+                if diagnostic.traceback.is_empty() {
+                    diagnostic.traceback = scopes.call_stack.clone();
                 }
-                _ => error_out!("Invalid base for call")
             }
+            scopes.call_stack.pop();
+            result?
         }
         ASTNode::Array {values} => {
 
             let mut eval_values = Vec::new();
             for i in values {
-                eval_values.push( protecute_id!(i, scope_id, memory, scopes) );
+                match &i.kind {
+                    ASTNode::Unary { op: Token::Range, value } => eval_values.extend( spread(value, scope_id, memory, scopes)? ),
+                    _ => eval_values.push( protecute_id!(i, scope_id, memory, scopes) ),
+                }
             }
             Value::Array(eval_values)
         }
-        ASTNode::Index { base: _, index: _ } => {
+        ASTNode::Dict {entries} => {
+
+            let mut map = BTreeMap::new();
+            for (key, value) in entries {
+                map.insert(key.clone(), protecute_id!(value, scope_id, memory, scopes));
+            }
+            Value::Dict(map)
+        }
+        ASTNode::Index { .. } | ASTNode::Member { .. } => {
             match get_value_id(node, false, scope_id, memory, scopes)? {
-                VarExistence::Id(id) => memory.register.get(&id).unwrap().clone(),
-                VarExistence::IdErr { id, err: _ } => memory.register.get(&id).unwrap().clone(),
-                _ => error_out!("if you get this error lemme know wtf ur code was")
+                VarExistence::Id(id) => memory.get(id)?.clone(),
+                VarExistence::IdErr { id, err: _ } => memory.get(id)?.clone(),
+                _ => error_out!(Internal, "if you get this error lemme know wtf ur code was")
             }
         }
     };
@@ -1,4 +1,30 @@
-use logos::Logos;
+use logos::{Filter, Lexer, Logos};
+
+use crate::{errors::{BaseError, ErrorCode}, int::Int};
+
+
+/// Location of a token or node in the source: a byte range plus the
+/// line and column (both starting at 1) where it begins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: u32,
+    pub end: u32,
+    pub line: u32,
+    pub col: u32,
+}
+
+impl Span {
+    /// Span covering everything from the start of `self` to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span { end: other.end.max(self.end), ..self }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
 
 
 fn convert_string(s: &str) -> String {
@@ -11,11 +37,39 @@ fn convert_string(s: &str) -> String {
         .replace("\\'", "'")
 }
 
+/// Skips a (possibly nested) block comment. Comments that span lines are
+/// emitted so that `lex` can end the line there, and unterminated ones
+/// swallow the rest of the source and are emitted so it can report them.
+fn block_comment(lex: &mut Lexer<Token>) -> Filter<bool> {
+    let rest = lex.remainder();
+    let mut depth = 1;
+    let mut chars = rest.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match (c, chars.peek()) {
+            ('/', Some((_, '*'))) => { chars.next(); depth += 1 },
+            ('*', Some((_, '/'))) => {
+                chars.next();
+                depth -= 1;
+                if depth == 0 {
+                    lex.bump(i + 2);
+                    return if rest[..i].contains('\n') { Filter::Emit(true) } else { Filter::Skip }
+                }
+            },
+            _ => (),
+        }
+    }
+    lex.bump(rest.len());
+    Filter::Emit(false)
+}
+
 #[derive(Logos, Debug, PartialEq, Clone)]
 pub enum Token {
-    #[regex(r"([0-9]+(\.[0-9]*)?|\.[0-9]*)", |lex| lex.slice().parse::<f64>())]
+    #[regex(r"([0-9]+\.[0-9]+|\.[0-9]+)", |lex| lex.slice().parse::<f64>())]
     Number(f64),
 
+    #[regex(r"[0-9]+", |lex| lex.slice().parse::<Int>())]
+    Int(Int),
+
     #[regex(r#""(?:\\.|[^\\"])*"|'(?:\\.|[^\\'])*'"#, 
         |s| convert_string(&s.slice()[1..s.slice().len()-1])
     )]
@@ -145,17 +199,99 @@ pub enum Token {
     Else,
     #[token("while")]
     While,
+    #[token("for")]
+    For,
+    #[token("in")]
+    In,
+    #[token("break")]
+    Break,
+    #[token("continue")]
+    Continue,
+    #[token("return")]
+    Return,
     #[token("as")]
     As,
 
     #[regex(r"[a-zA-Z_ඞ][a-zA-Z_0-9ඞ]*", |lex| lex.slice().to_string())]
     Identifier(String),
 
+    #[regex(r"///[^\n]*", |lex| lex.slice()[3..].trim().to_string())]
+    DocComment(String),
+
+    /// Whether the comment was closed.
+    #[token("/*", block_comment)]
+    BlockComment(bool),
+
     #[error]
     #[regex(r"[ \t\f]+", logos::skip)]
+    #[regex(r"//[^\n]*", logos::skip)]
     Error,
 
     Eof,
 }
 
+impl Token {
+    /// Tokens the lexer keeps for tooling but the parser ignores.
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, Token::DocComment(_))
+    }
+}
+
 
+/// Tracks line and column numbers while walking forward through the source.
+struct LineTracker<'a> {
+    code: &'a str,
+    line: usize,
+    line_start: usize,
+    scanned: usize,
+}
+
+impl<'a> LineTracker<'a> {
+    fn span(&mut self, range: std::ops::Range<usize>) -> Span {
+        for (i, c) in self.code[self.scanned..range.start].char_indices() {
+            if c == '\n' {
+                self.line += 1;
+                self.line_start = self.scanned + i + 1;
+            }
+        }
+        self.scanned = range.start;
+        Span {
+            start: range.start as u32,
+            end: range.end as u32,
+            line: self.line as u32,
+            col: (self.code[self.line_start..range.start].chars().count() + 1) as u32,
+        }
+    }
+}
+
+pub fn lex(code: &str) -> Result<Vec<SpannedToken>, BaseError> {
+    let mut tracker = LineTracker { code, line: 1, line_start: 0, scanned: 0 };
+    let mut tokens = Vec::new();
+
+    let mut lexer = Token::lexer(code);
+    while let Some(token) = lexer.next() {
+        let span = tracker.span(lexer.span());
+        if let Token::BlockComment(false) = token {
+            return Err(BaseError::lex(ErrorCode::UnterminatedComment, "Unterminated block comment", span)
+                .with_note("block comments nest, so every '/*' needs its own '*/'"));
+        }
+        if let Token::Error = token {
+            let slice = lexer.slice();
+            let first = slice.chars().next().unwrap_or_default();
+            let err = BaseError::lex(ErrorCode::UnexpectedCharacter, format!("Unexpected character '{}'", first), span);
+            return Err(if slice.starts_with('"') || slice.starts_with('\'') {
+                err.with_note("string literals must end with a matching quote")
+            } else {
+                err
+            });
+        }
+        // a comment across lines ends the statement before it, as its newline would
+        let token = if let Token::BlockComment(true) = token { Token::Eol } else { token };
+        tokens.push(SpannedToken { token, span });
+    }
+
+    let end = tracker.span(code.len()..code.len());
+    tokens.push(SpannedToken { token: Token::Eol, span: end });
+    tokens.push(SpannedToken { token: Token::Eof, span: end });
+    Ok(tokens)
+}
@@ -0,0 +1,43 @@
+//! BlueBat, a small dynamically typed scripting language.
+//!
+//! Embed it through [`Interpreter`]:
+//!
+//! ```
+//! let mut interp = bluebat::Interpreter::new();
+//! interp.eval("square = |x| x * x\n").unwrap();
+//! let nine = interp.call_function("square", vec![bluebat::Value::Number(3.0)]).unwrap();
+//! assert_eq!(interp.display(&nine), "9");
+//! ```
+//!
+//! Rust values cross the boundary through [`IntoValue`] and [`FromValue`],
+//! or with the `serde` feature through anything `Serialize`/`Deserialize`.
+
+pub mod lexer;
+pub mod parser;
+pub mod resolver;
+pub mod bytecode;
+pub mod compiler;
+pub mod blbc;
+pub mod errors;
+pub mod value;
+pub mod int;
+pub mod arena;
+pub mod interpreter;
+pub mod gc;
+pub mod vm;
+mod methods;
+pub mod limits;
+pub mod convert;
+#[cfg(feature = "serde")]
+pub mod serde_bridge;
+pub mod native;
+mod embed;
+
+pub use embed::{Backend, Interpreter};
+pub use convert::{ConversionError, FromValue, IntoValue};
+pub use errors::{BaseError, Error, ScriptError};
+pub use gc::{GcPolicy, GcStats};
+pub use limits::Limits;
+pub use native::{Arity, NativeContext, NativeFunction, ScriptIo, StdIo, StreamIo};
+pub use int::Int;
+pub use value::Value;
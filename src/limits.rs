@@ -0,0 +1,85 @@
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, time::{Duration, Instant}};
+
+use crate::errors::{BaseError, ErrorCode};
+
+
+/// How often the clock and the cancellation flag are looked at, in steps.
+const CHECK_INTERVAL: u64 = 256;
+
+/// Bounds on what a single `eval` or `call_function` may use. `None` means unlimited.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// Evaluation steps, one per node executed, or per instruction in the VM.
+    pub max_steps: Option<u64>,
+    /// Nested calls, counting builtins and methods.
+    pub max_call_depth: Option<usize>,
+    /// Values held in memory, after a collection tried to make room.
+    pub max_memory: Option<usize>,
+    pub timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_steps: None,
+            max_call_depth: Some(10_000),
+            max_memory: None,
+            timeout: None,
+        }
+    }
+}
+
+/// The limits together with what has been used of them so far.
+#[derive(Debug, Default)]
+pub struct Budget {
+    pub limits: Limits,
+    /// Set from any thread to stop the running script.
+    pub cancelled: Arc<AtomicBool>,
+    steps: u64,
+    deadline: Option<Instant>,
+}
+
+impl Budget {
+    /// Starts counting anew for a top-level run. The cancellation flag is
+    /// left alone, so that a cancel that came just before still counts.
+    pub fn start(&mut self) {
+        self.steps = 0;
+        self.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    /// Accounts for `count` evaluation steps.
+    pub fn step(&mut self, count: u64) -> Result<(), BaseError> {
+        let before = self.steps;
+        self.steps += count;
+        if let Some(max) = self.limits.max_steps {
+            if self.steps > max {
+                return Err(limit_error(format!("Step limit of {} exceeded", max)))
+            }
+        }
+        // from the first step on, so that a run cancelled before it started doesn't get going
+        if before.is_multiple_of(CHECK_INTERVAL) || before / CHECK_INTERVAL != self.steps / CHECK_INTERVAL {
+            if self.cancelled.load(Ordering::Relaxed) {
+                return Err(BaseError::interpreter(ErrorCode::Cancelled, "Execution was cancelled"))
+            }
+            if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(limit_error(format!("Timed out after {:?}", self.limits.timeout.unwrap())))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_call_depth(&self, depth: usize) -> Result<(), BaseError> {
+        match self.limits.max_call_depth {
+            Some(max) if depth >= max => Err(limit_error(format!("Call depth limit of {} exceeded", max))),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn memory_exceeded(&self, size: usize) -> bool {
+        self.limits.max_memory.is_some_and(|max| size > max)
+    }
+}
+
+pub fn limit_error(message: String) -> BaseError {
+    BaseError::interpreter(ErrorCode::LimitExceeded, message)
+}
@@ -1,97 +1,224 @@
-mod lexer;
-mod parser;
-mod errors;
-mod value;
-mod interpreter;
-
-use std::{fs, io::{self, Write}};
-use interpreter::ScopeList;
-use logos::Logos;
-
-use crate::{errors::BaseError, interpreter::{Memory}, lexer::Token, value::Value};
-
-
-fn run(code: String, memory: &mut Memory, scopes: &mut ScopeList, print_result: bool) {
-    let mut tokens = lexer::Token
-        ::lexer(&code)
-        .collect::<Vec<lexer::Token>>();
-    tokens.push(Token::Eol);
-    tokens.push(Token::Eof);
-    /*
-    for i in &tokens {
-        println!("{:?}",i);
-    }
-    */
-    
-    let tree = parser::parse(&tokens);
-
-    
-    match tree {
-        Ok((node, _)) => {
-            //println!("{:#?}",node);
-            
-            let ass = interpreter::start_execute(&node, scopes, memory);
-            if let Ok(result) = ass {
-                if print_result {
-                    match result {
-                        Value::Null => print!("\r"),
-                        _ => print!("{}",result.to_str(&memory, &mut vec![]))
-                    }
-                }
-            } else if let Err(BaseError::InterpreterError(message)) = ass {
-                print!("{:?}",message);
+use std::{env, fs, io::{self, IsTerminal, Read, Write}, path::Path, process::ExitCode};
+
+use bluebat::{blbc::{self, Program}, Backend, BaseError, Error, Interpreter, Value};
+
+
+const EXIT_USAGE: u8 = 64;
+const EXIT_PARSE_ERROR: u8 = 65;
+const EXIT_NO_INPUT: u8 = 66;
+const EXIT_RUNTIME_ERROR: u8 = 70;
+const EXIT_CANT_CREATE: u8 = 73;
+
+const USAGE: &str = "\
+Usage:
+    bluebat [--vm] run <file> [args...]    run a script or .blbc file ('-' reads a script from stdin)
+    bluebat [--vm] repl                    start the interactive console
+    bluebat [--vm] -e <code> [args...]     evaluate code and print the result
+    bluebat [--vm] <file> [args...]        shorthand for 'bluebat run'
+    bluebat compile <file> [options]       compile a script to a .blbc file next to it
+    bluebat disasm <file>                  list the bytecode of a script or .blbc file
+
+Options:
+    --vm         run on the bytecode VM instead of the tree-walking interpreter
+    -o <file>    where 'compile' writes the bytecode
+    --strip      leave out the source locations 'compile' keeps for diagnostics";
+
+
+fn report_error(err: Error) -> u8 {
+    match err {
+        Error::Script(err) => {
+            eprint!("{}", err.render(io::stderr().is_terminal()));
+            match err.error {
+                BaseError::LexError(_) | BaseError::ParseError(_) => EXIT_PARSE_ERROR,
+                BaseError::InterpreterError(_) => EXIT_RUNTIME_ERROR,
             }
         },
-        Err(BaseError::ParseError(message)) => print!("{}",message),
-        _ => unimplemented!(),
+        Error::Io { .. } => {
+            eprintln!("{}", err);
+            EXIT_NO_INPUT
+        },
+        Error::UnknownGlobal(_) => {
+            eprintln!("{}", err);
+            EXIT_RUNTIME_ERROR
+        },
+        Error::Bytecode { .. } => {
+            eprintln!("{}", err);
+            EXIT_PARSE_ERROR
+        },
+    }
+}
+
+fn print_result(interp: &Interpreter, result: &Value) {
+    match result {
+        Value::Null => print!("\r"),
+        _ => print!("{}", interp.display(result)),
     }
-    
 }
 
-fn main() {
+fn with_args(script_args: &[String], backend: Backend) -> Interpreter {
+    let mut interp = Interpreter::new();
+    interp.set_backend(backend);
+    let args = script_args.iter().map(|arg| Value::String(arg.clone())).collect();
+    let args = interp.array(args);
+    interp.set_global("args", args);
+    interp
+}
+
+fn run_file(path: &str, script_args: &[String], backend: Backend) -> Result<(), u8> {
+    let mut interp = with_args(script_args, backend);
+    if path != "-" {
+        return interp.run_file(path).map(|_| ()).map_err(report_error)
+    }
+
+    let mut code = String::new();
+    if let Err(error) = io::stdin().read_to_string(&mut code) {
+        return Err(report_error(Error::Io { path: path.into(), error }))
+    }
+    interp.eval_named(&code, "<stdin>").map(|_| ()).map_err(report_error)
+}
+
+fn run_expr(code: &str, script_args: &[String], backend: Backend) -> Result<(), u8> {
+    let mut interp = with_args(script_args, backend);
+    let result = interp.eval_named(code, "<expr>").map_err(report_error)?;
+    print_result(&interp, &result);
+    println!();
+    Ok(())
+}
+
+fn repl(backend: Backend) -> Result<(), u8> {
     print!("\x1B[2J\x1B[1;1H");
 
-    let mut memory = Memory::new();
-    let mut scopes = ScopeList::new();
-    
-    scopes.set_var_local("sin".to_string(), 0, &mut memory, &Value::Builtin("sin".to_string()));
-    scopes.set_var_local("cos".to_string(), 0, &mut memory, &Value::Builtin("cos".to_string()));
-    scopes.set_var_local("tan".to_string(), 0, &mut memory, &Value::Builtin("tan".to_string()));
-    scopes.set_var_local("print".to_string(), 0, &mut memory, &Value::Builtin("print".to_string()));
-    scopes.set_var_local("println".to_string(), 0, &mut memory, &Value::Builtin("println".to_string()));
-    scopes.set_var_local("memtest".to_string(), 0, &mut memory, &Value::Builtin("memtest".to_string()));
-    scopes.set_var_local("collect".to_string(), 0, &mut memory, &Value::Builtin("collect".to_string()));
-    scopes.set_var_local("input".to_string(), 0, &mut memory, &Value::Builtin("input".to_string()));
-    scopes.set_var_local("len".to_string(), 0, &mut memory, &Value::Builtin("len".to_string()));
-    
-    if true {
-        let input_str = fs::read_to_string("code.blb")
-            .expect("Something went wrong reading the file");
-        
-        run(input_str, &mut memory, &mut scopes, false);
-        print!("\n\n");
-    } else {
+    // no arguments, but scripts pasted in still find `args`
+    let mut interp = with_args(&[], backend);
 
-        print!("
+    print!("
 BlueBat v0.2.5 Console
---------------------------- 
+---------------------------
 
 ");
 
-        loop {
-            print!("{}", "\n>>> ");
-            io::stdout().flush().unwrap();
-    
-            let mut input_str = String::new();
-            io::stdin()
-                .read_line(&mut input_str)
-                .expect("Failed to read line");
-            
-            let input_str = format!("{}{}",input_str.replace("\r", ""),"\n");
-    
-            run(input_str, &mut memory, &mut scopes, true);
-            
+    loop {
+        print!("\n>>> ");
+        io::stdout().flush().unwrap();
+
+        let mut input_str = String::new();
+        match io::stdin().read_line(&mut input_str) {
+            Ok(0) => {
+                println!();
+                return Ok(())
+            },
+            Ok(_) => (),
+            Err(err) => {
+                eprintln!("Failed to read line: {}", err);
+                return Err(EXIT_NO_INPUT)
+            },
+        }
+
+        let input_str = format!("{}{}",input_str.replace('\r', ""),"\n");
+
+        // errors are reported and the console just keeps going
+        match interp.eval_named(&input_str, "<repl>") {
+            Ok(result) => print_result(&interp, &result),
+            Err(err) => { report_error(err); },
         }
     }
+}
+
+fn is_bytecode(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|extension| extension == "blbc")
+}
+
+fn read_source(path: &str) -> Result<String, u8> {
+    fs::read_to_string(path).map_err(|error| report_error(Error::Io { path: path.into(), error }))
+}
+
+fn compile(args: &[String]) -> Result<(), u8> {
+    let (mut path, mut output, mut strip) = (None, None, false);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "-o" => match args.next() {
+                Some(file) => output = Some(file.clone()),
+                None => return usage_error("Expected a file after '-o'"),
+            },
+            "--strip" => strip = true,
+            flag if flag.starts_with('-') => return usage_error(&format!("Unknown option '{}'", flag)),
+            file if path.is_none() => path = Some(file),
+            file => return usage_error(&format!("Unexpected argument '{}'", file)),
+        }
+    }
+    let Some(path) = path else { return usage_error("Expected a file to compile") };
+
+    let code = read_source(path)?;
+    let mut program = Interpreter::new().compile(&code, path).map_err(report_error)?;
+    if strip {
+        program.strip_debug_spans();
+    }
+    let output = output.unwrap_or_else(|| Path::new(path).with_extension("blbc").display().to_string());
+    fs::write(&output, program.encode()).map_err(|error| {
+        eprintln!("couldn't write '{}': {}", output, error);
+        EXIT_CANT_CREATE
+    })
+}
 
+fn disasm(path: &str) -> Result<(), u8> {
+    let program = if is_bytecode(path) {
+        let bytes = fs::read(path).map_err(|error| report_error(Error::Io { path: path.into(), error }))?;
+        Program::decode(&bytes).map_err(|error| report_error(Error::Bytecode { source_name: path.to_string(), error }))?
+    } else {
+        let code = read_source(path)?;
+        Interpreter::new().compile(&code, path).map_err(report_error)?
+    };
+    println!(
+        "; bytecode version {}, source hash {:016x}, {}",
+        blbc::VERSION, program.source_hash,
+        if program.has_debug_spans() { "with source locations" } else { "stripped" },
+    );
+    print!("{}", program.proto.disassemble());
+    Ok(())
+}
+
+fn usage_error(message: &str) -> Result<(), u8> {
+    eprintln!("{}\n\n{}", message, USAGE);
+    Err(EXIT_USAGE)
+}
+
+fn main() -> ExitCode {
+    let mut cli_args: Vec<String> = env::args().skip(1).collect();
+    let backend = match cli_args.first().map(|s| &s[..]) {
+        Some("--vm") => {
+            cli_args.remove(0);
+            Backend::Vm
+        },
+        _ => Backend::TreeWalker,
+    };
+
+    let result = match cli_args.first().map(|s| &s[..]) {
+        None => usage_error("No command given"),
+        Some("-h" | "--help" | "help") => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        Some("repl") => repl(backend),
+        Some("run") => match cli_args.get(1) {
+            Some(path) => run_file(path, &cli_args[2..], backend),
+            None => usage_error("Expected a file to run"),
+        },
+        Some("compile") => compile(&cli_args[1..]),
+        Some("disasm") => match cli_args.get(1) {
+            Some(path) => disasm(path),
+            None => usage_error("Expected a file to disassemble"),
+        },
+        Some("-e") => match cli_args.get(1) {
+            Some(code) => run_expr(code, &cli_args[2..], backend),
+            None => usage_error("Expected code after '-e'"),
+        },
+        Some(flag) if flag.starts_with('-') && flag != "-" => usage_error(&format!("Unknown option '{}'", flag)),
+        Some(path) => run_file(path, &cli_args[1..], backend),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(code) => ExitCode::from(code),
+    }
 }
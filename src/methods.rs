@@ -0,0 +1,207 @@
+use crate::{errors::{BaseError, ErrorCode}, int::Int, interpreter::{Memory, RegIndex, ValueResult}, value::{division_by_zero, DictKey, Value}};
+
+
+/// A method gets the id of its receiver so that it can mutate it in place.
+type MethodFn = fn(RegIndex, &[Value], &mut Memory) -> ValueResult;
+
+pub struct Method {
+    pub name: &'static str,
+    pub arity: usize,
+    pub func: MethodFn,
+}
+
+macro_rules! methods {
+    ( $( $name:literal / $arity:literal => $func:expr ),* $(,)? ) => {
+        &[ $( Method { name: $name, arity: $arity, func: $func } ),* ]
+    };
+}
+
+const ARRAY_METHODS: &[Method] = methods! {
+    "push" / 1 => |id, args, memory| {
+        let arg_id = memory.add(args[0].clone());
+        array_mut(id, memory, |arr| { arr.push(arg_id); Ok(Value::Null) })
+    },
+    "pop" / 0 => |id, _, memory| {
+        let popped = array_mut(id, memory, |arr| arr.pop().ok_or_else(||
+            BaseError::interpreter(ErrorCode::IndexOutOfBounds, "Cannot pop from an empty array")
+        ))?;
+        Ok(memory.get(popped)?.clone())
+    },
+    "insert" / 2 => |id, args, memory| {
+        let index = index_arg(&args[0])?;
+        let arg_id = memory.add(args[1].clone());
+        array_mut(id, memory, |arr| {
+            if index > arr.len() {
+                return Err(BaseError::interpreter(ErrorCode::IndexOutOfBounds, "Insertion index out of bounds"))
+            }
+            arr.insert(index, arg_id);
+            Ok(Value::Null)
+        })
+    },
+    "remove" / 1 => |id, args, memory| {
+        let index = index_arg(&args[0])?;
+        let removed = array_mut(id, memory, |arr| {
+            if index >= arr.len() {
+                return Err(BaseError::interpreter(ErrorCode::IndexOutOfBounds, "Index out of bounds"))
+            }
+            Ok(arr.remove(index))
+        })?;
+        Ok(memory.get(removed)?.clone())
+    },
+    "len" / 0 => |id, _, memory| memory.get(id)?.len(),
+    "contains" / 1 => |id, args, memory| match memory.get(id)? {
+        Value::Array(arr) => Ok(Value::Bool( arr.iter().any(|i| memory.get(*i).is_ok_and(|value| value.internal_equal(&args[0], memory))) )),
+        _ => unreachable!(),
+    },
+    "reverse" / 0 => |id, _, memory| array_mut(id, memory, |arr| { arr.reverse(); Ok(Value::Null) }),
+    "join" / 1 => |id, args, memory| match (memory.get(id)?, &args[0]) {
+        (Value::Array(arr), Value::String(sep)) => {
+            let strs = arr.iter()
+                .map(|i| Ok(memory.get(*i)?.to_str(memory)))
+                .collect::<Result<Vec<String>, BaseError>>()?;
+            Ok(Value::String(strs.join(sep)))
+        },
+        _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Expected a string separator")),
+    },
+};
+
+const STRING_METHODS: &[Method] = methods! {
+    "len" / 0 => |id, _, memory| memory.get(id)?.len(),
+    "split" / 1 => |id, args, memory| {
+        let (s, sep) = (string(memory.get(id)?), string_arg(&args[0])?);
+        let parts: Vec<String> = if sep.is_empty() {
+            s.chars().map(|c| c.to_string()).collect()
+        } else {
+            s.split(sep).map(|part| part.to_string()).collect()
+        };
+        Ok(Value::Array( parts.into_iter().map(|part| memory.add(Value::String(part))).collect() ))
+    },
+    "trim" / 0 => |id, _, memory| Ok(Value::String( string(memory.get(id)?).trim().to_string() )),
+    "upper" / 0 => |id, _, memory| Ok(Value::String( string(memory.get(id)?).to_uppercase() )),
+    "lower" / 0 => |id, _, memory| Ok(Value::String( string(memory.get(id)?).to_lowercase() )),
+    "contains" / 1 => |id, args, memory| Ok(Value::Bool( string(memory.get(id)?).contains(string_arg(&args[0])?) )),
+    "starts_with" / 1 => |id, args, memory| Ok(Value::Bool( string(memory.get(id)?).starts_with(string_arg(&args[0])?) )),
+    "ends_with" / 1 => |id, args, memory| Ok(Value::Bool( string(memory.get(id)?).ends_with(string_arg(&args[0])?) )),
+    "replace" / 2 => |id, args, memory| Ok(Value::String(
+        string(memory.get(id)?).replace(string_arg(&args[0])?, string_arg(&args[1])?)
+    )),
+};
+
+const NUMBER_METHODS: &[Method] = methods! {
+    "floor" / 0 => |id, _, memory| Ok(Value::Number( number(memory.get(id)?).floor() )),
+    "ceil" / 0 => |id, _, memory| Ok(Value::Number( number(memory.get(id)?).ceil() )),
+    "round" / 0 => |id, _, memory| Ok(Value::Number( number(memory.get(id)?).round() )),
+    "abs" / 0 => |id, _, memory| Ok(Value::Number( number(memory.get(id)?).abs() )),
+    "sqrt" / 0 => |id, _, memory| Ok(Value::Number( number(memory.get(id)?).sqrt() )),
+};
+
+const INT_METHODS: &[Method] = methods! {
+    "floor" / 0 => |id, _, memory| Ok(memory.get(id)?.clone()),
+    "ceil" / 0 => |id, _, memory| Ok(memory.get(id)?.clone()),
+    "round" / 0 => |id, _, memory| Ok(memory.get(id)?.clone()),
+    "abs" / 0 => |id, _, memory| Ok(Value::Int( int(memory.get(id)?).abs() )),
+    "sqrt" / 0 => |id, _, memory| Ok(Value::Number( int(memory.get(id)?).to_f64().sqrt() )),
+    "div" / 1 => |id, args, memory| int(memory.get(id)?).div(int_arg(&args[0])?).map(Value::Int).ok_or_else(division_by_zero),
+};
+
+const DICT_METHODS: &[Method] = methods! {
+    "len" / 0 => |id, _, memory| memory.get(id)?.len(),
+    "has" / 1 => |id, args, memory| match memory.get(id)? {
+        Value::Dict(map) => Ok(Value::Bool( map.contains_key(&DictKey::from_value(&args[0])?) )),
+        _ => unreachable!(),
+    },
+    "keys" / 0 => |id, _, memory| match memory.get(id)?.clone() {
+        Value::Dict(map) => Ok(Value::Array( map.keys().map(|k| memory.add(k.to_value())).collect() )),
+        _ => unreachable!(),
+    },
+    "values" / 0 => |id, _, memory| match memory.get(id)? {
+        Value::Dict(map) => Ok(Value::Array( map.values().copied().collect() )),
+        _ => unreachable!(),
+    },
+    "remove" / 1 => |id, args, memory| match memory.get(id)?.clone() {
+        Value::Dict(mut map) => {
+            let key = DictKey::from_value(&args[0])?;
+            match map.remove(&key) {
+                Some(removed) => {
+                    memory.set(Value::Dict(map), id)?;
+                    Ok(memory.get(removed)?.clone())
+                },
+                None => Err(BaseError::interpreter(ErrorCode::KeyNotFound, format!("Key '{}' not found in dictionary", args[0].to_str(memory)))),
+            }
+        },
+        _ => unreachable!(),
+    },
+};
+
+
+/// The methods that can be called on values of the receiver's type.
+pub fn method_table(receiver: &Value) -> &'static [Method] {
+    match receiver {
+        Value::Array(_) => ARRAY_METHODS,
+        Value::String(_) => STRING_METHODS,
+        Value::Number(_) => NUMBER_METHODS,
+        Value::Int(_) => INT_METHODS,
+        Value::Dict(_) => DICT_METHODS,
+        _ => &[],
+    }
+}
+
+pub fn find_method(receiver: &Value, name: &str) -> Option<&'static Method> {
+    method_table(receiver).iter().find(|method| method.name == name)
+}
+
+
+fn array_mut<T>(id: RegIndex, memory: &mut Memory, f: impl FnOnce(&mut Vec<RegIndex>) -> Result<T, BaseError>) -> Result<T, BaseError> {
+    match memory.get(id)?.clone() {
+        Value::Array(mut arr) => {
+            let result = f(&mut arr)?;
+            memory.set(Value::Array(arr), id)?;
+            Ok(result)
+        },
+        _ => unreachable!(),
+    }
+}
+
+fn index_arg(value: &Value) -> Result<usize, BaseError> {
+    match value {
+        Value::Number(n) if *n >= 0.0 => Ok(n.floor() as usize),
+        Value::Int(n) if !n.is_negative() => Ok(n.to_i64().map_or(usize::MAX, |n| n as usize)),
+        Value::Number(_) | Value::Int(_) => Err(BaseError::interpreter(ErrorCode::IndexOutOfBounds, "Index out of bounds")),
+        _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, format!("Expected a number index, found {}", value.type_name()))),
+    }
+}
+
+fn int_arg(value: &Value) -> Result<&Int, BaseError> {
+    match value {
+        Value::Int(n) => Ok(n),
+        _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, format!("Expected an integer, found {}", value.type_name()))),
+    }
+}
+
+fn string_arg(value: &Value) -> Result<&str, BaseError> {
+    match value {
+        Value::String(s) => Ok(s),
+        _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, format!("Expected a string, found {}", value.type_name()))),
+    }
+}
+
+fn string(value: &Value) -> &str {
+    match value {
+        Value::String(s) => s,
+        _ => unreachable!(),
+    }
+}
+
+fn int(value: &Value) -> &Int {
+    match value {
+        Value::Int(n) => n,
+        _ => unreachable!(),
+    }
+}
+
+fn number(value: &Value) -> f64 {
+    match value {
+        Value::Number(n) => *n,
+        _ => unreachable!(),
+    }
+}
@@ -0,0 +1,191 @@
+use std::{cell::RefCell, fmt, io::{self, BufRead, Write}, rc::Rc};
+
+use crate::{
+    convert::{ConversionError, FromValue, IntoValue},
+    errors::{BaseError, ErrorCode},
+    interpreter::{Memory, ScopeId, ScopeList, ValueResult},
+    value::Value,
+};
+
+
+/// How many arguments a native function takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    Exact(usize),
+    /// Variadic, with at least this many arguments.
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn check(&self, count: usize) -> Result<(), BaseError> {
+        match *self {
+            Arity::Exact(n) if count != n =>
+                Err(BaseError::interpreter(ErrorCode::ArgumentCount, format!("Expected {} argument(s)", n))),
+            Arity::AtLeast(n) if count < n =>
+                Err(BaseError::interpreter(ErrorCode::ArgumentCount, format!("Expected at least {} argument(s)", n))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What a native function can reach of the interpreter while it runs.
+pub struct NativeContext<'a> {
+    pub memory: &'a mut Memory,
+    pub scopes: &'a mut ScopeList,
+    /// The scope the function was called from.
+    pub scope_id: ScopeId,
+}
+
+impl NativeContext<'_> {
+    /// Moves `values` onto the heap as the elements of a new array.
+    pub fn array(&mut self, values: Vec<Value>) -> Value {
+        values.into_value(self.memory)
+    }
+    pub fn to_value(&mut self, value: impl IntoValue) -> Value {
+        value.into_value(self.memory)
+    }
+    pub fn from_value<T: FromValue>(&self, value: &Value) -> Result<T, ConversionError> {
+        T::from_value(value, self.memory)
+    }
+    /// Formats `value` the way `print` would.
+    pub fn display(&self, value: &Value) -> String {
+        value.to_str(self.memory)
+    }
+}
+
+pub type NativeFn = dyn Fn(&mut NativeContext, Vec<Value>) -> ValueResult;
+
+/// A function implemented in Rust, called from scripts like any other.
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: Arity,
+    pub func: Box<NativeFn>,
+}
+
+impl NativeFunction {
+    pub fn new(name: &str, arity: Arity, func: impl Fn(&mut NativeContext, Vec<Value>) -> ValueResult + 'static) -> Self {
+        NativeFunction { name: name.to_string(), arity, func: Box::new(func) }
+    }
+
+    pub fn call(&self, ctx: &mut NativeContext, args: Vec<Value>) -> ValueResult {
+        self.arity.check(args.len())?;
+        (self.func)(ctx, args)
+    }
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NativeFunction")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
+
+
+/// Where `print` and `println` write to and where `input` reads from.
+pub trait ScriptIo {
+    fn write(&mut self, text: &str) -> io::Result<()>;
+    /// Reads a line without its line ending, or `None` at the end of input.
+    fn read_line(&mut self) -> io::Result<Option<String>>;
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The process' stdout and stdin.
+#[derive(Debug, Default)]
+pub struct StdIo;
+
+impl ScriptIo for StdIo {
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        io::stdout().lock().write_all(text.as_bytes())
+    }
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line.replace(['\r', '\n'], ""))),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Any pair of handles, e.g. a `Cursor` of canned input and a `Vec<u8>`
+/// collecting the output.
+#[derive(Debug)]
+pub struct StreamIo<R, W> {
+    pub input: R,
+    pub output: W,
+}
+
+impl<R: BufRead, W: Write> ScriptIo for StreamIo<R, W> {
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        self.output.write_all(text.as_bytes())
+    }
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        match self.input.read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line.replace(['\r', '\n'], ""))),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// The I/O of an interpreter, shared with the builtins that use it.
+pub type SharedIo = Rc<RefCell<Box<dyn ScriptIo>>>;
+
+fn io_error(err: io::Error) -> BaseError {
+    BaseError::interpreter(ErrorCode::Io, format!("I/O error: {}", err))
+}
+
+
+/// The functions every interpreter starts out with.
+pub fn builtins(io: &SharedIo) -> Vec<NativeFunction> {
+    let (print_io, println_io, memtest_io, input_io) = (io.clone(), io.clone(), io.clone(), io.clone());
+    vec![
+        NativeFunction::new("sin", Arity::Exact(1), |_, args| args[0].sin()),
+        NativeFunction::new("cos", Arity::Exact(1), |_, args| args[0].cos()),
+        NativeFunction::new("tan", Arity::Exact(1), |_, args| args[0].tan()),
+        NativeFunction::new("print", Arity::AtLeast(0), move |ctx, args| {
+            let strs: Vec<String> = args.iter().map(|v| ctx.display(v)).collect();
+            print_io.borrow_mut().write(&strs.join("")).map_err(io_error)?;
+            Ok(Value::Null)
+        }),
+        NativeFunction::new("println", Arity::AtLeast(0), move |ctx, args| {
+            let strs: Vec<String> = args.iter().map(|v| ctx.display(v)).collect();
+            println_io.borrow_mut().write(&format!("{}\n", strs.join(""))).map_err(io_error)?;
+            Ok(Value::Null)
+        }),
+        NativeFunction::new("memtest", Arity::Exact(0), move |ctx, _| {
+            let mut io = memtest_io.borrow_mut();
+            io.write(&format!("{:#?}\n{:#?}\n", ctx.memory, ctx.scopes)).map_err(io_error)?;
+            io.flush().map_err(io_error)?;
+            Ok(Value::Null)
+        }),
+        NativeFunction::new("collect", Arity::Exact(0), |ctx, _| {
+            ctx.memory.collect(ctx.scopes, ctx.scope_id);
+            Ok(Value::Null)
+        }),
+        NativeFunction::new("gc_stats", Arity::Exact(0), |ctx, _| {
+            let stats = ctx.memory.stats(ctx.scopes);
+            Ok(ctx.to_value(stats))
+        }),
+        NativeFunction::new("input", Arity::Exact(1), move |ctx, args| {
+            let mut io = input_io.borrow_mut();
+            io.write(&ctx.display(&args[0])).map_err(io_error)?;
+            io.flush().map_err(io_error)?;
+
+            match io.read_line().map_err(io_error)? {
+                Some(line) => Ok(Value::String(line)),
+                None => Ok(Value::Null),
+            }
+        }),
+        NativeFunction::new("len", Arity::Exact(1), |_, args| args[0].len()),
+    ]
+}
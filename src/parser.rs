@@ -1,24 +1,123 @@
 
-use crate::{errors::{self, BaseError}, lexer::Token, value::Value};
+use std::{cell::Cell, ops::Index, rc::Rc};
+
+use crate::{errors::{self, BaseError, ErrorCode}, interpreter::{STACK_RED_ZONE, STACK_SEGMENT_SIZE}, lexer::{Span, SpannedToken, Token}, resolver::Slot, value::{DictKey, Value}};
 
 type ParsePos = usize;
-type ParseResult = Result<(ASTNode, ParsePos), errors::BaseError>;
-type TokenList = Vec<Token>;
+type ParseResult = Result<(Node, ParsePos), errors::BaseError>;
+
+/// How deeply expressions may nest, so that parsing and everything that
+/// walks the tree afterwards stays well within the stack.
+const MAX_NESTING: usize = 256;
+
+/// The tokens being parsed, and how deeply nested the parser is in them.
+/// Trivia is left out, but borrowed rather than copied from the lexer's
+/// output, which keeps it for tooling.
+struct TokenList<'a> {
+    tokens: Vec<&'a SpannedToken>,
+    depth: Cell<usize>,
+}
+
+impl Index<ParsePos> for TokenList<'_> {
+    type Output = SpannedToken;
+
+    fn index(&self, pos: ParsePos) -> &SpannedToken {
+        self.tokens[pos]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub kind: ASTNode,
+    pub span: Span,
+}
+
+/// A function literal, shared by the node and every function created from
+/// it, so that creating and copying functions doesn't copy their code.
+#[derive(Debug, Clone)]
+pub struct FuncDef {
+    pub arg_names: Vec<String>,
+    pub rest: Option<String>,
+    pub code: Node,
+}
 
 #[derive(Debug, Clone)]
 pub enum ASTNode {
-    StatementList {statements: Vec<ASTNode>},
-    Op {left: Box<ASTNode>, op: Token, right: Box<ASTNode>},
-    Block {code: Box<ASTNode> },
-    Call {base: Box<ASTNode>, args: Vec<ASTNode>},
-    Unary {op: Token, value: Box<ASTNode>},
-    Var {name: String},
+    StatementList {statements: Vec<Node>},
+    Op {left: Box<Node>, op: Token, right: Box<Node>},
+    Block {code: Box<Node> },
+    /// `tail` is set for calls whose value the function around them returns.
+    Call {base: Box<Node>, args: Vec<Node>, tail: bool},
+    Unary {op: Token, value: Box<Node>},
+    /// `slot` is filled in by the resolver.
+    Var {name: String, slot: Option<Slot>},
     Value {value: Value},
-    If {conds: Vec<(ASTNode,ASTNode)>, if_none: Box<Option<ASTNode>>},
-    While {cond: Box<ASTNode>, code: Box<ASTNode>},
-    Func {code: Box<ASTNode>, arg_names: Vec<String>},
-    Array {values: Vec<ASTNode>},
-    Index {base: Box<ASTNode>, index: Box<ASTNode>},
+    If {conds: Vec<(Node,Node)>, if_none: Box<Option<Node>>},
+    While {cond: Box<Node>, code: Box<Node>},
+    For {pattern: Box<Node>, iter: Box<Node>, code: Box<Node>},
+    Break {value: Box<Option<Node>>},
+    Continue,
+    Return {value: Box<Option<Node>>},
+    Func {def: Rc<FuncDef>},
+    Array {values: Vec<Node>},
+    Dict {entries: Vec<(DictKey, Node)>},
+    Index {base: Box<Node>, index: Box<Node>},
+    Member {base: Box<Node>, name: String},
+}
+
+impl ASTNode {
+    fn at(self, span: Span) -> Node {
+        Node { kind: self, span }
+    }
+
+    pub fn children(&self) -> Vec<&Node> {
+        match self {
+            ASTNode::StatementList { statements } => statements.iter().collect(),
+            ASTNode::Op { left, right, .. } => vec![left, right],
+            ASTNode::Block { code } => vec![code],
+            ASTNode::Call { base, args, .. } => std::iter::once(&**base).chain(args).collect(),
+            ASTNode::Unary { value, .. } => vec![value],
+            ASTNode::Var { .. } | ASTNode::Value { .. } | ASTNode::Continue => vec![],
+            ASTNode::If { conds, if_none } => conds
+                .iter()
+                .flat_map(|(cond, branch)| [cond, branch])
+                .chain(if_none.as_ref())
+                .collect(),
+            ASTNode::While { cond, code } => vec![cond, code],
+            ASTNode::For { pattern, iter, code } => vec![pattern, iter, code],
+            ASTNode::Break { value } | ASTNode::Return { value } => value.iter().collect(),
+            ASTNode::Func { def } => vec![&def.code],
+            ASTNode::Array { values } => values.iter().collect(),
+            ASTNode::Dict { entries } => entries.iter().map(|(_, value)| value).collect(),
+            ASTNode::Index { base, index } => vec![base, index],
+            ASTNode::Member { base, .. } => vec![base],
+        }
+    }
+
+    pub fn children_mut(&mut self) -> Vec<&mut Node> {
+        match self {
+            ASTNode::StatementList { statements } => statements.iter_mut().collect(),
+            ASTNode::Op { left, right, .. } => vec![left, right],
+            ASTNode::Block { code } => vec![code],
+            ASTNode::Call { base, args, .. } => std::iter::once(&mut **base).chain(args).collect(),
+            ASTNode::Unary { value, .. } => vec![value],
+            ASTNode::Var { .. } | ASTNode::Value { .. } | ASTNode::Continue => vec![],
+            ASTNode::If { conds, if_none } => conds
+                .iter_mut()
+                .flat_map(|(cond, branch)| [cond, branch])
+                .chain(if_none.as_mut())
+                .collect(),
+            ASTNode::While { cond, code } => vec![cond, code],
+            ASTNode::For { pattern, iter, code } => vec![pattern, iter, code],
+            ASTNode::Break { value } | ASTNode::Return { value } => value.iter_mut().collect(),
+            // the definition isn't shared yet while the tree is being worked on
+            ASTNode::Func { def } => vec![&mut Rc::make_mut(def).code],
+            ASTNode::Array { values } => values.iter_mut().collect(),
+            ASTNode::Dict { entries } => entries.iter_mut().map(|(_, value)| value).collect(),
+            ASTNode::Index { base, index } => vec![base, index],
+            ASTNode::Member { base, .. } => vec![base],
+        }
+    }
 }
 
 struct Precedence {
@@ -42,6 +141,7 @@ const PRECEDENCES: &[Precedence] = &[
     Precedence {right_assoc: false, tok_check: ( |t| matches!(t, Token::Or )) },
     Precedence {right_assoc: false, tok_check: ( |t| matches!(t, Token::And )) },
     Precedence {right_assoc: false, tok_check: ( |t| matches!(t, Token::Greater | Token::Lesser | Token::GreaterEq | Token::LesserEq | Token::Eq | Token::NotEq )) },
+    Precedence {right_assoc: false, tok_check: ( |t| matches!(t, Token::Range )) },
     Precedence {right_assoc: false, tok_check: ( |t| matches!(t, Token::Plus | Token::Minus )) },
     Precedence {right_assoc: false, tok_check: ( |t| matches!(t, Token::Mult | Token::Div | Token::Mod )) },
     Precedence {right_assoc: true, tok_check: ( |t| matches!(t, Token::Pow )) },
@@ -49,146 +149,272 @@ const PRECEDENCES: &[Precedence] = &[
 ];
 
 
+fn span(tokens: &TokenList, start: ParsePos, end: ParsePos) -> Span {
+    if end <= start {
+        tokens[start].span
+    } else {
+        tokens[start].span.to(tokens[end - 1].span)
+    }
+}
+
+fn parse_error(tokens: &TokenList, pos: ParsePos, message: &str) -> BaseError {
+    BaseError::parse(ErrorCode::UnexpectedToken, message, tokens[pos].span)
+}
+
+/// Whether the token can't start an expression, so that a `break` or
+/// `return` in front of it has no value.
+fn ends_expr(token: &Token) -> bool {
+    matches!(token, Token::Eol | Token::Eof | Token::RBracket | Token::RParen | Token::RSqBracket | Token::Comma | Token::Elif | Token::Else)
+}
+
 fn skip_eol(tokens: &TokenList, mut pos: ParsePos) -> ParsePos {
-    while matches!(&tokens[pos], Token::Eol) {
+    while matches!(&tokens[pos].token, Token::Eol) {
         pos += 1;
     }
     pos
 }
 
+/// Whether the `{` at `pos` opens a dictionary literal rather than a block,
+/// which is the case when it's followed by `key:` or is `{:}`.
+fn starts_dict(tokens: &TokenList, pos: ParsePos) -> bool {
+    let pos = skip_eol(tokens, pos + 1);
+    match &tokens[pos].token {
+        Token::Colon => true,
+        Token::Identifier(_) | Token::StringLiteral(_) | Token::Number(_) | Token::Int(_) => matches!(&tokens[pos + 1].token, Token::Colon),
+        _ => false,
+    }
+}
+
+/// Runs `parse` one level of nesting deeper, failing past `MAX_NESTING`
+/// and growing the stack as the recursion needs.
+fn nested(tokens: &TokenList, pos: ParsePos, parse: impl FnOnce() -> ParseResult) -> ParseResult {
+    let depth = tokens.depth.get();
+    if depth >= MAX_NESTING {
+        return Err(BaseError::parse(ErrorCode::LimitExceeded, format!("Expression nested more than {} levels deep", MAX_NESTING), tokens[pos].span))
+    }
+    tokens.depth.set(depth + 1);
+    let result = stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, parse);
+    tokens.depth.set(depth);
+    result
+}
+
 fn parse_value(tokens: &TokenList, mut pos: ParsePos) -> ParseResult {
-    let tok = &tokens[pos];
-    match tok {
-        Token::Number(value) => Ok((ASTNode::Value{ value: Value::Number(*value) }, pos + 1)),
-        Token::StringLiteral(s) => Ok((ASTNode::Value{ value: Value::String(s.clone()) }, pos + 1)),
-        Token::TypeName(name) => Ok((ASTNode::Value{ value: Value::TypeName(name.clone()) }, pos + 1)),
+    let start = pos;
+    let tok = &tokens[pos].token;
+    let (kind, pos) = match tok {
+        Token::Number(value) => (ASTNode::Value{ value: Value::Number(*value) }, pos + 1),
+        Token::Int(value) => (ASTNode::Value{ value: Value::Int(value.clone()) }, pos + 1),
+        Token::StringLiteral(s) => (ASTNode::Value{ value: Value::String(s.clone()) }, pos + 1),
+        Token::TypeName(name) => (ASTNode::Value{ value: Value::TypeName(name.clone()) }, pos + 1),
         Token::Plus | Token::Minus | Token::Not | Token::Range => {
             let op = tok;
-            destr!{!let value, pos from parse_op(tokens, pos + 1, PRECEDENCES.len() - 2)}
-            Ok((ASTNode::Unary{op: op.clone(), value: Box::new(value)}, pos))
+            destr!{!let value, pos from nested(tokens, pos, || parse_op(tokens, pos + 1, PRECEDENCES.len() - 2))}
+            (ASTNode::Unary{op: op.clone(), value: Box::new(value)}, pos)
         },
         Token::LParen => {
             destr!{!let value, pos from parse_expr(tokens, pos + 1)}
-            if !matches!(&tokens[pos], Token::RParen) {
-                return Err(BaseError::ParseError("Expected ')'".to_string()));
+            if !matches!(&tokens[pos].token, Token::RParen) {
+                return Err(parse_error(tokens, pos, "Expected ')'")
+                    .with_label(tokens[start].span, "unclosed '(' opened here"));
             }
-            Ok((value, pos + 1))
+            // keep the parentheses inside the span of the inner expression
+            return Ok((value.kind.at(span(tokens, start, pos + 1)), pos + 1))
         },
-        Token::Identifier(name) => Ok((ASTNode::Var{name: name.clone()}, pos + 1)),
-        Token::True => Ok((ASTNode::Value{value: Value::Bool(true)}, pos + 1)),
-        Token::False => Ok((ASTNode::Value{value: Value::Bool(false)}, pos + 1)),
-        Token::Null => Ok((ASTNode::Value{value: Value::Null}, pos + 1)),
+        Token::Identifier(name) => (ASTNode::Var{name: name.clone(), slot: None}, pos + 1),
+        Token::True => (ASTNode::Value{value: Value::Bool(true)}, pos + 1),
+        Token::False => (ASTNode::Value{value: Value::Bool(false)}, pos + 1),
+        Token::Null => (ASTNode::Value{value: Value::Null}, pos + 1),
         Token::If => {
-            let mut conds: Vec<(ASTNode, ASTNode)> = Vec::new();
+            let mut conds: Vec<(Node, Node)> = Vec::new();
 
             destr!{!let condition, pos from parse_expr(tokens, pos + 1)}
             destr!{!let branch, pos from parse_expr(tokens, pos)}
             conds.push((condition, branch));
 
-            let mut if_none: Option<ASTNode> = None;
+            let mut if_none: Option<Node> = None;
 
-            while matches!(&tokens[pos], Token::Elif) {
+            while matches!(&tokens[pos].token, Token::Elif) {
                 destr!{!let condition, pos from parse_expr(tokens, pos + 1)}
                 destr!{!let branch, pos from parse_expr(tokens, pos)}
 
                 conds.push((condition, branch));
             }
-            if matches!(&tokens[pos], Token::Else) {
+            if matches!(&tokens[pos].token, Token::Else) {
                 let temp = parse_expr(tokens, pos + 1)?;
                 if_none = Some(temp.0);
                 pos = temp.1;
             }
             
-            Ok((ASTNode::If{conds, if_none: Box::new(if_none)}, pos))
+            (ASTNode::If{conds, if_none: Box::new(if_none)}, pos)
         },
         Token::While => {
             destr!{!let condition, pos from parse_expr(tokens, pos + 1)}
             destr!{!let code, pos from parse_expr(tokens, pos)}
             
-            Ok((ASTNode::While{cond: Box::new(condition), code: Box::new(code)}, pos))
+            (ASTNode::While{cond: Box::new(condition), code: Box::new(code)}, pos)
+        },
+        Token::For => {
+            destr!{!let pattern, pos from parse_term(tokens, pos + 1)}
+            if !matches!(&tokens[pos].token, Token::In) {
+                return Err(parse_error(tokens, pos, "Expected 'in'"));
+            }
+            destr!{!let iter, pos from parse_expr(tokens, pos + 1)}
+            destr!{!let code, pos from parse_expr(tokens, pos)}
+
+            (ASTNode::For{pattern: Box::new(pattern), iter: Box::new(iter), code: Box::new(code)}, pos)
+        },
+        Token::Break | Token::Return => {
+            let mut value = None;
+            pos += 1;
+            if !ends_expr(&tokens[pos].token) {
+                let temp = parse_expr(tokens, pos)?;
+                value = Some(temp.0);
+                pos = temp.1;
+            }
+            if let Token::Break = tok {
+                (ASTNode::Break{value: Box::new(value)}, pos)
+            } else {
+                (ASTNode::Return{value: Box::new(value)}, pos)
+            }
         },
+        Token::Continue => (ASTNode::Continue, pos + 1),
         Token::LBracket => {
-            parse_block(tokens, pos)
+            if !starts_dict(tokens, pos) {
+                return parse_block(tokens, pos)
+            }
+            let mut entries: Vec<(DictKey, Node)> = Vec::new();
+
+            pos += 1;
+            pos = skip_eol(tokens, pos);
+            // `{:}` is the empty dictionary, `{}` stays an empty block
+            if matches!(&tokens[pos].token, Token::Colon) {
+                pos = skip_eol(tokens, pos + 1);
+            }
+            while !matches!(&tokens[pos].token, Token::RBracket) {
+                let key = match &tokens[pos].token {
+                    Token::Identifier(name) | Token::StringLiteral(name) => DictKey::String(name.clone()),
+                    Token::Number(n) => DictKey::Number(n + 0.0),
+                    Token::Int(n) => DictKey::Int(n.clone()),
+                    _ => return Err(parse_error(tokens, pos, "Expected dictionary key")),
+                };
+                if !matches!(&tokens[pos + 1].token, Token::Colon) {
+                    return Err(parse_error(tokens, pos + 1, "Expected ':'"));
+                }
+                pos = skip_eol(tokens, pos + 2);
+                destr!{!let value, pos from parse_expr(tokens, pos)}
+                entries.push((key, value));
+                pos = skip_eol(tokens, pos);
+                if !matches!(&tokens[pos].token, Token::Comma) {
+                    if !matches!(&tokens[pos].token, Token::RBracket) {
+                        return Err(parse_error(tokens, pos, "Expected ',' or '}'")
+                            .with_label(tokens[start].span, "dictionary opened here"));
+                    }
+                } else { pos += 1; pos = skip_eol(tokens, pos); }
+            }
+            pos += 1;
+            (ASTNode::Dict { entries }, pos)
         },
         Token::Pipe | Token::Or => {
             let mut arg_names: Vec<String> = Vec::new();
+            let mut rest = None;
             if let Token::Pipe = tok {
                 pos += 1;
                 pos = skip_eol(tokens, pos);
-                while !matches!(&tokens[pos], Token::Pipe) {
-                    if let Token::Identifier(name) = &tokens[pos] {
+                while !matches!(&tokens[pos].token, Token::Pipe) {
+                    if let (Token::Range, Token::Identifier(name)) = (&tokens[pos].token, &tokens[pos + 1].token) {
+                        rest = Some(name.clone());
+                        pos = skip_eol(tokens, pos + 2);
+                        if !matches!(&tokens[pos].token, Token::Pipe) {
+                            return Err(parse_error(tokens, pos, "Expected '|' after rest parameter")
+                                .with_note("the rest parameter has to come last"));
+                        }
+                    } else if let Token::Identifier(name) = &tokens[pos].token {
                         arg_names.push(name.clone());
                         pos += 1;
                         pos = skip_eol(tokens, pos);
-                        if !matches!(&tokens[pos], Token::Comma) {
-                            if !matches!(&tokens[pos], Token::Pipe) {
-                                return Err(BaseError::ParseError("Expected ',' or '|'".to_string()));
+                        if !matches!(&tokens[pos].token, Token::Comma) {
+                            if !matches!(&tokens[pos].token, Token::Pipe) {
+                                return Err(parse_error(tokens, pos, "Expected ',' or '|'"));
                             }
                         } else {
                             pos += 1;
                         }
                     } else {
-                        return Err(BaseError::ParseError("Expected argument name".to_string()));
+                        return Err(parse_error(tokens, pos, "Expected argument name"));
                     }
                 }
             }
             destr!{!let code, pos from parse_expr(tokens, pos + 1)}
-            Ok((ASTNode::Func{code: Box::new(code), arg_names}, pos))
+            let mut def = FuncDef { arg_names, rest, code };
+            mark_tail_calls(&mut def.code);
+            mark_returned_calls(&mut def.code);
+            (ASTNode::Func{def: Rc::new(def)}, pos)
         },
         Token::LSqBracket => {
-            let mut values: Vec<ASTNode> = Vec::new();
+            let mut values: Vec<Node> = Vec::new();
 
             pos += 1;
             pos = skip_eol(tokens, pos);
-            while !matches!(&tokens[pos], Token::RSqBracket) {
+            while !matches!(&tokens[pos].token, Token::RSqBracket) {
                 pos = skip_eol(tokens, pos);
                 destr!{!let value, pos from parse_expr(tokens, pos)}
                 values.push(value);
                 pos = skip_eol(tokens, pos);
-                if !matches!(&tokens[pos], Token::Comma) {
-                    if !matches!(&tokens[pos], Token::RSqBracket) {
-                        return Err(BaseError::ParseError("Expected ',' or ']'".to_string()));
+                if !matches!(&tokens[pos].token, Token::Comma) {
+                    if !matches!(&tokens[pos].token, Token::RSqBracket) {
+                        return Err(parse_error(tokens, pos, "Expected ',' or ']'")
+                            .with_label(tokens[start].span, "array opened here"));
                     }
                 } else { pos += 1; pos = skip_eol(tokens, pos); }
             }
             pos += 1;
-            Ok((ASTNode::Array { values }, pos))
+            (ASTNode::Array { values }, pos)
         }
-        _ => Err(BaseError::ParseError("Expected value".to_string()))
-    }
+        _ => return Err(parse_error(tokens, pos, "Expected value"))
+    };
+
+    Ok((kind.at(span(tokens, start, pos)), pos))
 }
 
 fn parse_term(tokens: &TokenList, pos: ParsePos) -> ParseResult {
+    let start = pos;
     let (mut value, mut pos) = parse_value(tokens, pos)?;
 
     loop {
-        if matches!(&tokens[pos], Token::LParen) {
+        if matches!(&tokens[pos].token, Token::LParen) {
             pos += 1;
             pos = skip_eol(tokens, pos);
-            let mut args: Vec<ASTNode> = Vec::new();
-            while !matches!(&tokens[pos], Token::RParen) {
+            let mut args: Vec<Node> = Vec::new();
+            while !matches!(&tokens[pos].token, Token::RParen) {
                 pos = skip_eol(tokens, pos);
                 destr!{!let arg, pos from parse_expr(tokens, pos)}
                 args.push(arg);
                 pos = skip_eol(tokens, pos);
-                if !matches!(&tokens[pos], Token::Comma) {
-                    if !matches!(&tokens[pos], Token::RParen) {
-                        return Err(BaseError::ParseError("Expected ',' or ')'".to_string()));
+                if !matches!(&tokens[pos].token, Token::Comma) {
+                    if !matches!(&tokens[pos].token, Token::RParen) {
+                        return Err(parse_error(tokens, pos, "Expected ',' or ')'"));
                     }
                 } else { pos += 1; pos = skip_eol(tokens, pos); }
             }
             pos += 1;
-            value = ASTNode::Call {base: Box::new(value), args}
-        } else if matches!(&tokens[pos], Token::LSqBracket) {
+            value = ASTNode::Call {base: Box::new(value), args, tail: false}.at(span(tokens, start, pos))
+        } else if matches!(&tokens[pos].token, Token::LSqBracket) {
             pos += 1;
             pos = skip_eol(tokens, pos);
             destr!{!let index, pos from parse_expr(tokens, pos)}
             pos = skip_eol(tokens, pos);
-            if !matches!(&tokens[pos], Token::RSqBracket) {
-                return Err(BaseError::ParseError("Expected ']'".to_string()));
+            if !matches!(&tokens[pos].token, Token::RSqBracket) {
+                return Err(parse_error(tokens, pos, "Expected ']'"));
             }
             pos += 1;
-            value = ASTNode::Index { base: Box::new(value), index: Box::new(index) }
+            value = ASTNode::Index { base: Box::new(value), index: Box::new(index) }.at(span(tokens, start, pos))
+        } else if matches!(&tokens[pos].token, Token::Dot) {
+            let name = match &tokens[pos + 1].token {
+                Token::Identifier(name) => name.clone(),
+                _ => return Err(parse_error(tokens, pos + 1, "Expected field or method name after '.'")),
+            };
+            pos += 2;
+            value = ASTNode::Member { base: Box::new(value), name }.at(span(tokens, start, pos))
         } else {
             return Ok((value, pos))
         }
@@ -197,21 +423,24 @@ fn parse_term(tokens: &TokenList, pos: ParsePos) -> ParseResult {
 
 fn parse_block(tokens: &TokenList, mut pos: ParsePos) -> ParseResult {
     pos = skip_eol(tokens, pos);
-    if !matches!(&tokens[pos], Token::LBracket) {
-        return Err(BaseError::ParseError("Expected '{'".to_string()))
+    let start = pos;
+    if !matches!(&tokens[pos].token, Token::LBracket) {
+        return Err(parse_error(tokens, pos, "Expected '{'"))
     }
     destr!{!let code, pos from parse_statements(tokens, pos + 1)}
 
-    if !matches!(&tokens[pos], Token::RBracket) {
-        return Err(BaseError::ParseError("Expected '}'".to_string()))
+    if !matches!(&tokens[pos].token, Token::RBracket) {
+        return Err(parse_error(tokens, pos, "Expected '}'")
+            .with_label(tokens[start].span, "unclosed '{' opened here"))
     } 
 
-    Ok((ASTNode::Block{code: Box::new(code)}, pos + 1))
+    Ok((ASTNode::Block{code: Box::new(code)}.at(span(tokens, start, pos + 1)), pos + 1))
 }
 
 fn parse_op(tokens: &TokenList, pos: ParsePos, op_id: usize) -> ParseResult {
     let tok_check = &PRECEDENCES[op_id].tok_check;
     let right_assoc = &PRECEDENCES[op_id].right_assoc;
+    let start = pos;
     
     let (mut left, mut pos) = 
         if op_id + 1 >= PRECEDENCES.len()
@@ -219,9 +448,9 @@ fn parse_op(tokens: &TokenList, pos: ParsePos, op_id: usize) -> ParseResult {
         else
         { parse_op(tokens, pos, op_id + 1) }?;
     
-    while tok_check(&tokens[pos]) {
-        let op = tokens.get(pos).unwrap(); pos += 1;
-        let right: ASTNode;
+    while tok_check(&tokens[pos].token) {
+        let op = &tokens[pos].token; pos += 1;
+        let right: Node;
 
         destr!{right, pos from if !right_assoc {
             if op_id + 1 >= PRECEDENCES.len()
@@ -229,10 +458,10 @@ fn parse_op(tokens: &TokenList, pos: ParsePos, op_id: usize) -> ParseResult {
             else
             { parse_op(tokens, pos, op_id + 1) }
         } else {
-            parse_op(tokens, pos, op_id)
+            nested(tokens, pos, || parse_op(tokens, pos, op_id))
         }};
 
-        left = ASTNode::Op {left: Box::new(left), op: op.clone(), right: Box::new(right)};
+        left = ASTNode::Op {left: Box::new(left), op: op.clone(), right: Box::new(right)}.at(span(tokens, start, pos));
 
     }
 
@@ -241,38 +470,144 @@ fn parse_op(tokens: &TokenList, pos: ParsePos, op_id: usize) -> ParseResult {
 }
 
 fn parse_expr(tokens: &TokenList, pos: ParsePos) -> ParseResult {
-    parse_op(tokens, pos, 0)
+    nested(tokens, pos, || parse_op(tokens, pos, 0))
 }
 
 fn parse_statement(tokens: &TokenList, pos: ParsePos) -> ParseResult {
     
     let (statement, pos) = parse_expr(tokens, pos)?;
-    if !matches!(&tokens[pos], Token::Eol) {
-        return Err(BaseError::ParseError("Expected end of line (';' or newline)".to_string()))
+    if !matches!(&tokens[pos].token, Token::Eol) {
+        return Err(parse_error(tokens, pos, "Expected end of line (';' or newline)")
+            .with_note("statements are separated by ';' or a newline"))
     }
     
     Ok((statement,pos + 1))
 }
 
 fn parse_statements(tokens: &TokenList, mut pos: ParsePos) -> ParseResult {
+    let start = pos;
+
+    let mut statements: Vec<Node> = Vec::new();
     
-    let mut statements: Vec<ASTNode> = Vec::new();
-    
-    while !matches!(&tokens[pos], Token::Eof | Token::RBracket) {
+    while !matches!(&tokens[pos].token, Token::Eof | Token::RBracket) {
         pos = skip_eol(tokens, pos);
         destr!{!let statement, pos from parse_statement(tokens, pos)};
         statements.push(statement);
         pos = skip_eol(tokens, pos);
     }
     
-    Ok((ASTNode::StatementList{statements},pos))
+    Ok((ASTNode::StatementList{statements}.at(span(tokens, start, pos)),pos))
+}
+
+/// Rejects `break` and `continue` outside of loops and `return` outside of functions.
+fn check_control_flow(node: &Node, in_loop: bool, in_function: bool) -> Result<(), BaseError> {
+    let (in_loop, in_function) = match &node.kind {
+        ASTNode::Break { .. } | ASTNode::Continue if !in_loop => return Err(
+            BaseError::parse(ErrorCode::InvalidControlFlow, "'break' or 'continue' outside of a loop", node.span)
+        ),
+        ASTNode::Return { .. } if !in_function => return Err(
+            BaseError::parse(ErrorCode::InvalidControlFlow, "'return' outside of a function", node.span)
+        ),
+        ASTNode::While { cond, code } => {
+            check_control_flow(cond, in_loop, in_function)?;
+            return check_control_flow(code, true, in_function)
+        },
+        ASTNode::For { pattern, iter, code } => {
+            check_control_flow(pattern, in_loop, in_function)?;
+            check_control_flow(iter, in_loop, in_function)?;
+            return check_control_flow(code, true, in_function)
+        },
+        // loops don't reach into the functions defined inside them
+        ASTNode::Func { .. } => (false, true),
+        _ => (in_loop, in_function),
+    };
+    for child in node.kind.children() {
+        check_control_flow(child, in_loop, in_function)?;
+    }
+    Ok(())
+}
+
+/// Rejects `..` spreads outside of array literals and call arguments,
+/// which also covers array destructuring patterns.
+fn check_spreads(node: &Node) -> Result<(), BaseError> {
+    fn unspread(node: &Node) -> &Node {
+        match &node.kind {
+            ASTNode::Unary { op: Token::Range, value } => value,
+            _ => node,
+        }
+    }
+    let children: Vec<&Node> = match &node.kind {
+        ASTNode::Unary { op: Token::Range, .. } => return Err(
+            BaseError::parse(ErrorCode::UnexpectedToken, "Spread outside of an array or call arguments", node.span)
+                .with_note("'..' spreads an array into an array literal, call arguments or a destructuring pattern")
+        ),
+        ASTNode::Array { values } => values.iter().map(unspread).collect(),
+        ASTNode::Call { base, args, .. } => std::iter::once(&**base).chain(args.iter().map(unspread)).collect(),
+        kind => kind.children(),
+    };
+    for child in children {
+        check_spreads(child)?;
+    }
+    Ok(())
+}
+
+/// Marks the calls a function body ends on, through the branches of `if`
+/// and the last statement of blocks.
+fn mark_tail_calls(node: &mut Node) {
+    match &mut node.kind {
+        ASTNode::Call { tail, .. } => *tail = true,
+        ASTNode::StatementList { statements } => if let Some(last) = statements.last_mut() {
+            mark_tail_calls(last)
+        },
+        ASTNode::Block { code } => mark_tail_calls(code),
+        ASTNode::If { conds, if_none } => {
+            for (_, branch) in conds {
+                mark_tail_calls(branch);
+            }
+            if let Some(node) = &mut **if_none {
+                mark_tail_calls(node);
+            }
+        },
+        _ => (),
+    }
+}
+
+/// Marks the calls that are the value of a `return`, anywhere in a function
+/// body but the functions defined in it, which were marked on their own.
+fn mark_returned_calls(node: &mut Node) {
+    match &mut node.kind {
+        ASTNode::Func { .. } => return,
+        ASTNode::Return { value } => if let Some(value) = &mut **value {
+            mark_tail_calls(value);
+        },
+        _ => (),
+    }
+    for child in node.kind.children_mut() {
+        mark_returned_calls(child);
+    }
 }
 
-pub fn parse(tokens: &TokenList) -> ParseResult {
+/// Parses a whole program, skipping trivia such as doc comments, which
+/// stay in `tokens` for anything else that wants them.
+///
+/// ```
+/// use bluebat::{lexer::{lex, Token}, parser::parse};
+///
+/// let tokens = lex("/// Doubles `x`.\ndouble = |x| x * 2\n").unwrap();
+/// assert!(parse(&tokens).is_ok());
+/// assert!(matches!(&tokens[0].token, Token::DocComment(doc) if doc == "Doubles `x`."));
+/// ```
+pub fn parse(tokens: &[SpannedToken]) -> ParseResult {
+    let tokens = &TokenList {
+        tokens: tokens.iter().filter(|t| !t.token.is_trivia()).collect(),
+        depth: Cell::new(0),
+    };
     let (result, pos) = parse_statements(tokens, 0)?;
-    if !matches!(&tokens[pos], Token::Eof) {
-        return Err(BaseError::ParseError("Expected end of file".to_string()))
+    if !matches!(&tokens[pos].token, Token::Eof) {
+        return Err(parse_error(tokens, pos, "Expected end of file"))
     }
+    check_control_flow(&result, false, false)?;
+    check_spreads(&result)?;
 
     Ok((result,pos))
 }
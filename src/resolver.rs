@@ -0,0 +1,203 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{errors::{BaseError, ErrorCode}, lexer::Token, parser::{ASTNode, Node}};
+
+
+/// Where a variable lives: `depth` scopes up from the one it's used in,
+/// at `index` in that scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Slot {
+    pub depth: usize,
+    pub index: usize,
+}
+
+/// The slots of the global scope by name, which outlive any one program.
+pub type Globals = HashMap<String, usize>;
+
+/// Resolves every variable in `node` to a slot, reporting the ones that
+/// aren't defined anywhere they could be seen from.
+///
+/// Names assigned with `=` belong to the innermost scope that assigns them
+/// from its start, unless an enclosing scope has them too, so functions
+/// can call ones defined after them. Names declared with `:=` are only
+/// local from the declaration on. Globals that are new to `globals` are
+/// added to it.
+///
+/// Nothing is looked up by name at runtime. A name that a function assigns
+/// before any global of that name exists stays local to the function,
+/// even once a later program defines the global.
+pub fn resolve(node: &mut Node, globals: &mut Globals) -> Result<(), BaseError> {
+    let names = std::mem::take(globals);
+    let mut resolver = Resolver { scopes: vec![Scope { len: names.len(), names }] };
+    resolver.hoist(node);
+    let result = resolver.visit(node);
+    *globals = resolver.scopes.pop().unwrap().names;
+    result
+}
+
+#[derive(Default)]
+struct Scope {
+    names: HashMap<String, usize>,
+    /// Slots handed out, which can be more than `names` has when parameters repeat.
+    len: usize,
+}
+
+struct Resolver {
+    /// The scopes a variable could be found in, innermost last.
+    scopes: Vec<Scope>,
+}
+
+impl Resolver {
+    fn declare(&mut self, name: &str) -> usize {
+        let scope = self.scopes.last_mut().unwrap();
+        match scope.names.get(name) {
+            Some(index) => *index,
+            None => Self::declare_new(scope, name),
+        }
+    }
+
+    /// Hands out the next slot even if `name` already has one, as
+    /// parameters are bound by position.
+    fn declare_new(scope: &mut Scope, name: &str) -> usize {
+        scope.names.insert(name.to_string(), scope.len);
+        scope.len += 1;
+        scope.len - 1
+    }
+
+    fn lookup(&self, name: &str) -> Option<Slot> {
+        self.scopes.iter().rev().enumerate().find_map(|(depth, scope)| {
+            scope.names.get(name).map(|index| Slot { depth, index: *index })
+        })
+    }
+
+    /// Declares the names `node` assigns with `=` in the innermost scope,
+    /// without looking into the scopes it creates.
+    fn hoist(&mut self, node: &Node) {
+        let mut names = Vec::new();
+        assigned_names(node, &mut names);
+        for name in names {
+            if self.lookup(&name).is_none() {
+                self.declare(&name);
+            }
+        }
+    }
+
+    /// Resolves `node` in a scope of its own, as `derive_scope` runs it.
+    fn visit_scope(&mut self, node: &mut Node, params: &[String]) -> Result<(), BaseError> {
+        let mut scope = Scope::default();
+        for name in params {
+            Self::declare_new(&mut scope, name);
+        }
+        self.scopes.push(scope);
+        self.hoist(node);
+        let result = self.visit(node);
+        self.scopes.pop();
+        result
+    }
+
+    fn visit(&mut self, node: &mut Node) -> Result<(), BaseError> {
+        match &mut node.kind {
+            ASTNode::Var { name, slot } => {
+                *slot = Some(self.lookup(name).ok_or_else(||
+                    BaseError::parse(ErrorCode::UnknownVariable, format!("Unknown variable {}", name), node.span)
+                )?);
+                Ok(())
+            },
+            // the right side runs first, and can still see what `:=` shadows
+            ASTNode::Op { left, op: Token::Assign, right } => {
+                self.visit(right)?;
+                self.visit(left)
+            },
+            ASTNode::Op { left, op: Token::LocalAssign, right } => {
+                self.visit(right)?;
+                // the interpreter rejects anything but a variable
+                if let ASTNode::Var { name, slot } = &mut left.kind {
+                    let index = self.declare(name);
+                    *slot = Some(Slot { depth: 0, index });
+                }
+                Ok(())
+            },
+            ASTNode::Block { code } => self.visit_scope(code, &[]),
+            ASTNode::If { conds, if_none } => {
+                for (cond, branch) in conds {
+                    self.visit(cond)?;
+                    self.visit_scope(branch, &[])?;
+                }
+                match &mut **if_none {
+                    Some(node) => self.visit(node),
+                    None => Ok(()),
+                }
+            },
+            ASTNode::While { cond, code } => {
+                self.visit(cond)?;
+                self.visit_scope(code, &[])
+            },
+            ASTNode::For { pattern, iter, code } => {
+                self.visit(iter)?;
+                let mut names = Vec::new();
+                pattern_names(pattern, &mut names);
+                self.scopes.push(Scope::default());
+                for name in &names {
+                    self.declare(name);
+                }
+                self.hoist(code);
+                let result = self.visit(pattern).and_then(|_| self.visit(code));
+                self.scopes.pop();
+                result
+            },
+            ASTNode::Func { def } => {
+                let def = Rc::make_mut(def);
+                let params: Vec<String> = def.arg_names.iter().chain(def.rest.iter()).cloned().collect();
+                self.visit_scope(&mut def.code, &params)
+            },
+            kind => {
+                for child in kind.children_mut() {
+                    self.visit(child)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Collects the names `node` assigns with `=`, stopping at the nodes that
+/// run in scopes of their own.
+fn assigned_names(node: &Node, names: &mut Vec<String>) {
+    match &node.kind {
+        ASTNode::Op { left, op: Token::Assign, right: _ } => {
+            pattern_names(left, names);
+            for child in node.kind.children() {
+                assigned_names(child, names);
+            }
+        },
+        ASTNode::Block { .. } | ASTNode::Func { .. } => (),
+        ASTNode::If { conds, if_none } => {
+            for (cond, _) in conds {
+                assigned_names(cond, names);
+            }
+            if let Some(node) = &**if_none {
+                assigned_names(node, names);
+            }
+        },
+        ASTNode::While { cond, code: _ } => assigned_names(cond, names),
+        ASTNode::For { pattern: _, iter, code: _ } => assigned_names(iter, names),
+        kind => for child in kind.children() {
+            assigned_names(child, names);
+        },
+    }
+}
+
+/// Collects the variable names a destructuring pattern binds.
+fn pattern_names(pattern: &Node, names: &mut Vec<String>) {
+    match &pattern.kind {
+        ASTNode::Var { name, .. } => names.push(name.clone()),
+        ASTNode::Array { values } => for i in values {
+            pattern_names(i, names);
+        },
+        ASTNode::Dict { entries } => for (_, i) in entries {
+            pattern_names(i, names);
+        },
+        ASTNode::Unary { op: Token::Range, value } => pattern_names(value, names),
+        _ => (),
+    }
+}
@@ -0,0 +1,409 @@
+//! Conversions between `Value` and anything implementing serde's traits.
+//!
+//! Sequences and tuples become arrays; maps and structs become dictionaries.
+//! Enums are externally tagged, as in `serde_json`: unit variants become their
+//! name and other variants a dictionary with the name as its only key.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use serde::{
+    de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor},
+    forward_to_deserialize_any,
+    ser::{self, Serialize},
+};
+
+use crate::{
+    convert::{element, index_segment, key_segment, ConversionError, IntoValue},
+    int::Int,
+    interpreter::{Memory, RegIndex},
+    value::{DictKey, Value},
+};
+
+
+impl ser::Error for ConversionError {
+    fn custom<T: Display>(msg: T) -> Self {
+        ConversionError::new(msg.to_string())
+    }
+}
+
+impl de::Error for ConversionError {
+    fn custom<T: Display>(msg: T) -> Self {
+        ConversionError::new(msg.to_string())
+    }
+}
+
+/// Serializes `value` into a BlueBat value, allocating its elements in `memory`.
+pub fn to_value<T: Serialize + ?Sized>(value: &T, memory: &mut Memory) -> Result<Value, ConversionError> {
+    value.serialize(ValueSerializer { memory })
+}
+
+/// Deserializes a `T` out of a BlueBat value.
+pub fn from_value<T: DeserializeOwned>(value: &Value, memory: &Memory) -> Result<T, ConversionError> {
+    T::deserialize(ValueDeserializer { value, memory })
+}
+
+
+struct ValueSerializer<'a> {
+    memory: &'a mut Memory,
+}
+
+impl ValueSerializer<'_> {
+    fn add<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<RegIndex, ConversionError> {
+        let value = value.serialize(ValueSerializer { memory: self.memory })?;
+        Ok(self.memory.add(value))
+    }
+
+    /// Wraps the value of an enum variant as `{variant: value}`.
+    fn tagged(&mut self, variant: &str, value: Value) -> Value {
+        let id = self.memory.add(value);
+        Value::Dict(BTreeMap::from([(DictKey::String(variant.to_string()), id)]))
+    }
+}
+
+struct SeqSerializer<'a> {
+    serializer: ValueSerializer<'a>,
+    ids: Vec<RegIndex>,
+    variant: Option<&'static str>,
+}
+
+struct MapSerializer<'a> {
+    serializer: ValueSerializer<'a>,
+    map: BTreeMap<DictKey, RegIndex>,
+    next_key: Option<DictKey>,
+    variant: Option<&'static str>,
+}
+
+impl<'a> ser::Serializer for ValueSerializer<'a> {
+    type Ok = Value;
+    type Error = ConversionError;
+    type SerializeSeq = SeqSerializer<'a>;
+    type SerializeTuple = SeqSerializer<'a>;
+    type SerializeTupleStruct = SeqSerializer<'a>;
+    type SerializeTupleVariant = SeqSerializer<'a>;
+    type SerializeMap = MapSerializer<'a>;
+    type SerializeStruct = MapSerializer<'a>;
+    type SerializeStructVariant = MapSerializer<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, ConversionError> { Ok(Value::Bool(v)) }
+    fn serialize_i8(self, v: i8) -> Result<Value, ConversionError> { Ok(v.into_value(self.memory)) }
+    fn serialize_i16(self, v: i16) -> Result<Value, ConversionError> { Ok(v.into_value(self.memory)) }
+    fn serialize_i32(self, v: i32) -> Result<Value, ConversionError> { Ok(v.into_value(self.memory)) }
+    fn serialize_i64(self, v: i64) -> Result<Value, ConversionError> { Ok(v.into_value(self.memory)) }
+    fn serialize_u8(self, v: u8) -> Result<Value, ConversionError> { Ok(v.into_value(self.memory)) }
+    fn serialize_u16(self, v: u16) -> Result<Value, ConversionError> { Ok(v.into_value(self.memory)) }
+    fn serialize_u32(self, v: u32) -> Result<Value, ConversionError> { Ok(v.into_value(self.memory)) }
+    fn serialize_u64(self, v: u64) -> Result<Value, ConversionError> { Ok(v.into_value(self.memory)) }
+    fn serialize_i128(self, v: i128) -> Result<Value, ConversionError> { Ok(v.into_value(self.memory)) }
+    fn serialize_u128(self, v: u128) -> Result<Value, ConversionError> { Ok(v.into_value(self.memory)) }
+    fn serialize_f32(self, v: f32) -> Result<Value, ConversionError> { Ok(Value::Number(v as f64)) }
+    fn serialize_f64(self, v: f64) -> Result<Value, ConversionError> { Ok(Value::Number(v)) }
+    fn serialize_char(self, v: char) -> Result<Value, ConversionError> { Ok(Value::String(v.to_string())) }
+    fn serialize_str(self, v: &str) -> Result<Value, ConversionError> { Ok(Value::String(v.to_string())) }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, ConversionError> {
+        let ids = v.iter().map(|b| self.memory.add(Value::Int(Int::Small(*b as i64)))).collect();
+        Ok(Value::Array(ids))
+    }
+
+    fn serialize_none(self) -> Result<Value, ConversionError> { Ok(Value::Null) }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, ConversionError> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Value, ConversionError> { Ok(Value::Null) }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Value, ConversionError> { Ok(Value::Null) }
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str) -> Result<Value, ConversionError> {
+        Ok(Value::String(variant.to_string()))
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<Value, ConversionError> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        mut self, _: &'static str, _: u32, variant: &'static str, value: &T,
+    ) -> Result<Value, ConversionError> {
+        let value = value.serialize(ValueSerializer { memory: self.memory })?;
+        Ok(self.tagged(variant, value))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SeqSerializer<'a>, ConversionError> {
+        Ok(SeqSerializer { serializer: self, ids: Vec::with_capacity(len.unwrap_or(0)), variant: None })
+    }
+    fn serialize_tuple(self, len: usize) -> Result<SeqSerializer<'a>, ConversionError> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_struct(self, _: &'static str, len: usize) -> Result<SeqSerializer<'a>, ConversionError> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_variant(
+        self, _: &'static str, _: u32, variant: &'static str, len: usize,
+    ) -> Result<SeqSerializer<'a>, ConversionError> {
+        Ok(SeqSerializer { serializer: self, ids: Vec::with_capacity(len), variant: Some(variant) })
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<MapSerializer<'a>, ConversionError> {
+        Ok(MapSerializer { serializer: self, map: BTreeMap::new(), next_key: None, variant: None })
+    }
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<MapSerializer<'a>, ConversionError> {
+        self.serialize_map(Some(len))
+    }
+    fn serialize_struct_variant(
+        self, _: &'static str, _: u32, variant: &'static str, _: usize,
+    ) -> Result<MapSerializer<'a>, ConversionError> {
+        Ok(MapSerializer { serializer: self, map: BTreeMap::new(), next_key: None, variant: Some(variant) })
+    }
+}
+
+impl SeqSerializer<'_> {
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ConversionError> {
+        let index = self.ids.len();
+        let id = self.serializer.add(value).map_err(|err| err.at(index_segment(index)))?;
+        self.ids.push(id);
+        Ok(())
+    }
+    fn finish(mut self) -> Result<Value, ConversionError> {
+        let value = Value::Array(self.ids);
+        Ok(match self.variant {
+            Some(variant) => self.serializer.tagged(variant, value),
+            None => value,
+        })
+    }
+}
+
+macro_rules! seq_impls {
+    ( $( $trait:ident :: $method:ident ),* ) => { $(
+        impl ser::$trait for SeqSerializer<'_> {
+            type Ok = Value;
+            type Error = ConversionError;
+            fn $method<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ConversionError> {
+                self.element(value)
+            }
+            fn end(self) -> Result<Value, ConversionError> {
+                self.finish()
+            }
+        }
+    )* };
+}
+seq_impls!(SerializeSeq::serialize_element, SerializeTuple::serialize_element, SerializeTupleStruct::serialize_field, SerializeTupleVariant::serialize_field);
+
+impl MapSerializer<'_> {
+    fn entry<T: Serialize + ?Sized>(&mut self, key: DictKey, value: &T) -> Result<(), ConversionError> {
+        let segment = key_segment(&key);
+        let id = self.serializer.add(value).map_err(|err| err.at(segment))?;
+        self.map.insert(key, id);
+        Ok(())
+    }
+    fn finish(mut self) -> Result<Value, ConversionError> {
+        let value = Value::Dict(self.map);
+        Ok(match self.variant {
+            Some(variant) => self.serializer.tagged(variant, value),
+            None => value,
+        })
+    }
+}
+
+impl ser::SerializeMap for MapSerializer<'_> {
+    type Ok = Value;
+    type Error = ConversionError;
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), ConversionError> {
+        let key = key.serialize(ValueSerializer { memory: self.serializer.memory })?;
+        self.next_key = Some(DictKey::from_value(&key)
+            .map_err(|_| ConversionError::expected("a number or string key", &key))?);
+        Ok(())
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ConversionError> {
+        let key = self.next_key.take()
+            .ok_or_else(|| ConversionError::new("map value serialized before its key"))?;
+        self.entry(key, value)
+    }
+    fn end(self) -> Result<Value, ConversionError> {
+        self.finish()
+    }
+}
+
+macro_rules! struct_impls {
+    ( $( $trait:ident ),* ) => { $(
+        impl ser::$trait for MapSerializer<'_> {
+            type Ok = Value;
+            type Error = ConversionError;
+            fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), ConversionError> {
+                self.entry(DictKey::String(key.to_string()), value)
+            }
+            fn end(self) -> Result<Value, ConversionError> {
+                self.finish()
+            }
+        }
+    )* };
+}
+struct_impls!(SerializeStruct, SerializeStructVariant);
+
+
+struct ValueDeserializer<'a> {
+    value: &'a Value,
+    memory: &'a Memory,
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer<'_> {
+    type Error = ConversionError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConversionError> {
+        match self.value {
+            Value::Null => visitor.visit_unit(),
+            Value::Bool(b) => visitor.visit_bool(*b),
+            // whole numbers are offered as integers so that integer fields accept them
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < 9007199254740992.0 => visitor.visit_i64(*n as i64),
+            Value::Number(n) => visitor.visit_f64(*n),
+            Value::Int(Int::Small(n)) => visitor.visit_i64(*n),
+            // the narrowest width first, as `u64` fields don't take an `i128`
+            Value::Int(n @ Int::Big(big)) => {
+                if let Ok(n) = u64::try_from(big) {
+                    visitor.visit_u64(n)
+                } else if let Ok(n) = i128::try_from(big) {
+                    visitor.visit_i128(n)
+                } else if let Ok(n) = u128::try_from(big) {
+                    visitor.visit_u128(n)
+                } else {
+                    Err(ConversionError::new(format!("{} is too large to deserialize", n)))
+                }
+            },
+            Value::String(s) => visitor.visit_str(s),
+            Value::Array(arr) => visitor.visit_seq(SeqDeserializer { ids: arr.iter(), index: 0, memory: self.memory }),
+            Value::Dict(map) => visitor.visit_map(MapDeserializer { entries: map.iter(), value: None, memory: self.memory }),
+            _ => Err(ConversionError::new(format!("{} can't be deserialized", self.value.type_name()))),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConversionError> {
+        match self.value {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, ConversionError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self, _: &'static str, _: &'static [&'static str], visitor: V,
+    ) -> Result<V::Value, ConversionError> {
+        match self.value {
+            Value::String(variant) => visitor.visit_enum(EnumDeserializer { variant: variant.clone(), value: None, memory: self.memory }),
+            Value::Dict(map) if map.len() == 1 => {
+                let (key, id) = map.iter().next().unwrap();
+                let variant = match key {
+                    DictKey::String(s) => s.clone(),
+                    DictKey::Number(_) | DictKey::Int(_) => return Err(ConversionError::new("expected a variant name")),
+                };
+                let value = element(*id, self.memory)?;
+                visitor.visit_enum(EnumDeserializer { variant, value: Some(value), memory: self.memory })
+            },
+            _ => Err(ConversionError::expected("a variant name or a dictionary with one entry", self.value)),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+struct SeqDeserializer<'a> {
+    ids: std::slice::Iter<'a, RegIndex>,
+    index: usize,
+    memory: &'a Memory,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqDeserializer<'_> {
+    type Error = ConversionError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, ConversionError> {
+        let Some(id) = self.ids.next() else { return Ok(None) };
+        let index = self.index;
+        self.index += 1;
+        element(*id, self.memory)
+            .and_then(|value| seed.deserialize(ValueDeserializer { value, memory: self.memory }))
+            .map(Some)
+            .map_err(|err| err.at(index_segment(index)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.ids.len())
+    }
+}
+
+struct MapDeserializer<'a> {
+    entries: std::collections::btree_map::Iter<'a, DictKey, RegIndex>,
+    value: Option<(&'a DictKey, RegIndex)>,
+    memory: &'a Memory,
+}
+
+impl<'de> de::MapAccess<'de> for MapDeserializer<'_> {
+    type Error = ConversionError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, ConversionError> {
+        let Some((key, id)) = self.entries.next() else { return Ok(None) };
+        self.value = Some((key, *id));
+        let key = key.to_value();
+        seed.deserialize(ValueDeserializer { value: &key, memory: self.memory }).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, ConversionError> {
+        let (key, id) = self.value.take()
+            .ok_or_else(|| ConversionError::new("map value deserialized before its key"))?;
+        element(id, self.memory)
+            .and_then(|value| seed.deserialize(ValueDeserializer { value, memory: self.memory }))
+            .map_err(|err| err.at(key_segment(key)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+struct EnumDeserializer<'a> {
+    variant: String,
+    value: Option<&'a Value>,
+    memory: &'a Memory,
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for EnumDeserializer<'a> {
+    type Error = ConversionError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), ConversionError> {
+        let variant = seed.deserialize(self.variant.clone().into_deserializer())?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for EnumDeserializer<'_> {
+    type Error = ConversionError;
+
+    fn unit_variant(self) -> Result<(), ConversionError> {
+        match self.value {
+            None | Some(Value::Null) => Ok(()),
+            Some(value) => Err(ConversionError::expected("a unit variant", value)),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, ConversionError> {
+        match self.value {
+            Some(value) => seed.deserialize(ValueDeserializer { value, memory: self.memory }),
+            None => Err(ConversionError::new(format!("expected a value for variant {}", self.variant))),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, ConversionError> {
+        match self.value {
+            Some(value) => de::Deserializer::deserialize_seq(ValueDeserializer { value, memory: self.memory }, visitor),
+            None => Err(ConversionError::new(format!("expected an array for variant {}", self.variant))),
+        }
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _: &'static [&'static str], visitor: V) -> Result<V::Value, ConversionError> {
+        match self.value {
+            Some(value) => de::Deserializer::deserialize_map(ValueDeserializer { value, memory: self.memory }, visitor),
+            None => Err(ConversionError::new(format!("expected a dictionary for variant {}", self.variant))),
+        }
+    }
+}
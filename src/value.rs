@@ -1,66 +1,280 @@
-use std::io::{self, Write};
+use std::{cmp::Ordering, collections::{BTreeMap, HashSet}, rc::Rc};
 
-use crate::{errors::BaseError, interpreter::{Memory, RegIndex, ValueResult}, parser::ASTNode};
+use crate::{bytecode::Proto, errors::{BaseError, ErrorCode}, int::{Int, MAX_POW_BITS}, interpreter::{Memory, RegIndex, ScopeId, ValueResult, STACK_RED_ZONE, STACK_SEGMENT_SIZE}, limits::limit_error, native::NativeFunction, parser::FuncDef};
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum Value {
     Null,
     Number(f64),
+    Int(Int),
     Bool(bool),
     String(String),
-    Builtin(String),
-    Function {arg_names: Vec<String>, code: Box<ASTNode>, scope_id: RegIndex},
+    Builtin(Rc<NativeFunction>),
+    Function {def: Rc<FuncDef>, scope_id: ScopeId},
+    /// A function compiled for the VM.
+    Closure {proto: Rc<Proto>, scope_id: ScopeId},
+    /// A method looked up through `.`, bound to the value it was called on.
+    Method {receiver: RegIndex, name: String},
     Array(Vec<RegIndex>),
+    Dict(BTreeMap<DictKey, RegIndex>),
     TypeName(String),
+    Range(f64, f64),
+}
+
+// Values are cloned on every variable read, so the plain ones are copied
+// inline and only the others go through a call.
+impl Clone for Value {
+    #[inline]
+    fn clone(&self) -> Self {
+        match self {
+            Value::Null => Value::Null,
+            Value::Number(n) => Value::Number(*n),
+            Value::Int(Int::Small(n)) => Value::Int(Int::Small(*n)),
+            Value::Bool(b) => Value::Bool(*b),
+            _ => self.clone_slow(),
+        }
+    }
+}
+
+impl Value {
+    #[inline(never)]
+    fn clone_slow(&self) -> Value {
+        match self {
+            Value::Null => Value::Null,
+            Value::Number(n) => Value::Number(*n),
+            Value::Int(n) => Value::Int(n.clone()),
+            Value::Bool(b) => Value::Bool(*b),
+            Value::String(s) => Value::String(s.clone()),
+            Value::Builtin(native) => Value::Builtin(native.clone()),
+            Value::Function { def, scope_id } => Value::Function { def: def.clone(), scope_id: *scope_id },
+            Value::Closure { proto, scope_id } => Value::Closure { proto: proto.clone(), scope_id: *scope_id },
+            Value::Method { receiver, name } => Value::Method { receiver: *receiver, name: name.clone() },
+            Value::Array(ids) => Value::Array(ids.clone()),
+            Value::Dict(map) => Value::Dict(map.clone()),
+            Value::TypeName(name) => Value::TypeName(name.clone()),
+            Value::Range(start, end) => Value::Range(*start, *end),
+        }
+    }
+}
+
+/// Dictionary keys are numbers or strings; numbers sort before strings.
+/// An integer and a float that are equal are the same key.
+#[derive(Debug, Clone)]
+pub enum DictKey {
+    Number(f64),
+    Int(Int),
+    String(String),
+}
+
+impl DictKey {
+    pub fn from_value(value: &Value) -> Result<DictKey, BaseError> {
+        match value {
+            Value::Number(n) if n.is_nan() => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "NaN cannot be used as a dictionary key")),
+            // `+ 0.0` turns -0 into 0 so that both find the same entry
+            Value::Number(n) => Ok(DictKey::Number(n + 0.0)),
+            Value::Int(n) => Ok(DictKey::Int(n.clone())),
+            Value::String(s) => Ok(DictKey::String(s.clone())),
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, format!("Cannot use {} as a dictionary key", value.type_name()))),
+        }
+    }
+    pub fn to_value(&self) -> Value {
+        match self {
+            DictKey::Number(n) => Value::Number(*n),
+            DictKey::Int(n) => Value::Int(n.clone()),
+            DictKey::String(s) => Value::String(s.clone()),
+        }
+    }
+}
+
+impl Ord for DictKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (DictKey::Number(a), DictKey::Number(b)) => a.total_cmp(b),
+            (DictKey::Int(a), DictKey::Int(b)) => a.cmp(b),
+            // NaN can't be a key
+            (DictKey::Int(a), DictKey::Number(b)) => a.cmp_f64(*b).unwrap(),
+            (DictKey::Number(a), DictKey::Int(b)) => b.cmp_f64(*a).unwrap().reverse(),
+            (DictKey::Number(_) | DictKey::Int(_), DictKey::String(_)) => Ordering::Less,
+            (DictKey::String(_), DictKey::Number(_) | DictKey::Int(_)) => Ordering::Greater,
+            (DictKey::String(a), DictKey::String(b)) => a.cmp(b),
+        }
+    }
+}
+impl PartialOrd for DictKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for DictKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for DictKey {}
+
+/// Two numbers brought to a common type for arithmetic: integers stay
+/// exact, and an integer with a float becomes a float too.
+enum Operands<'a> {
+    Ints(&'a Int, &'a Int),
+    Floats(f64, f64),
+}
+
+fn operands<'a>(left: &'a Value, right: &'a Value) -> Option<Operands<'a>> {
+    match (left, right) {
+        (Value::Int(a), Value::Int(b)) => Some(Operands::Ints(a, b)),
+        (Value::Number(a), Value::Number(b)) => Some(Operands::Floats(*a, *b)),
+        (Value::Int(a), Value::Number(b)) => Some(Operands::Floats(a.to_f64(), *b)),
+        (Value::Number(a), Value::Int(b)) => Some(Operands::Floats(*a, b.to_f64())),
+        _ => None,
+    }
+}
+
+/// Orders two numbers of either type exactly, without rounding integers
+/// to floats. `None` if either isn't a number, `Some(None)` for NaN.
+fn compare(left: &Value, right: &Value) -> Option<Option<Ordering>> {
+    match (left, right) {
+        (Value::Int(a), Value::Int(b)) => Some(Some(a.cmp(b))),
+        (Value::Number(a), Value::Number(b)) => Some(a.partial_cmp(b)),
+        (Value::Int(a), Value::Number(b)) => Some(a.cmp_f64(*b)),
+        (Value::Number(a), Value::Int(b)) => Some(b.cmp_f64(*a).map(Ordering::reverse)),
+        _ => None,
+    }
+}
+
+pub(crate) fn division_by_zero() -> BaseError {
+    BaseError::interpreter(ErrorCode::DivisionByZero, "Integer division by zero")
+}
+
+/// The most bytes `*` will repeat a string to.
+const MAX_REPEAT_LEN: usize = 1 << 30;
+
+/// `s` repeated `count` times, with negative counts giving an empty string.
+fn repeat(s: &str, count: &Int) -> ValueResult {
+    if count.is_negative() {
+        return Ok(Value::String( String::new() ))
+    }
+    match count.to_i64().and_then(|n| usize::try_from(n).ok()).filter(|n| n.saturating_mul(s.len()) <= MAX_REPEAT_LEN) {
+        Some(count) => Ok(Value::String( s.repeat(count) )),
+        None => Err(limit_error(format!("Repeated string would be longer than {} bytes", MAX_REPEAT_LEN))),
+    }
+}
+
+/// Whether two arrays or dictionaries hold the very same elements, which
+/// for one inside the other means that it contains itself.
+fn same_elements(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Array(arr1), Value::Array(arr2)) => arr1 == arr2,
+        (Value::Dict(map1), Value::Dict(map2)) => map1 == map2,
+        _ => false,
+    }
+}
+
+/// Shows a collected element instead of failing, so printing never does.
+/// Elements that are already being shown further out, in `open` or as
+/// the `root`, come out as `[...]` or `{...}`.
+fn element_str(id: RegIndex, root: &Value, memory: &Memory, open: &mut HashSet<RegIndex>) -> String {
+    let value = match memory.get(id) {
+        Ok(value) => value,
+        Err(_) => return String::from("<collected>"),
+    };
+    match value {
+        Value::Array(_) if open.contains(&id) || same_elements(value, root) => String::from("[...]"),
+        Value::Dict(_) if open.contains(&id) || same_elements(value, root) => String::from("{...}"),
+        Value::Array(_) | Value::Dict(_) => {
+            open.insert(id);
+            let shown = stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || value.elements_str(root, memory, open));
+            open.remove(&id);
+            shown
+        },
+        _ => value.elements_str(root, memory, open),
+    }
+}
+
+/// A collected element equals nothing.
+fn elements_equal(i: RegIndex, j: RegIndex, memory: &Memory) -> bool {
+    match (memory.get(i), memory.get(j)) {
+        (Ok(a), Ok(b)) => stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || a.internal_equal(b, memory)),
+        _ => false,
+    }
 }
 
 impl Value {
 
-    pub fn to_str(&self, memory: &Memory, visited: &mut Vec<Value>) -> String {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "Null",
+            Value::Number(_) => "a number",
+            Value::Int(_) => "an integer",
+            Value::Bool(_) => "a boolean",
+            Value::String(_) => "a string",
+            Value::Builtin(_) => "a builtin",
+            Value::Function { .. } | Value::Closure { .. } => "a function",
+            Value::Method { .. } => "a method",
+            Value::Array(_) => "an array",
+            Value::Dict(_) => "a dictionary",
+            Value::TypeName(_) => "a type name",
+            Value::Range(..) => "a range",
+        }
+    }
+
+    pub fn to_str(&self, memory: &Memory) -> String {
+        self.elements_str(self, memory, &mut HashSet::new())
+    }
+
+    fn elements_str(&self, root: &Value, memory: &Memory, open: &mut HashSet<RegIndex>) -> String {
         match self {
             Value::Null => String::from("Null"),
             Value::Number(value) => value.to_string(),
+            Value::Int(value) => value.to_string(),
             Value::Bool(value) => if *value { String::from("True") } else { String::from("False") },
-            Value::String(value) => format!("{}",value),
+            Value::String(value) => value.to_string(),
             Value::TypeName(name) => format!("#{}",name),
-            Value::Builtin(name) => format!("<builtin: {}>", name),
-            Value::Function { arg_names: _, code: _, scope_id: _ } => String::from("|...| {...}"),
+            Value::Range(start, end) => format!("{}..{}", start, end),
+            Value::Builtin(native) => format!("<builtin: {}>", native.name),
+            Value::Function { .. } | Value::Closure { .. } => String::from("|...| {...}"),
+            Value::Method { receiver: _, name } => format!("<method: {}>", name),
             Value::Array(arr) => {
-                //println!("ga: {:?} has {:?}?",visited, self);
-                io::stdout().flush().unwrap();
-                for i in visited.iter() {
-                    if i.internal_equal(self, memory) {
-                        return format!("[...]")
-                    }
-                }
-                visited.push(self.clone());
                 let mut str_vec = Vec::new();
                 for i in arr {
-                    str_vec.push(memory.get(*i).to_str(memory, visited));
+                    str_vec.push(element_str(*i, root, memory, open));
                 }
-                visited.pop();
                 format!("[{}]",str_vec.join(","))
             },
+            Value::Dict(map) => {
+                let mut str_vec = Vec::new();
+                for (k, i) in map {
+                    str_vec.push(format!("{}: {}", k.to_value().to_str(memory), element_str(*i, root, memory, open)));
+                }
+                format!("{{{}}}",str_vec.join(", "))
+            },
+        }
+    }
+
+    fn dicts_equal(map1: &BTreeMap<DictKey, RegIndex>, map2: &BTreeMap<DictKey, RegIndex>, memory: &Memory) -> bool {
+        if map1.len() != map2.len() { return false }
+        for ((k1, i), (k2, j)) in map1.iter().zip(map2.iter()) {
+            if k1 != k2 { return false }
+            if *i != *j && !elements_equal(*i, *j, memory) { return false }
         }
+        true
     }
 
-    fn internal_equal(&self, other: &Value, memory: &Memory) -> bool {
-        //println!("{:?} == {:?}", self, other);
+    pub fn internal_equal(&self, other: &Value, memory: &Memory) -> bool {
         match (self, other) {
             (Value::Null, Value::Null) => true,
-            (Value::Number(v1), Value::Number(v2)) => *v1 == *v2,
+            (Value::Number(_) | Value::Int(_), Value::Number(_) | Value::Int(_)) => compare(self, other) == Some(Some(Ordering::Equal)),
             (Value::Bool(v1), Value::Bool(v2)) => *v1 == *v2,
             (Value::String(v1), Value::String(v2)) => *v1 == *v2,
+            (Value::Range(s1, e1), Value::Range(s2, e2)) => s1 == s2 && e1 == e2,
+            (Value::Dict(map1), Value::Dict(map2)) => Value::dicts_equal(map1, map2, memory),
             (Value::Array(arr1), Value::Array(arr2)) => {
                 if arr1.len() != arr2.len() { return false }
                 for (i, j) in arr1.iter().zip(arr2.iter()) {
                     if *i == *j {
                         continue
                     } else {
-                        let a = memory.get(*i);
-                        let b = memory.get(*j);
-                        if !a.internal_equal(b, memory) { return false }
+                        if !elements_equal(*i, *j, memory) { return false }
                     }
                 }
                 true
@@ -75,97 +289,142 @@ impl Value {
                 match &name[..] {
                     "number" => match v.parse::<f64>() {
                         Ok(n) => Ok(Value::Number(n)),
-                        Err(_) => Err(BaseError::InterpreterError("Couldn't convert string to number".to_string())),
+                        Err(_) => Err(BaseError::interpreter(ErrorCode::InvalidConversion, "Couldn't convert string to number")),
+                    }
+                    "int" => match v.trim().parse::<Int>() {
+                        Ok(n) => Ok(Value::Int(n)),
+                        Err(_) => Err(BaseError::interpreter(ErrorCode::InvalidConversion, "Couldn't convert string to integer")),
                     }
                     "string" => Ok(Value::String(v.clone())),
                     _ => Ok(Value::Number(3.0)),
                 }
             }
+            (Value::Number(n), Value::TypeName(name)) if name == "int" => match Int::from_f64(*n) {
+                Some(n) => Ok(Value::Int(n)),
+                None => Err(BaseError::interpreter(ErrorCode::InvalidConversion, format!("Couldn't convert {} to integer", n))),
+            },
+            (Value::Int(n), Value::TypeName(name)) if name == "number" => Ok(Value::Number(n.to_f64())),
+            (Value::Number(_), Value::TypeName(name)) if name == "number" => Ok(self.clone()),
+            (Value::Int(_), Value::TypeName(name)) if name == "int" => Ok(self.clone()),
             (_, Value::TypeName(name)) => {
                 match &name[..] {
-                    "string" => Ok(Value::String(self.to_str(memory, &mut vec![]))),
-                    _ => Err(BaseError::InterpreterError("Couldn't convert".to_string()))
+                    "string" => Ok(Value::String(self.to_str(memory))),
+                    _ => Err(BaseError::interpreter(ErrorCode::InvalidConversion, "Couldn't convert"))
                 }
             }
-            _ => Err(BaseError::InterpreterError("Casting not defined for types".to_string()))
+            _ => Err(BaseError::interpreter(ErrorCode::InvalidConversion, "Casting not defined for types"))
         }
     }
     pub fn len(&self) -> ValueResult {
         match self {
-            Value::Array(v) => Ok(Value::Number(v.len() as f64)),
-            Value::String(v) => Ok(Value::Number(v.chars().count() as f64)),
-            _ => Err(BaseError::InterpreterError("Cannot get length of type".to_string()))
+            Value::Array(v) => Ok(Value::Int(Int::from(v.len() as i64))),
+            Value::Dict(map) => Ok(Value::Int(Int::from(map.len() as i64))),
+            Value::String(v) => Ok(Value::Int(Int::from(v.chars().count() as i64))),
+            Value::Range(start, end) => Ok(Value::Int(Int::from((end - start).ceil().max(0.0) as i64))),
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Cannot get length of type"))
         }
     }
 
+    /// Arithmetic on two integers gives an integer, growing past 64 bits
+    /// as needed, except for `/` and for `^` with a negative exponent. With
+    /// a float on either side it's done on floats. See [`Int`] for the rules.
     pub fn plus(&self, other: &Value) -> ValueResult {
-        match (self, other) {
-            (Value::Number(v1), Value::Number(v2)) =>
-                Ok(Value::Number( *v1 + v2 )),
-            (Value::String(v1), Value::String(v2)) =>
+        match (operands(self, other), self, other) {
+            (Some(Operands::Ints(v1, v2)), _, _) => Ok(Value::Int( v1.add(v2) )),
+            (Some(Operands::Floats(v1, v2)), _, _) => Ok(Value::Number( v1 + v2 )),
+            (None, Value::String(v1), Value::String(v2)) =>
                 Ok(Value::String( format!("{}{}",v1,v2) )),
-            (Value::Array(v1), Value::Array(v2)) =>
+            (None, Value::Array(v1), Value::Array(v2)) =>
                 Ok(Value::Array( {
                     let mut v = v1.to_vec();
                     v.append(&mut v2.clone());
                     v
                 } )),
-            _ => Err(BaseError::InterpreterError("Operation '+' not defined for types".to_string()))
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Operation '+' not defined for types"))
         }
     }
     pub fn minus(&self, other: &Value) -> ValueResult {
-        match (self, other) {
-            (Value::Number(v1), Value::Number(v2)) =>
-                Ok(Value::Number( *v1 - v2 )),
-            _ => Err(BaseError::InterpreterError("Operation '-' not defined for types".to_string()))
+        match operands(self, other) {
+            Some(Operands::Ints(v1, v2)) => Ok(Value::Int( v1.sub(v2) )),
+            Some(Operands::Floats(v1, v2)) => Ok(Value::Number( v1 - v2 )),
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Operation '-' not defined for types"))
         }
     }
+    /// A string times a count repeats it, up to a limit on its length.
+    ///
+    /// ```
+    /// let mut interp = bluebat::Interpreter::new();
+    /// let repeated = interp.eval("'ab' * 3 + 'ab' * -1 + 'ab' * 1.5\n").unwrap();
+    /// assert_eq!(interp.display(&repeated), "abababab");
+    /// assert!(interp.eval("'ab' * 2 ^ 70\n").unwrap_err().to_string().contains("[E212]"));
+    /// assert!(interp.eval("'ab' * 2.0 ^ 70\n").unwrap_err().to_string().contains("[E212]"));
+    /// ```
     pub fn mult(&self, other: &Value) -> ValueResult {
-        match (self, other) {
-            (Value::Number(v1), Value::Number(v2)) =>
-                Ok(Value::Number( *v1 * v2 )),
-            (Value::String(v1), Value::Number(v2)) =>
-                Ok(Value::String( v1.repeat(*v2 as usize) )),
-            _ => Err(BaseError::InterpreterError("Operation '*' not defined for types".to_string()))
+        match (operands(self, other), self, other) {
+            (Some(Operands::Ints(v1, v2)), _, _) => Ok(Value::Int( v1.mul(v2) )),
+            (Some(Operands::Floats(v1, v2)), _, _) => Ok(Value::Number( v1 * v2 )),
+            (None, Value::String(v1), Value::Number(v2)) => match Int::from_f64(*v2) {
+                Some(count) => repeat(v1, &count),
+                None if v2.is_nan() => Ok(Value::String( String::new() )),
+                None => repeat(v1, &Int::from(v2.signum() as i64 * i64::MAX)),
+            },
+            (None, Value::String(v1), Value::Int(v2)) => repeat(v1, v2),
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Operation '*' not defined for types"))
         }
     }
+    /// Always a float; integers divide exactly with their `div` method.
     pub fn div(&self, other: &Value) -> ValueResult {
-        match (self, other) {
-            (Value::Number(v1), Value::Number(v2)) =>
-                Ok(Value::Number( *v1 / v2 )),
-            _ => Err(BaseError::InterpreterError("Operation '/' not defined for types".to_string()))
+        match operands(self, other) {
+            Some(Operands::Ints(v1, v2)) => Ok(Value::Number( v1.to_f64() / v2.to_f64() )),
+            Some(Operands::Floats(v1, v2)) => Ok(Value::Number( v1 / v2 )),
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Operation '/' not defined for types"))
         }
     }
     pub fn rem(&self, other: &Value) -> ValueResult {
-        match (self, other) {
-            (Value::Number(v1), Value::Number(v2)) =>
-                Ok(Value::Number( *v1 % v2 )),
-            _ => Err(BaseError::InterpreterError("Operation '%' not defined for types".to_string()))
+        match operands(self, other) {
+            Some(Operands::Ints(v1, v2)) => v1.rem(v2).map(Value::Int).ok_or_else(division_by_zero),
+            Some(Operands::Floats(v1, v2)) => Ok(Value::Number( v1 % v2 )),
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Operation '%' not defined for types"))
         }
     }
+    /// Exponents too large for 32 bits are left to floats as well, but
+    /// integers too large to build are an error.
+    ///
+    /// ```
+    /// let mut interp = bluebat::Interpreter::new();
+    /// assert!(interp.eval("10 ^ 4000000000\n").unwrap_err().to_string().contains("[E212]"));
+    /// let fine = interp.eval("[1 ^ 4000000000, (-1) ^ 4000000001, 0 ^ 4000000000, (2 ^ 64) ^ 1000 > 0]\n");
+    /// assert_eq!(interp.display(&fine.unwrap()), "[1,-1,0,True]");
+    /// ```
     pub fn pow(&self, other: &Value) -> ValueResult {
-        match (self, other) {
-            (Value::Number(v1), Value::Number(v2)) =>
-                Ok(Value::Number(f64::powf(*v1,*v2))),
-            _ => Err(BaseError::InterpreterError("Operation '^' not defined for types".to_string()))
+        match operands(self, other) {
+            Some(Operands::Ints(v1, v2)) => match v2.to_i64().and_then(|n| u32::try_from(n).ok()) {
+                Some(exponent) => v1.pow(exponent).map(Value::Int).ok_or_else(|| limit_error(
+                    format!("Result of '^' would take more than {} bits", MAX_POW_BITS)
+                )),
+                None => Ok(Value::Number( f64::powf(v1.to_f64(), v2.to_f64()) )),
+            },
+            Some(Operands::Floats(v1, v2)) => Ok(Value::Number(f64::powf(v1, v2))),
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Operation '^' not defined for types"))
         }
     }
     pub fn neg(&self) -> ValueResult {
         match self {
             Value::Number(value) => Ok(Value::Number(-value)),
-            _ => Err(BaseError::InterpreterError("Unary operation '-' not defined for type".to_string()))
+            Value::Int(value) => Ok(Value::Int(value.neg())),
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Unary operation '-' not defined for type"))
         }
     }
     pub fn give(&self) -> ValueResult {
         match self {
-            Value::Number(value) => Ok(Value::Number(*value)),
-            _ => Err(BaseError::InterpreterError("Unary operation '+' not defined for type (flushed emoji)".to_string()))
+            Value::Number(_) | Value::Int(_) => Ok(self.clone()),
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Unary operation '+' not defined for type (flushed emoji)"))
         }
     }
     pub fn not(&self) -> ValueResult {
         match self {
             Value::Bool(value) => Ok(Value::Bool(!value)),
-            _ => Err(BaseError::InterpreterError("Unary operation '!' not defined for type".to_string()))
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Unary operation '!' not defined for type"))
         }
     }
 
@@ -173,83 +432,115 @@ impl Value {
     pub fn eq(&self, other: &Value, memory: &Memory) -> ValueResult {
         match (self, other) {
             (Value::Null, Value::Null) => Ok(Value::Bool( true )),
-            (Value::Number(v1), Value::Number(v2)) => Ok(Value::Bool( *v1 == *v2 )),
+            (Value::Number(_) | Value::Int(_), Value::Number(_) | Value::Int(_)) => Ok(Value::Bool( compare(self, other) == Some(Some(Ordering::Equal)) )),
             (Value::Bool(v1), Value::Bool(v2)) => Ok(Value::Bool( *v1 == *v2 )),
             (Value::String(v1), Value::String(v2)) => Ok(Value::Bool( *v1 == *v2 )),
             (Value::TypeName(v1), Value::TypeName(v2)) => Ok(Value::Bool( *v1 == *v2 )),
+            (Value::Range(s1, e1), Value::Range(s2, e2)) => Ok(Value::Bool( s1 == s2 && e1 == e2 )),
+            (Value::Dict(map1), Value::Dict(map2)) => Ok(Value::Bool( Value::dicts_equal(map1, map2, memory) )),
             (Value::Array(arr1), Value::Array(arr2)) => {
                 if arr1.len() != arr2.len() { return Ok(Value::Bool(false)) }
                 for (i, j) in arr1.iter().zip(arr2.iter()) {
                     if *i == *j {
                         continue
                     } else {
-                        let a = memory.get(*i);
-                        let b = memory.get(*j);
-                        if !a.internal_equal(b, memory) { return Ok(Value::Bool(false)) }
+                        if !elements_equal(*i, *j, memory) { return Ok(Value::Bool(false)) }
                     }
                 }
                 Ok(Value::Bool(true))
             },
-            _ => Err(BaseError::InterpreterError("Operation '==' not defined for types".to_string()))
+            // anything can be checked for being Null, e.g. what `input` returns at the end of input
+            (Value::Null, _) | (_, Value::Null) => Ok(Value::Bool( false )),
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Operation '==' not defined for types"))
         }
     }
-    pub fn neq(&self, other: &Value) -> ValueResult {
-        match (self, other) {
-            (Value::Null, Value::Null) => Ok(Value::Bool( false )),
-            (Value::Number(v1), Value::Number(v2)) => Ok(Value::Bool( *v1 != *v2 )),
-            (Value::Bool(v1), Value::Bool(v2)) => Ok(Value::Bool( *v1 != *v2 )),
-            (Value::String(v1), Value::String(v2)) => Ok(Value::Bool( *v1 != *v2 )),
-            _ => Err(BaseError::InterpreterError("Operation '!=' not defined for types".to_string()))
+    pub fn neq(&self, other: &Value, memory: &Memory) -> ValueResult {
+        match self.eq(other, memory) {
+            Ok(Value::Bool(equal)) => Ok(Value::Bool( !equal )),
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Operation '!=' not defined for types"))
         }
     }
     pub fn gr(&self, other: &Value) -> ValueResult {
-        match (self, other) {
-            (Value::Number(v1), Value::Number(v2)) => Ok(Value::Bool( *v1 > *v2 )),
-            _ => Err(BaseError::InterpreterError("Operation '>' not defined for types".to_string()))
+        match compare(self, other) {
+            Some(ordering) => Ok(Value::Bool( matches!(ordering, Some(Ordering::Greater)) )),
+            None => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Operation '>' not defined for types"))
         }
     }
     pub fn greq(&self, other: &Value) -> ValueResult {
-        match (self, other) {
-            (Value::Number(v1), Value::Number(v2)) => Ok(Value::Bool( *v1 >= *v2 )),
-            _ => Err(BaseError::InterpreterError("Operation '>=' not defined for types".to_string()))
+        match compare(self, other) {
+            Some(ordering) => Ok(Value::Bool( matches!(ordering, Some(Ordering::Greater | Ordering::Equal)) )),
+            None => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Operation '>=' not defined for types"))
         }
     }
     pub fn sm(&self, other: &Value) -> ValueResult {
-        match (self, other) {
-            (Value::Number(v1), Value::Number(v2)) => Ok(Value::Bool( *v1 < *v2 )),
-            _ => Err(BaseError::InterpreterError("Operation '<' not defined for types".to_string()))
+        match compare(self, other) {
+            Some(ordering) => Ok(Value::Bool( matches!(ordering, Some(Ordering::Less)) )),
+            None => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Operation '<' not defined for types"))
         }
     }
     pub fn smeq(&self, other: &Value) -> ValueResult {
-        match (self, other) {
-            (Value::Number(v1), Value::Number(v2)) => Ok(Value::Bool( *v1 <= *v2 )),
-            _ => Err(BaseError::InterpreterError("Operation '<=' not defined for types".to_string()))
+        match compare(self, other) {
+            Some(ordering) => Ok(Value::Bool( matches!(ordering, Some(Ordering::Less | Ordering::Equal)) )),
+            None => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Operation '<=' not defined for types"))
+        }
+    }
+
+    pub fn range(&self, other: &Value) -> ValueResult {
+        match operands(self, other) {
+            Some(Operands::Ints(v1, v2)) => Ok(Value::Range(v1.to_f64(), v2.to_f64())),
+            Some(Operands::Floats(v1, v2)) => Ok(Value::Range(v1, v2)),
+            None => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Operation '..' not defined for types"))
+        }
+    }
+
+    /// An item of a range, which is an integer when it's a whole number.
+    pub fn range_item(n: f64) -> Value {
+        match Int::from_f64(n) {
+            Some(int) if int.to_f64() == n => Value::Int(int),
+            _ => Value::Number(n),
+        }
+    }
+
+    /// A number as an index, floored, with integers past the range of
+    /// indices held at its ends.
+    pub fn to_index(&self) -> Option<isize> {
+        match self {
+            Value::Number(n) => Some(n.floor() as isize),
+            Value::Int(n) => Some(match n.to_i64().and_then(|n| isize::try_from(n).ok()) {
+                Some(n) => n,
+                None if n.is_negative() => isize::MIN,
+                None => isize::MAX,
+            }),
+            _ => None,
         }
     }
 
     pub fn to_bool(&self) -> Result<bool, BaseError> {
         match self {
             Value::Bool(result) => Ok(*result),
-            _ => Err(BaseError::InterpreterError("Cannot convert to boolean".to_string()))
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Cannot convert to boolean"))
         }
     }
 
     pub fn sin(&self) -> ValueResult {
         match self {
             Value::Number(value) => Ok(Value::Number(value.sin())),
-            _ => Err(BaseError::InterpreterError("Expected number for argument".to_string()))
+            Value::Int(value) => Ok(Value::Number(value.to_f64().sin())),
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Expected number for argument"))
         }
     }
     pub fn cos(&self) -> ValueResult {
         match self {
             Value::Number(value) => Ok(Value::Number(value.cos())),
-            _ => Err(BaseError::InterpreterError("Expected number for argument".to_string()))
+            Value::Int(value) => Ok(Value::Number(value.to_f64().cos())),
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Expected number for argument"))
         }
     }
     pub fn tan(&self) -> ValueResult {
         match self {
             Value::Number(value) => Ok(Value::Number(value.tan())),
-            _ => Err(BaseError::InterpreterError("Expected number for argument".to_string()))
+            Value::Int(value) => Ok(Value::Number(value.to_f64().tan())),
+            _ => Err(BaseError::interpreter(ErrorCode::TypeMismatch, "Expected number for argument"))
         }
     }
 
@@ -0,0 +1,773 @@
+use std::rc::Rc;
+
+use crate::{
+    bytecode::{BinaryOp, Op, Pattern, Proto, UnaryOp},
+    errors::{BaseError, CallFrame, ErrorCode},
+    int::Int,
+    interpreter::{self, derive_scope, dict_entry, Memory, RegIndex, ScopeId, ScopeList, ValueResult},
+    methods,
+    native::NativeContext,
+    resolver::Slot,
+    value::{DictKey, Value},
+};
+
+
+/// The operands of the VM, the places it's working on, and the variables
+/// of the functions that keep them in their frame. They live in `Memory`
+/// so that the collector sees them as roots.
+#[derive(Debug, Default)]
+pub(crate) struct Stack {
+    pub(crate) values: Vec<Value>,
+    pub(crate) places: Vec<Place>,
+    pub(crate) locals: Vec<Local>,
+}
+
+/// A variable in a frame. Its value only moves to the heap once something
+/// needs its id, such as a method of it or `+=`.
+#[derive(Debug, Clone)]
+pub(crate) enum Local {
+    Unset,
+    Value(Value),
+    Boxed(RegIndex),
+}
+
+/// The id of a value that is about to be read or assigned. Read-only
+/// places are copies, such as the characters of strings, or methods.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Place {
+    pub(crate) id: RegIndex,
+    read_only: bool,
+}
+
+struct Frame {
+    proto: Rc<Proto>,
+    pc: usize,
+    /// Where the function's values start on the stack, with the function
+    /// itself first, which keeps the scope it was defined in alive.
+    base: usize,
+    /// The innermost scope the function has entered, or the one it was
+    /// defined in if it keeps its variables in its frame.
+    scope_id: ScopeId,
+    /// Where the function's variables start in `Stack::locals`.
+    locals: usize,
+    /// The scope the function was called from, through which the
+    /// collector finds the scopes of its callers.
+    caller: ScopeId,
+    /// Whether the VM pushed a call frame for the call, rather than
+    /// whoever called into the VM.
+    traced: bool,
+}
+
+/// Runs a compiled program in the global scope.
+pub fn start_run(proto: Rc<Proto>, scopes: &mut ScopeList, memory: &mut Memory) -> ValueResult {
+    memory.protected.clear();
+    memory.stack = Stack::default();
+    scopes.call_stack.clear();
+    scopes.budget.start();
+    run(Frame { proto, pc: 0, base: 0, scope_id: 0, locals: 0, caller: 0, traced: false }, memory, scopes)
+}
+
+/// Calls a compiled function from outside the VM, e.g. from the interpreter.
+pub(crate) fn call_closure(proto: Rc<Proto>, def_scope: ScopeId, args: Vec<Value>, scope_id: ScopeId, memory: &mut Memory, scopes: &mut ScopeList) -> ValueResult {
+    check_arity(&proto, args.len())?;
+    let base = memory.stack.values.len();
+    memory.stack.values.push(Value::Closure { proto: proto.clone(), scope_id: def_scope });
+    let frame = enter(proto, def_scope, scope_id, args.into_iter(), memory, scopes);
+    run(Frame { base, ..frame }, memory, scopes)
+}
+
+fn check_arity(proto: &Proto, count: usize) -> Result<(), BaseError> {
+    match proto.rest {
+        None if count != proto.params.len() =>
+            Err(BaseError::interpreter(ErrorCode::ArgumentCount, format!("Expected {} argument(s)", proto.params.len()))),
+        Some(_) if count < proto.params.len() =>
+            Err(BaseError::interpreter(ErrorCode::ArgumentCount, format!("Expected at least {} argument(s)", proto.params.len()))),
+        _ => Ok(()),
+    }
+}
+
+/// Sets up the frame for a call of `proto`, leaving its `base` and
+/// `traced` to the caller. The parameters are bound to the first variables
+/// of the function's scope, or of its frame if it keeps them there, and
+/// the rest parameter to the one after.
+fn enter(proto: Rc<Proto>, def_scope: ScopeId, caller: ScopeId, mut args: impl Iterator<Item = Value>, memory: &mut Memory, scopes: &mut ScopeList) -> Frame {
+    let locals = memory.stack.locals.len();
+    let params = proto.params.len();
+    let scope_id = match proto.locals {
+        Some(count) => {
+            memory.stack.locals.extend(args.by_ref().take(params).map(Local::Value));
+            if proto.rest.is_some() {
+                let extra = args.map(|value| memory.add(value)).collect();
+                memory.stack.locals.push(Local::Value(Value::Array(extra)));
+            }
+            memory.stack.locals.resize(locals + count as usize, Local::Unset);
+            def_scope
+        },
+        None => {
+            let run_scope = derive_scope(def_scope, caller, scopes);
+            for (index, value) in args.by_ref().take(params).enumerate() {
+                let id = memory.add(value);
+                scopes.bind(run_scope, index, id);
+            }
+            if proto.rest.is_some() {
+                let extra = args.map(|value| memory.add(value)).collect();
+                let id = memory.add(Value::Array(extra));
+                scopes.bind(run_scope, params, id);
+            }
+            run_scope
+        },
+    };
+    Frame { proto, pc: 0, base: 0, scope_id, locals, caller, traced: false }
+}
+
+fn run(frame: Frame, memory: &mut Memory, scopes: &mut ScopeList) -> ValueResult {
+    let (places, call_depth) = (memory.stack.places.len(), scopes.call_stack.len());
+    let (base, locals) = (frame.base, frame.locals);
+    let mut vm = Vm { frame, frames: Vec::new(), steps: 0, memory, scopes };
+    match vm.execute() {
+        Ok(value) => Ok(value),
+        Err(err) => {
+            let mut err = match vm.frame.proto.span(vm.frame.pc - 1) {
+                Some(span) => err.or_span(span),
+                None => err,
+            };
+            let diagnostic = err.diagnostic_mut();
+            if diagnostic.traceback.is_empty() {
+                diagnostic.traceback = vm.scopes.call_stack.clone();
+            }
+            vm.memory.stack.values.truncate(base);
+            vm.memory.stack.places.truncate(places);
+            vm.memory.stack.locals.truncate(locals);
+            vm.scopes.call_stack.truncate(call_depth);
+            Err(err)
+        },
+    }
+}
+
+struct Vm<'a> {
+    frame: Frame,
+    /// The callers of the current frame, outermost first.
+    frames: Vec<Frame>,
+    /// Instructions run since the budget was last told about them.
+    steps: u64,
+    memory: &'a mut Memory,
+    scopes: &'a mut ScopeList,
+}
+
+macro_rules! error_out {
+    ( $code:ident, $message:expr ) => {
+        { return Err(BaseError::interpreter(ErrorCode::$code, $message)); }
+    }
+}
+
+impl Vm<'_> {
+    fn push(&mut self, value: Value) {
+        self.memory.stack.values.push(value);
+    }
+
+    fn pop(&mut self) -> Value {
+        self.memory.stack.values.pop().unwrap()
+    }
+
+    fn top(&self) -> &Value {
+        self.memory.stack.values.last().unwrap()
+    }
+
+    fn pop_place(&mut self) -> Place {
+        self.memory.stack.places.pop().unwrap()
+    }
+
+    fn push_place(&mut self, id: RegIndex, read_only: bool) {
+        self.memory.stack.places.push(Place { id, read_only });
+    }
+
+    fn name(&self, index: u32) -> &str {
+        &self.frame.proto.names[index as usize]
+    }
+
+    /// The scope the collector starts from: the current one, or if the
+    /// function has none of its own, the one it was called from. The one
+    /// it was defined in is kept alive by the function on the stack.
+    fn root(&self) -> ScopeId {
+        match self.frame.proto.locals {
+            Some(_) => self.frame.caller,
+            None => self.frame.scope_id,
+        }
+    }
+
+    /// Settles the steps taken so far with the budget and gives the
+    /// collector a chance to run. Only jumps, calls and returns do this:
+    /// every loop and recursion goes through one, and the code in between
+    /// is straight-line, so it can't run or allocate for long.
+    fn tick(&mut self) -> Result<(), BaseError> {
+        let steps = std::mem::take(&mut self.steps);
+        interpreter::tick(steps, self.root(), self.memory, self.scopes)
+    }
+
+    fn execute(&mut self) -> ValueResult {
+        loop {
+            let op = self.frame.proto.code[self.frame.pc];
+            self.frame.pc += 1;
+            self.steps += 1;
+
+            match op {
+                Op::Const(index) => {
+                    let value = self.frame.proto.constants[index as usize].clone();
+                    self.push(value);
+                },
+                Op::Pop => { self.pop(); },
+                Op::PopN(n) => {
+                    let len = self.memory.stack.values.len();
+                    self.memory.stack.values.truncate(len - n as usize);
+                },
+                Op::Dup => {
+                    let value = self.top().clone();
+                    self.push(value);
+                },
+                Op::Unwind(n) => {
+                    let top = self.pop();
+                    let len = self.memory.stack.values.len();
+                    self.memory.stack.values.truncate(len - n as usize);
+                    self.push(top);
+                },
+                Op::GetVar { depth, index, name } => {
+                    let slot = Slot { depth: depth as usize, index: index as usize };
+                    let value = match self.scopes.get_var(self.frame.scope_id, slot) {
+                        Some(id) => self.memory.get(id)?.clone(),
+                        None => error_out!(UnknownVariable, format!("Unknown variable {}", self.name(name))),
+                    };
+                    self.push(value);
+                },
+                Op::SetVar { depth, index } => {
+                    let slot = Slot { depth: depth as usize, index: index as usize };
+                    let value = self.pop();
+                    self.scopes.set_var(self.frame.scope_id, slot, self.memory, &value)?;
+                    self.push(value);
+                },
+                Op::DeclareVar(index) => {
+                    let value = self.pop();
+                    self.scopes.set_var_local(self.frame.scope_id, index as usize, self.memory, &value);
+                    self.push(value);
+                },
+                Op::GetLocal { index, name } => {
+                    let value = match &self.memory.stack.locals[self.frame.locals + index as usize] {
+                        Local::Value(value) => value.clone(),
+                        Local::Boxed(id) => self.memory.get(*id)?.clone(),
+                        Local::Unset => error_out!(UnknownVariable, format!("Unknown variable {}", self.name(name))),
+                    };
+                    self.push(value);
+                },
+                Op::SetLocal(index) => {
+                    let value = self.top().clone();
+                    let at = self.frame.locals + index as usize;
+                    match self.memory.stack.locals[at] {
+                        Local::Boxed(id) => self.memory.set(value, id)?,
+                        _ => self.memory.stack.locals[at] = Local::Value(value),
+                    }
+                },
+                Op::DeclareLocal(index) => {
+                    let value = self.top().clone();
+                    self.memory.stack.locals[self.frame.locals + index as usize] = Local::Value(value);
+                },
+                Op::ClearLocals { start, count } => {
+                    let start = self.frame.locals + start as usize;
+                    self.memory.stack.locals[start..start + count as usize].fill(Local::Unset);
+                },
+                Op::Unary(op) => {
+                    let value = self.pop();
+                    self.push(match op {
+                        UnaryOp::Plus => value.give()?,
+                        UnaryOp::Minus => value.neg()?,
+                        UnaryOp::Not => value.not()?,
+                    });
+                },
+                Op::Binary(op) => {
+                    // two small ints are worked on in place, without moving them off the stack
+                    let values = &mut self.memory.stack.values;
+                    let len = values.len();
+                    if let [Value::Int(Int::Small(a)), Value::Int(Int::Small(b))] = values[len - 2..] {
+                        if let Some(value) = small_binary(op, a, b) {
+                            values.pop();
+                            values[len - 2] = value;
+                            continue
+                        }
+                    }
+                    let right = self.pop();
+                    let left = self.pop();
+                    let result = binary(op, &left, &right, self.memory).map_err(|err| {
+                        match self.frame.proto.operand_spans(self.frame.pc - 1) {
+                            Some((left_span, right_span)) => err
+                                .with_label(left_span, format!("this is {}", left.type_name()))
+                                .with_label(right_span, format!("this is {}", right.type_name())),
+                            None => err,
+                        }
+                    })?;
+                    self.push(result);
+                },
+                Op::Cast => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    self.push(left.cast_to(&right, self.memory)?);
+                },
+                Op::Jump(to) => {
+                    self.tick()?;
+                    self.frame.pc = to as usize;
+                },
+                Op::JumpIfFalse(to) => if !self.pop().to_bool()? {
+                    self.frame.pc = to as usize;
+                },
+                Op::JumpIfTrue(to) => if self.pop().to_bool()? {
+                    self.frame.pc = to as usize;
+                },
+                Op::EnterScope => {
+                    self.frame.scope_id = derive_scope(self.frame.scope_id, self.frame.scope_id, self.scopes);
+                },
+                Op::LeaveScope => {
+                    let scope_id = self.frame.scope_id;
+                    self.frame.scope_id = self.scopes.register[&scope_id].parent_id.unwrap();
+                    self.scopes.release(scope_id);
+                },
+                Op::Array(count) => {
+                    let len = self.memory.stack.values.len();
+                    let values: Vec<Value> = self.memory.stack.values.drain(len - count as usize..).collect();
+                    let ids = values.into_iter().map(|value| self.memory.add(value)).collect();
+                    self.push(Value::Array(ids));
+                },
+                Op::Append => {
+                    let value = self.pop();
+                    let id = self.memory.add(value);
+                    match self.memory.stack.values.last_mut() {
+                        Some(Value::Array(ids)) => ids.push(id),
+                        _ => error_out!(Internal, "Appending to a non-array"),
+                    }
+                },
+                Op::Extend => {
+                    let extra = match self.pop() {
+                        Value::Array(ids) => ids,
+                        value => error_out!(TypeMismatch, format!("Cannot spread {}", value.type_name())),
+                    };
+                    match self.memory.stack.values.last_mut() {
+                        Some(Value::Array(ids)) => ids.extend(extra),
+                        _ => error_out!(Internal, "Appending to a non-array"),
+                    }
+                },
+                Op::Dict { start, count } => {
+                    let len = self.memory.stack.values.len();
+                    let values: Vec<Value> = self.memory.stack.values.drain(len - count as usize..).collect();
+                    let mut map = std::collections::BTreeMap::new();
+                    for (i, value) in values.into_iter().enumerate() {
+                        let key = self.frame.proto.keys[start as usize + i].clone();
+                        map.insert(key, self.memory.add(value));
+                    }
+                    self.push(Value::Dict(map));
+                },
+                Op::PlaceVar { depth, index, name } => {
+                    let slot = Slot { depth: depth as usize, index: index as usize };
+                    match self.scopes.get_var(self.frame.scope_id, slot) {
+                        Some(id) => self.push_place(id, false),
+                        None => error_out!(UnknownVariable, format!("Unknown variable {}", self.name(name))),
+                    }
+                },
+                Op::PlaceLocal { index, name } => {
+                    let at = self.frame.locals + index as usize;
+                    let id = match std::mem::replace(&mut self.memory.stack.locals[at], Local::Unset) {
+                        Local::Value(value) => self.memory.add(value),
+                        Local::Boxed(id) => id,
+                        Local::Unset => error_out!(UnknownVariable, format!("Unknown variable {}", self.name(name))),
+                    };
+                    self.memory.stack.locals[at] = Local::Boxed(id);
+                    self.push_place(id, false);
+                },
+                Op::PlaceValue => {
+                    let value = self.pop();
+                    let id = self.memory.add(value);
+                    self.push_place(id, false);
+                },
+                Op::PlaceIndex { assign } => {
+                    let index = self.pop();
+                    let base = self.pop_place();
+                    self.place_index(base.id, index, assign)?;
+                },
+                Op::PlaceMember { name, assign } => {
+                    let base = self.pop_place();
+                    let name = self.name(name).to_string();
+                    self.place_member(base.id, name, assign)?;
+                },
+                Op::Load => {
+                    let place = self.pop_place();
+                    let value = self.memory.get(place.id)?.clone();
+                    self.push(value);
+                },
+                Op::Store => {
+                    let place = self.writable_place()?;
+                    let value = self.top().clone();
+                    self.memory.set(value, place.id)?;
+                },
+                Op::Compound(op) => {
+                    let place = self.writable_place()?;
+                    let right = self.pop();
+                    let value = binary(op, self.memory.get(place.id)?, &right, self.memory)?;
+                    self.memory.set(value.clone(), place.id)?;
+                    self.push(value);
+                },
+                Op::Destructure(index) => {
+                    let value = self.pop();
+                    let proto = self.frame.proto.clone();
+                    let pattern = &proto.patterns[index as usize];
+                    let mut targets = vec![Target::Unset; pattern.targets()];
+                    destructure(pattern, &value, false, &mut targets, self.memory)?;
+                    for target in targets.into_iter().rev() {
+                        let value = match target {
+                            Target::Unset => Value::Null,
+                            Target::Single(id) => self.memory.get(id)?.clone(),
+                            Target::Spread(ids) => Value::Array(ids),
+                        };
+                        self.push(value);
+                    }
+                },
+                Op::IterStart => {
+                    let iterable = match self.pop() {
+                        Value::String(s) => Value::Array(s.chars().map(|c| self.memory.add(Value::String(c.to_string()))).collect()),
+                        iterable @ (Value::Array(_) | Value::Range(..)) => iterable,
+                        iterable => error_out!(TypeMismatch, format!("Cannot iterate over {}", iterable.type_name())),
+                    };
+                    self.push(iterable);
+                    self.push(Value::Number(0.0));
+                },
+                Op::IterNext(exit) => {
+                    let len = self.memory.stack.values.len();
+                    let Value::Number(index) = self.memory.stack.values[len - 2] else { error_out!(Internal, "Corrupted loop state") };
+                    let item = match &self.memory.stack.values[len - 3] {
+                        Value::Array(arr) => match arr.get(index as usize) {
+                            Some(id) => Some(self.memory.get(*id)?.clone()),
+                            None => None,
+                        },
+                        Value::Range(start, end) => Some(start + index).filter(|n| n < end).map(Value::range_item),
+                        _ => error_out!(Internal, "Corrupted loop state"),
+                    };
+                    match item {
+                        Some(item) => {
+                            self.memory.stack.values[len - 2] = Value::Number(index + 1.0);
+                            self.push(item);
+                        },
+                        None => self.frame.pc = exit as usize,
+                    }
+                },
+                Op::Closure(index) => {
+                    self.scopes.capture(self.frame.scope_id);
+                    let proto = self.frame.proto.protos[index as usize].clone();
+                    self.push(Value::Closure { proto, scope_id: self.frame.scope_id });
+                },
+                Op::Call { argc, name } => {
+                    self.tick()?;
+                    let at = self.memory.stack.values.len() - argc as usize - 1;
+                    self.call(at, name)?;
+                },
+                Op::TailCall { argc, name } => {
+                    self.tick()?;
+                    let at = self.memory.stack.values.len() - argc as usize - 1;
+                    self.tail_call(at, name)?;
+                },
+                Op::CallSpread { name } => {
+                    self.tick()?;
+                    let args = match self.pop() {
+                        Value::Array(ids) => ids,
+                        _ => error_out!(Internal, "Spread arguments that aren't an array"),
+                    };
+                    let at = self.memory.stack.values.len() - 1;
+                    for id in args {
+                        let value = self.memory.get(id)?.clone();
+                        self.push(value);
+                    }
+                    self.call(at, name)?;
+                },
+                Op::Return => {
+                    self.tick()?;
+                    let value = self.pop();
+                    if self.frame.proto.locals.is_none() {
+                        self.scopes.release(self.frame.scope_id);
+                    }
+                    self.memory.stack.values.truncate(self.frame.base);
+                    self.memory.stack.locals.truncate(self.frame.locals);
+                    if self.frame.traced {
+                        self.scopes.call_stack.pop();
+                    }
+                    match self.frames.pop() {
+                        Some(caller) => {
+                            self.frame = caller;
+                            self.push(value);
+                        },
+                        None => return Ok(value),
+                    }
+                },
+            }
+        }
+    }
+
+    fn writable_place(&mut self) -> Result<Place, BaseError> {
+        let place = self.pop_place();
+        if place.read_only {
+            match self.memory.get(place.id)? {
+                Value::Method { receiver: _, name } => error_out!(InvalidAssignment, format!("Can't assign to method '{}'", name)),
+                _ => error_out!(InvalidAssignment, "Can't assign to string index"),
+            }
+        }
+        Ok(place)
+    }
+
+    fn place_index(&mut self, base_id: RegIndex, index: Value, assign: bool) -> Result<(), BaseError> {
+        let base_value = self.memory.get(base_id)?;
+        if let Value::Dict(_) = base_value {
+            let key = DictKey::from_value(&index)?;
+            return match dict_entry(base_id, &key, assign, self.memory)? {
+                Some(id) => { self.push_place(id, false); Ok(()) },
+                None => error_out!(KeyNotFound, format!("Key '{}' not found in dictionary", key.to_value().to_str(self.memory))),
+            }
+        }
+        let i = match index.to_index() {
+            Some(i) => i,
+            None => error_out!(TypeMismatch, "Cannot index with type"),
+        };
+        match base_value {
+            Value::Array(arr) => if i >= arr.len() as isize || i < 0 {
+                error_out!(IndexOutOfBounds, "Index out of bounds")
+            } else {
+                let id = arr[i as usize];
+                self.push_place(id, false);
+            },
+            Value::String(s) => if i >= s.chars().count() as isize || i < 0 {
+                error_out!(IndexOutOfBounds, "String index out of bounds")
+            } else {
+                let c = Value::String(s.chars().nth(i as usize).unwrap().to_string());
+                let id = self.memory.add(c);
+                self.push_place(id, true);
+            },
+            _ => error_out!(TypeMismatch, "Type cannot be indexed"),
+        }
+        Ok(())
+    }
+
+    fn place_member(&mut self, base_id: RegIndex, name: String, assign: bool) -> Result<(), BaseError> {
+        // fields shadow methods of the same name
+        if let Some(id) = dict_entry(base_id, &DictKey::String(name.clone()), false, self.memory)? {
+            self.push_place(id, false);
+            return Ok(())
+        }
+        let base_value = self.memory.get(base_id)?;
+        if methods::find_method(base_value, &name).is_some() {
+            let id = self.memory.add(Value::Method { receiver: base_id, name });
+            self.push_place(id, true);
+            return Ok(())
+        }
+        match base_value {
+            Value::Dict(_) if assign => {
+                let id = dict_entry(base_id, &DictKey::String(name), true, self.memory)?.unwrap();
+                self.push_place(id, false);
+                Ok(())
+            },
+            Value::Dict(_) => error_out!(KeyNotFound, format!("Key '{}' not found in dictionary", name)),
+            _ => error_out!(UnknownMember, format!("{} has no field or method '{}'", base_value.type_name(), name)),
+        }
+    }
+
+    /// Calls the value at `at` on the stack with the values above it in
+    /// place of the current function, if it's a compiled one. Anything else
+    /// is called as usual, for the `Return` after to return its value.
+    fn tail_call(&mut self, at: usize, name: u32) -> Result<(), BaseError> {
+        let (proto, def_scope) = match &self.memory.stack.values[at] {
+            Value::Closure { proto, scope_id } => (proto.clone(), *scope_id),
+            _ => return self.call(at, name),
+        };
+        let argc = self.memory.stack.values.len() - at - 1;
+        check_arity(&proto, argc)?;
+        let call_site = self.frame.proto.span(self.frame.pc - 1).unwrap_or_default();
+        if let Some(top) = self.scopes.call_stack.last_mut() {
+            let function = self.frame.proto.names[name as usize].clone();
+            *top = CallFrame { function, call_site, builtin: None, tail_calls: top.tail_calls + 1 };
+        }
+
+        if self.frame.proto.locals.is_none() {
+            self.scopes.release(self.frame.scope_id);
+        }
+        self.memory.stack.locals.truncate(self.frame.locals);
+        // the callee is called from where the current function was, and
+        // takes its place on the stack
+        let base = self.frame.base;
+        let mut stack = std::mem::take(&mut self.memory.stack.values);
+        let callee = enter(proto, def_scope, self.frame.caller, stack.drain(at + 1..), self.memory, self.scopes);
+        stack.drain(base..at);
+        self.memory.stack.values = stack;
+        self.frame = Frame { base, traced: self.frame.traced, ..callee };
+        Ok(())
+    }
+
+    /// Calls the value at `at` on the stack with the values above it.
+    fn call(&mut self, at: usize, name: u32) -> Result<(), BaseError> {
+        let call_site = self.frame.proto.span(self.frame.pc - 1).unwrap_or_default();
+        let builtin = match &self.memory.stack.values[at] {
+            Value::Builtin(native) => Some(native.name.clone()),
+            Value::Method { receiver: _, name } => Some(name.clone()),
+            _ => None,
+        };
+        self.scopes.budget.check_call_depth(self.scopes.call_stack.len())?;
+        self.scopes.call_stack.push(CallFrame { function: self.frame.proto.names[name as usize].clone(), call_site, builtin, tail_calls: 0 });
+
+        if let Value::Closure { proto, scope_id: def_scope } = &self.memory.stack.values[at] {
+            let (proto, def_scope) = (proto.clone(), *def_scope);
+            let argc = self.memory.stack.values.len() - at - 1;
+            check_arity(&proto, argc)?;
+            let caller = self.root();
+            // nothing is collected while the arguments are off the stack
+            let mut stack = std::mem::take(&mut self.memory.stack.values);
+            let callee = enter(proto, def_scope, caller, stack.drain(at + 1..), self.memory, self.scopes);
+            self.memory.stack.values = stack;
+            self.frames.push(std::mem::replace(&mut self.frame, Frame { base: at, traced: true, ..callee }));
+            return Ok(())
+        }
+
+        let args = self.memory.stack.values.split_off(at + 1);
+        let base = self.pop();
+        let value = match base {
+            Value::Builtin(native) => {
+                let scope_id = self.root();
+                native.call(&mut NativeContext { memory: self.memory, scopes: self.scopes, scope_id }, args)?
+            },
+            Value::Method { receiver, name } => {
+                let receiver_value = self.memory.get(receiver)?;
+                let method = match methods::find_method(receiver_value, &name) {
+                    Some(method) => method,
+                    None => error_out!(InvalidCall, format!("{} has no method '{}'", receiver_value.type_name(), name)),
+                };
+                if args.len() != method.arity {
+                    error_out!(ArgumentCount, format!{"Expected {} argument(s)", method.arity})
+                }
+                (method.func)(receiver, &args, self.memory)?
+            },
+            function @ Value::Function { .. } => {
+                interpreter::call_function(function, args, self.root(), self.memory, self.scopes)?
+            },
+            _ => error_out!(InvalidCall, "Invalid base for call"),
+        };
+        self.scopes.call_stack.pop();
+        self.push(value);
+        Ok(())
+    }
+}
+
+#[inline]
+fn binary(op: BinaryOp, left: &Value, right: &Value, memory: &Memory) -> ValueResult {
+    if let (Value::Int(Int::Small(a)), Value::Int(Int::Small(b))) = (left, right) {
+        if let Some(value) = small_binary(op, *a, *b) {
+            return Ok(value)
+        }
+    }
+    match op {
+        BinaryOp::Add => left.plus(right),
+        BinaryOp::Sub => left.minus(right),
+        BinaryOp::Mul => left.mult(right),
+        BinaryOp::Div => left.div(right),
+        BinaryOp::Mod => left.rem(right),
+        BinaryOp::Pow => left.pow(right),
+        BinaryOp::Greater => left.gr(right),
+        BinaryOp::GreaterEq => left.greq(right),
+        BinaryOp::Lesser => left.sm(right),
+        BinaryOp::LesserEq => left.smeq(right),
+        BinaryOp::Eq => left.eq(right, memory),
+        BinaryOp::NotEq => left.neq(right, memory),
+        BinaryOp::Range => left.range(right),
+    }
+}
+
+/// The common cases of `binary` on two small integers, without going
+/// through `Value`'s methods. `None` for the rest, and on overflow.
+fn small_binary(op: BinaryOp, a: i64, b: i64) -> Option<Value> {
+    match op {
+        BinaryOp::Add => a.checked_add(b).map(|n| Value::Int(Int::Small(n))),
+        BinaryOp::Sub => a.checked_sub(b).map(|n| Value::Int(Int::Small(n))),
+        BinaryOp::Mul => a.checked_mul(b).map(|n| Value::Int(Int::Small(n))),
+        BinaryOp::Greater => Some(Value::Bool(a > b)),
+        BinaryOp::GreaterEq => Some(Value::Bool(a >= b)),
+        BinaryOp::Lesser => Some(Value::Bool(a < b)),
+        BinaryOp::LesserEq => Some(Value::Bool(a <= b)),
+        BinaryOp::Eq => Some(Value::Bool(a == b)),
+        BinaryOp::NotEq => Some(Value::Bool(a != b)),
+        _ => None,
+    }
+}
+
+/// What a pattern's target ends up with.
+#[derive(Debug, Clone)]
+enum Target {
+    Unset,
+    Single(RegIndex),
+    Spread(Vec<RegIndex>),
+}
+
+/// Takes `value` apart the way the interpreter's `assign` does, with
+/// targets standing in for the variables.
+fn destructure(pattern: &Pattern, value: &Value, spread: bool, targets: &mut [Target], memory: &Memory) -> Result<(), BaseError> {
+    match pattern {
+        Pattern::Target(_) => unreachable!("a target is assigned by id"),
+        Pattern::Array(elements) => {
+            let Value::Array(r_values) = value else { error_out!(Destructure, "Cannot destructure non-array") };
+            let spread_vars = elements.iter().filter(|(spread, _)| *spread).count();
+            if spread_vars == 0 {
+                if elements.len() != r_values.len() {
+                    error_out!(Destructure, format!("Inequal amount of values to destructure, expected {}", elements.len()))
+                }
+                for ((_, pattern), id) in elements.iter().zip(r_values) {
+                    destructure_id(pattern, *id, spread, targets, memory)?;
+                }
+                return Ok(())
+            }
+            if elements.len() - spread_vars > r_values.len() {
+                error_out!(Destructure, format!("Not enough values to destructure, expected at least {}", elements.len() - spread_vars))
+            }
+            let spread_amount = r_values.len() - (elements.len() - spread_vars);
+            let modulo = spread_amount % spread_vars;
+            let mut r_values = r_values.iter();
+            let mut current_spread = 0;
+            for (is_spread, pattern) in elements {
+                if !is_spread {
+                    destructure_id(pattern, *r_values.next().unwrap(), spread, targets, memory)?;
+                    continue
+                }
+                let length = spread_amount / spread_vars + if current_spread < modulo { 1 } else { 0 };
+                current_spread += 1;
+                if length == 0 {
+                    if let Pattern::Target(n) = pattern {
+                        if !matches!(targets[*n as usize], Target::Spread(_)) {
+                            targets[*n as usize] = Target::Spread(Vec::new());
+                        }
+                    }
+                }
+                for _ in 0..length {
+                    destructure_id(pattern, *r_values.next().unwrap(), true, targets, memory)?;
+                }
+            }
+        },
+        Pattern::Dict(entries) => {
+            let Value::Dict(r_map) = value else { error_out!(Destructure, "Cannot destructure non-dictionary") };
+            for (key, pattern) in entries {
+                match r_map.get(key) {
+                    Some(id) => destructure_id(pattern, *id, spread, targets, memory)?,
+                    None => error_out!(Destructure, format!("Cannot destructure missing key '{}'", key.to_value().to_str(memory))),
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+fn destructure_id(pattern: &Pattern, id: RegIndex, spread: bool, targets: &mut [Target], memory: &Memory) -> Result<(), BaseError> {
+    let Pattern::Target(n) = pattern else {
+        return destructure(pattern, memory.get(id)?, spread, targets, memory)
+    };
+    let target = &mut targets[*n as usize];
+    match target {
+        Target::Spread(ids) if spread => ids.push(id),
+        _ if spread => *target = Target::Spread(vec![id]),
+        _ => *target = Target::Single(id),
+    }
+    Ok(())
+}
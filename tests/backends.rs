@@ -0,0 +1,146 @@
+//! The tree-walker and the VM have to agree: every script here runs on
+//! both, and what it prints, its result and its error code must match.
+
+use std::{cell::RefCell, io::{self, Cursor, Write}, rc::Rc};
+
+use bluebat::{blbc::Program, errors::ErrorCode, Backend, Error, Interpreter, StreamIo};
+
+
+const CORPUS: &[&str] = &[
+    // arithmetic and numbers
+    "[1 + 2 * 3, 7 - 10, 2 ^ 10, 7 % 3, -7 % 3, 7 / 2, 1 / 0, 7.div(2)]",
+    "[2 ^ 64 + 1, (2 ^ 64) * (2 ^ 64) - 1, 9223372036854775807 + 1, -9223372036854775807 - 2]",
+    "[0.1 + 0.2, 1.5 * 2, 2 ^ 0.5, 2 ^ -1, 3 > 2.5, 2 == 2.0, 2 ^ 53 + 1 > 2.0 ^ 53]",
+    "[(2.7).floor(), (2.2).ceil(), (-3).abs(), (16).sqrt(), (2.5).round()]",
+    "7.div(0)",
+    "1 + 'a'",
+    "10 ^ 4000000000",
+    // strings
+    "['ab' * 3, 'ab' + 'cd', 'abc'[1], 'a,b,c'.split(','), ' x '.trim(), 'Hi'.upper()]",
+    "['abc'.contains('b'), 'abc'.starts_with('ab'), 'abc'.replace('b', 'x'), 'abc'.len(), len('abcd')]",
+    "'abc'[5]",
+    "'ab' * 2 ^ 70",
+    "[12 as #string, '12' as #number, 1.5 as #string, True as #string]",
+    // arrays and dicts
+    "a = [3, 1, 2]\na.push(4)\na[0] = 9\n[a, a.len(), a.contains(2), a.reverse(), a.pop(), a]",
+    "a = [1, 2]\nb = a\nb.push(3)\n[a, b, a == b, [1, [2]] == [1, [2]]]",
+    "d = {a: 1, 'b': 2, 3: 'c'}\nd.x = 4\nd['y'] = 5\n[d, d.keys(), d.values(), d.has('a'), d.len()]",
+    "d = {:}\nd[1] = 'one'\nd[1.0] = 'uno'\n[d, d.remove(1), d]",
+    "[1, 2][2]",
+    "{a: 1}.b",
+    "{a: 1}['b']",
+    "x = 5\nx.nothing",
+    "[1, ..[2, 3], 4]",
+    "[[1] != [2], [1, [2]] != [1, [2]], {a: 1} != {a: 2}, {a: [1]} != {a: [1]}, 'a'..'b' != 1, 1 != 'a']",
+    // control flow
+    "i = 0\ns = 0\nwhile i < 100 {\n  i += 1\n  if i % 2 == 0 continue\n  if i > 50 break\n  s += i\n}\n[i, s]",
+    "s = []\nfor x in 0..5 {\n  s.push(x * x)\n}\nfor c in 'abc' s.push(c)\ns",
+    "s = 0\nfor [a, b] in [[1, 2], [3, 4]] s += a * b\ns",
+    "[if True 1 else 2, if False 1, if (1 > 2) 'a' else if (2 > 1) 'b' else 'c']",
+    "x = while False 1\nx",
+    "[a, b, ..rest] = [1, 2, 3, 4]\n[a, b, rest]",
+    "[a, [b, c]] = [1, [2, 3]]\n[a, b, c]",
+    "break",
+    // variables and closures
+    "x = 1\n{\n  x = 2\n  y := 3\n  x := 4\n}\nx",
+    "counter = || {\n  n = 0\n  || {\n    n += 1\n    n\n  }\n}\nc = counter()\nc()\nc()\n[c(), counter()()]",
+    "adders = []\nfor i in 0..3 adders.push(|x| x + i)\n[adders[0](10), adders[2](10)]",
+    "f = |a, b| a * b\n[f(2, 3), f(..[4, 5])]",
+    "f = |a| a\nf(1, 2)",
+    "nope + 1",
+    "5(1)",
+    // recursion, tail calls and their limits
+    "fib = |n| if (n < 2) n else fib(n - 1) + fib(n - 2)\nfib(20)",
+    "even = |n| if (n == 0) True else odd(n - 1)\nodd = |n| if (n == 0) False else even(n - 1)\neven(10001)",
+    "loop = |n, acc| if (n == 0) acc else loop(n - 1, acc + n)\nloop(100000, 0)",
+    "down = |n| if (n == 0) 0 else 1 + down(n - 1)\ndown(100000)",
+    "f = |n| {\n  if n > 3 return n\n  f(n + 1)\n}\nf(0)",
+    // functions that create none keep their variables in their frame
+    "x = 10\nf = |n| {\n  x += n\n  y = x * 2\n  [x, y]\n}\n[f(1), f(2), x]",
+    "f = |a| {\n  a.push(3)\n  n = 1\n  n += 2\n  s = 'ab'\n  [a, n, s.upper(), a.len()]\n}\nf([1])",
+    "f = |a, ..rest| [a, rest, rest.len()]\n[f(1), f(1, 2, 3)]",
+    "f = || {\n  x = 1\n  {\n    x := 2\n    x += 1\n    y := x\n  }\n  x\n}\nf()",
+    "f = || {\n  r = []\n  for i in 0..3 {\n    if i == 0 y = 5\n    r.push(i)\n  }\n  r\n}\nf()",
+    "f = || {\n  for i in 0..2 {\n    if i == 1 println(y)\n    y = i\n  }\n}\nf()",
+    "f = |p| {\n  [a, ..b] = p\n  e = 0\n  for [c, d] in [[a, b]] e = [d, c]\n  e\n}\nf([1, 2, 3])",
+    "make = |k| |x| x * k\n[make(2)(5), make(3)(5)]",
+    "count = |n, acc| if (n == 0) acc else count(n - 1, acc + 1)\ncount(10000, 0)",
+    "f = || {\n  z = y\n  y = 1\n}\nf()",
+    // builtins and printing
+    "println(1, 'two', [3], {four: 4})\nprint('no newline')\nprintln()",
+    "println(sin(0), cos(0), len([1, 2, 3]))",
+    "x = [1]\nx.push(x)\nprintln(x)\nx",
+    "println('before')\n1 / 'x'",
+];
+
+/// A writer that can still be read after it was handed to the interpreter.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    printed: String,
+    result: Result<String, ErrorCode>,
+}
+
+fn run(backend: Backend, code: &str) -> Outcome {
+    let output = Output::default();
+    let mut interp = Interpreter::new();
+    interp.set_backend(backend);
+    interp.set_io(StreamIo { input: Cursor::new(""), output: output.clone() });
+    let result = match interp.eval(&format!("{}\n", code)) {
+        Ok(value) => Ok(interp.display(&value)),
+        Err(Error::Script(err)) => Err(err.error.diagnostic().code),
+        Err(err) => panic!("{} failed outside the script: {}", code, err),
+    };
+    let printed = String::from_utf8(output.0.take()).unwrap();
+    Outcome { printed, result }
+}
+
+#[test]
+fn backends_agree() {
+    for code in CORPUS {
+        let tree_walker = run(Backend::TreeWalker, code);
+        let vm = run(Backend::Vm, code);
+        assert_eq!(tree_walker, vm, "the backends disagree on\n{}", code);
+    }
+}
+
+#[test]
+fn inequality_is_the_negation_of_equality() {
+    let code = "\
+a = {x: [1, {y: 2}], z: 'z'}
+b = {z: 'z', x: [1, {y: 2}]}
+c = {x: [1, {y: 3}], z: 'z'}
+[a != b, a != c, [a] != [b], [a, 1] != [b], #number != #number, #number != #string, 0..2 != 0..3, a != Null]";
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let expected = Ok("[False,True,False,True,False,True,True,True]".to_string());
+        assert_eq!(run(backend, code).result, expected, "on {:?}", backend);
+    }
+}
+
+#[test]
+fn frame_variables_survive_bytecode_files() {
+    let code = "\
+f = |a, ..rest| {
+  total = a
+  for n in rest total += n
+  seen = []
+  seen.push(total)
+  [total, seen]
+}
+f(1, 2, 3)";
+    let mut interp = Interpreter::new();
+    let bytes = interp.compile(code, "<test>").unwrap().encode();
+    let value = interp.run_program(Program::decode(&bytes).unwrap(), "<test>", None).unwrap();
+    assert_eq!(interp.display(&value), "[6,[6]]");
+}
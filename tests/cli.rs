@@ -0,0 +1,30 @@
+//! Every way of running code from the command line defines `args`.
+
+use std::{io::Write, process::{Command, Stdio}};
+
+
+fn bluebat(args: &[&str], stdin: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bluebat"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn args_in_every_mode() {
+    for vm in [&[][..], &["--vm"]] {
+        let with = |args: &[&'static str]| [vm, args].concat();
+        assert_eq!(bluebat(&with(&["-e", "args\n", "a", "b"]), ""), "[a,b]\n");
+        assert_eq!(bluebat(&with(&["run", "-", "a"]), "println(args)\n"), "[a]\n");
+        let repl = bluebat(&with(&["repl"]), "len(args)\nargs.push(1)\nargs\n");
+        assert!(repl.contains(">>> 0\n"), "{}", repl);
+        assert!(repl.contains(">>> [1]\n"), "{}", repl);
+    }
+}
@@ -0,0 +1,193 @@
+//! Incremental collection with the heap changing between slices. Every
+//! change is tried after each number of marking steps, so that whatever
+//! order the collector scans in, some run changes a value it already
+//! marked and relies on the write barrier.
+
+use bluebat::{
+    interpreter::{Memory, RegIndex, ScopeList},
+    resolver::Slot,
+    value::{DictKey, Value},
+    Backend, GcPolicy, Interpreter,
+};
+
+
+/// A heap with `moved` reachable only through the array `from`, and an
+/// empty array and dictionary for it to be moved into, all in globals.
+struct Heap {
+    memory: Memory,
+    scopes: ScopeList,
+    from: RegIndex,
+    array: RegIndex,
+    dict: RegIndex,
+    var: Slot,
+    moved: RegIndex,
+    garbage: Vec<RegIndex>,
+}
+
+impl Heap {
+    fn new() -> Heap {
+        let mut memory = Memory::new();
+        let mut scopes = ScopeList::new();
+        let moved = memory.add(Value::String("moved".to_string()));
+        let from = scopes.set_var_local(0, 0, &mut memory, &Value::Array(vec![moved]));
+        let array = scopes.set_var_local(0, 1, &mut memory, &Value::Array(Vec::new()));
+        let dict = scopes.set_var_local(0, 2, &mut memory, &Value::Dict(Default::default()));
+        scopes.set_var_local(0, 3, &mut memory, &Value::Null);
+        // enough other globals that marking takes a while
+        for index in 4..40 {
+            scopes.set_var_local(0, index, &mut memory, &Value::Int((index as i64).into()));
+        }
+        let garbage = unreachable_cycle(&mut memory);
+        Heap { memory, scopes, from, array, dict, var: Slot { depth: 0, index: 3 }, moved, garbage }
+    }
+
+    fn step(&mut self) -> bool {
+        self.memory.collect_step(&mut self.scopes, 0, 1)
+    }
+
+    fn finish(&mut self) {
+        while !self.step() {}
+    }
+
+    /// Takes `moved` out of the array it started in.
+    fn unlink(&mut self) {
+        self.memory.set(Value::Array(Vec::new()), self.from).unwrap();
+    }
+
+    fn assert_collected_correctly(&self) {
+        assert!(matches!(self.memory.get(self.moved), Ok(Value::String(s)) if s == "moved"));
+        for id in &self.garbage {
+            assert!(self.memory.get(*id).is_err());
+        }
+    }
+}
+
+/// Two arrays and a dictionary pointing at each other, and at nothing else.
+fn unreachable_cycle(memory: &mut Memory) -> Vec<RegIndex> {
+    let first = memory.add(Value::Array(Vec::new()));
+    let second = memory.add(Value::Array(vec![first]));
+    let dict = memory.add(Value::Dict([(DictKey::String("back".to_string()), second)].into()));
+    memory.set(Value::Array(vec![second, dict]), first).unwrap();
+    vec![first, second, dict]
+}
+
+/// Runs `change` after each number of marking steps, and checks the
+/// outcome of the cycle.
+fn interleave(change: impl Fn(&mut Heap)) {
+    for steps in 0..60 {
+        let mut heap = Heap::new();
+        let mut finished = false;
+        for _ in 0..=steps {
+            finished = heap.step();
+        }
+        assert!(!finished, "marking ended before the change");
+        change(&mut heap);
+        heap.finish();
+        heap.assert_collected_correctly();
+    }
+}
+
+#[test]
+fn array_push_during_marking() {
+    interleave(|heap| {
+        let Ok(Value::Array(ids)) = heap.memory.get(heap.array) else { unreachable!() };
+        let mut ids = ids.clone();
+        ids.push(heap.moved);
+        heap.memory.set(Value::Array(ids), heap.array).unwrap();
+        heap.unlink();
+    });
+}
+
+#[test]
+fn dict_insert_during_marking() {
+    interleave(|heap| {
+        let Ok(Value::Dict(map)) = heap.memory.get(heap.dict) else { unreachable!() };
+        let mut map = map.clone();
+        map.insert(DictKey::String("moved".to_string()), heap.moved);
+        heap.memory.set(Value::Dict(map), heap.dict).unwrap();
+        heap.unlink();
+    });
+}
+
+#[test]
+fn rebinding_a_variable_during_marking() {
+    interleave(|heap| {
+        let value = Value::Array(vec![heap.moved]);
+        heap.scopes.set_var(0, heap.var, &mut heap.memory, &value).unwrap();
+        heap.unlink();
+    });
+}
+
+#[test]
+fn values_allocated_during_marking_survive() {
+    interleave(|heap| {
+        let fresh = heap.memory.add(Value::String("fresh".to_string()));
+        heap.memory.set(Value::Array(vec![fresh]), heap.array).unwrap();
+        heap.finish();
+        assert!(heap.memory.get(fresh).is_ok());
+    });
+}
+
+#[test]
+fn unreachable_cycles_are_freed() {
+    let mut heap = Heap::new();
+    let live = heap.memory.len();
+    heap.finish();
+    heap.assert_collected_correctly();
+    assert_eq!(heap.memory.len(), live - heap.garbage.len());
+    assert_eq!(heap.memory.stats(&heap.scopes).freed, heap.garbage.len() as u64);
+}
+
+#[test]
+fn scripts_mutate_while_collecting() {
+    let code = "\
+keep = []
+d = {:}
+for i in 0..2000 {
+  keep.push([i, [i]])
+  d[i % 50] = [i]
+  pair = [keep[i], d[i % 50]]
+  junk = [[i], {x: i}]
+}
+sum = 0
+for [i, [j]] in keep sum += i + j
+for k in d.keys() sum += d[k][0]
+sum
+";
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        for work in [5, 20, 1000] {
+            let mut interp = Interpreter::new();
+            interp.set_backend(backend);
+            interp.set_gc_policy(GcPolicy::Incremental { step: 100, work });
+            let sum = interp.eval(code).unwrap();
+            assert_eq!(interp.display(&sum), "4096725", "{:?} with work {}", backend, work);
+            assert!(interp.gc_stats().collections > 0);
+        }
+    }
+}
+
+#[test]
+fn frame_variables_are_roots() {
+    let code = "\
+build = |n| {
+  keep = []
+  for i in 0..n {
+    item = [i, [i]]
+    keep.push(item)
+    junk = [[i], {x: i}]
+  }
+  sum = 0
+  for [i, [j]] in keep sum += i + j
+  sum
+}
+build(2000)
+";
+    for policy in [GcPolicy::Fixed(50), GcPolicy::Incremental { step: 100, work: 5 }] {
+        let mut interp = Interpreter::new();
+        interp.set_backend(Backend::Vm);
+        interp.set_gc_policy(policy);
+        let sum = interp.eval(code).unwrap();
+        assert_eq!(interp.display(&sum), "3998000", "with {:?}", policy);
+        assert!(interp.gc_stats().collections > 0);
+    }
+}
@@ -0,0 +1,39 @@
+//! Comments are skipped, but a block comment that spans lines still ends
+//! the statement in front of it.
+
+use bluebat::{lexer::{lex, Token}, Backend, Interpreter};
+
+
+fn tokens(code: &str) -> Vec<Token> {
+    lex(code).unwrap().into_iter().map(|t| t.token).collect()
+}
+
+fn int(n: i64) -> Token {
+    Token::Int(n.into())
+}
+
+#[test]
+fn multi_line_block_comment_ends_the_line() {
+    let code = "a = 1 /* x\n /* nested\n */ */ b = 2\n";
+    assert_eq!(tokens(code), [
+        Token::Identifier("a".to_string()), Token::Assign, int(1), Token::Eol,
+        Token::Identifier("b".to_string()), Token::Assign, int(2), Token::Eol,
+        Token::Eol, Token::Eof,
+    ]);
+    let spans: Vec<_> = lex(code).unwrap().into_iter().map(|t| (t.span.line, t.span.col)).collect();
+    assert_eq!(spans[3], (1, 7));
+    assert_eq!(spans[4], (3, 8));
+
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut interp = Interpreter::new();
+        interp.set_backend(backend);
+        let value = interp.eval(&format!("{}[a, b]\n", code)).unwrap();
+        assert_eq!(interp.display(&value), "[1,2]", "on {:?}", backend);
+    }
+}
+
+#[test]
+fn single_line_comments_are_skipped() {
+    assert_eq!(tokens("1 /* x */ + /* y */ 2"), [int(1), Token::Plus, int(2), Token::Eol, Token::Eof]);
+    assert_eq!(tokens("1 // x\n2"), [int(1), Token::Eol, int(2), Token::Eol, Token::Eof]);
+}
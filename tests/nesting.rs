@@ -0,0 +1,64 @@
+//! Deeply nested code and values have to end in an error or a result,
+//! never in a stack overflow, even on a thread with a small stack.
+
+use std::thread;
+
+use bluebat::{Backend, Interpreter};
+
+
+fn on_small_stack(test: impl FnOnce() + Send + 'static) {
+    thread::Builder::new().stack_size(1 << 20).spawn(test).unwrap().join().unwrap();
+}
+
+fn eval(backend: Backend, code: &str) -> Result<String, String> {
+    let mut interp = Interpreter::new();
+    interp.set_backend(backend);
+    interp.eval(code).map(|value| interp.display(&value)).map_err(|err| err.to_string())
+}
+
+#[test]
+fn nesting_up_to_the_limit_runs() {
+    on_small_stack(|| {
+        let n = 250;
+        for code in [
+            format!("{}1{}\n", "(".repeat(n), ")".repeat(n)),
+            format!("len({}1{})\n", "[".repeat(n - 1), "]".repeat(n - 1)),
+            format!("{}1\n", "-".repeat(n / 2)),
+            format!("x = {}1\n", "y = ".repeat(n / 2)),
+            format!("{}1\n{}", "{\n".repeat(n), "}\n".repeat(n)),
+            format!("{}1\n", "if True ".repeat(n)),
+            format!("f = |x| x\n{}1{}\n", "f(".repeat(n), ")".repeat(n)),
+            format!("f = {}1\n", "|| ".repeat(n)),
+        ] {
+            for backend in [Backend::TreeWalker, Backend::Vm] {
+                assert!(eval(backend, &code).is_ok(), "{:?} failed on {}", backend, code);
+            }
+        }
+    });
+}
+
+#[test]
+fn nesting_past_the_limit_is_an_error() {
+    on_small_stack(|| {
+        for code in [
+            format!("{}1{}\n", "(".repeat(50_000), ")".repeat(50_000)),
+            format!("{}1{}\n", "[".repeat(50_000), "]".repeat(50_000)),
+            format!("{}1\n", "-".repeat(50_000)),
+            format!("{}1\n", "x = ".repeat(50_000)),
+            format!("{}1\n", "2 ^ ".repeat(50_000)),
+        ] {
+            let err = eval(Backend::TreeWalker, &code).unwrap_err();
+            assert!(err.contains("[E212]"), "{}", err);
+        }
+    });
+}
+
+#[test]
+fn deep_values_compare_and_print() {
+    on_small_stack(|| {
+        let code = "a = []\nb = []\nfor i in 0..20000 {\n  a = [a]\n  b = [b]\n}\n[a == b, len(a as #string)]\n";
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            assert_eq!(eval(backend, code).unwrap(), "[True,40002]");
+        }
+    });
+}
@@ -0,0 +1,113 @@
+//! Which variable a name refers to is settled before anything runs; both
+//! backends have to follow the resolver's slots the same way.
+
+use bluebat::{errors::ErrorCode, Backend, BaseError, Error, Interpreter};
+
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Value(String),
+    ParseError(ErrorCode),
+    RuntimeError(ErrorCode),
+}
+
+/// Evaluates `programs` one after another in the same interpreter, as the
+/// REPL does, and returns what the last one gave.
+fn run(backend: Backend, programs: &[&str]) -> Outcome {
+    let mut interp = Interpreter::new();
+    interp.set_backend(backend);
+    let mut outcome = None;
+    for program in programs {
+        outcome = Some(match interp.eval(&format!("{}\n", program)) {
+            Ok(value) => Outcome::Value(interp.display(&value)),
+            Err(Error::Script(err)) => match err.error {
+                BaseError::LexError(d) | BaseError::ParseError(d) => Outcome::ParseError(d.code),
+                BaseError::InterpreterError(d) => Outcome::RuntimeError(d.code),
+            },
+            Err(err) => panic!("{} failed outside the script: {}", program, err),
+        });
+    }
+    outcome.unwrap()
+}
+
+fn check(cases: &[(&[&str], Outcome)]) {
+    for (programs, expected) in cases {
+        for backend in [Backend::TreeWalker, Backend::Vm] {
+            assert_eq!(&run(backend, programs), expected, "{:?} on {:?}", backend, programs);
+        }
+    }
+}
+
+fn value(display: &str) -> Outcome {
+    Outcome::Value(display.to_string())
+}
+
+#[test]
+fn hoisting() {
+    check(&[
+        (&["f = || g()\ng = || 1\nf()"], value("1")),
+        (&["even = |n| if (n == 0) True else odd(n - 1)\nodd = |n| if (n == 0) False else even(n - 1)\nodd(7)"], value("True")),
+        // hoisted, but not assigned yet when it's read
+        (&["x\nx = 1"], Outcome::RuntimeError(ErrorCode::UnknownVariable)),
+        (&["f = || later\nf()\nlater = 1"], Outcome::RuntimeError(ErrorCode::UnknownVariable)),
+        // assignments inside a function belong to it
+        (&["f = || {\n  t = 1\n}\nf()\nt"], Outcome::ParseError(ErrorCode::UnknownVariable)),
+        (&["n = 1\nf = || {\n  n = 2\n}\nf()\nn"], value("2")),
+        (&["[a, {b: b}] = [1, {b: 2}]\n[a, b]"], value("[1,2]")),
+    ]);
+}
+
+#[test]
+fn local_declarations() {
+    check(&[
+        (&["x = 1\n{\n  x := 2\n}\nx"], value("1")),
+        (&["x = 1\n{\n  x = 2\n}\nx"], value("2")),
+        // the right side and everything before still see the outer one
+        (&["x = 1\n{\n  a = x\n  x := x + 1\n  [a, x]\n}"], value("[1,2]")),
+        (&["x = 1\nf = || {\n  x := 5\n  x += 1\n  x\n}\n[f(), x]"], value("[6,1]")),
+        (&["x := 1\nx := x + 1\nx"], value("2")),
+    ]);
+}
+
+#[test]
+fn for_patterns() {
+    check(&[
+        (&["i = 'outer'\nfor i in 0..3 {\n  i\n}\ni"], value("outer")),
+        (&["s = 0\nfor [a, b] in [[1, 2], [3, 4]] s += a * b\ns"], value("14")),
+        (&["s = []\nfor {k: k, v: [v, ..rest]} in [{k: 1, v: [2, 3]}] s.push([k, v, rest])\ns"], value("[[1,2,[3]]]")),
+        (&["for [a, b] in [[1, 2]] 1\na"], Outcome::ParseError(ErrorCode::UnknownVariable)),
+        (&["for i in 0..3 {\n  c = i\n}\nc"], Outcome::ParseError(ErrorCode::UnknownVariable)),
+    ]);
+}
+
+#[test]
+fn closures() {
+    check(&[
+        (&["f = |x| {\n  g = || x = x + 1\n  g()\n  g()\n  x\n}\nf(1)"], value("3")),
+        (&["make = || {\n  n = 0\n  || {\n    n += 1\n    n\n  }\n}\nc = make()\nc()\n[c(), make()()]"], value("[2,1]")),
+        (&["fs = []\nfor i in 0..3 fs.push(|| i)\n[fs[0](), fs[2]()]"], value("[0,2]")),
+        (&["a = 1\nf = |a| a * 2\n[f(5), a]"], value("[10,1]")),
+        (&["f = |a, ..rest| [a, rest]\nf(1, 2, 3)"], value("[1,[2,3]]")),
+    ]);
+}
+
+#[test]
+fn unknown_variables() {
+    check(&[
+        (&["nope"], Outcome::ParseError(ErrorCode::UnknownVariable)),
+        (&["if False {\n  nope\n}"], Outcome::ParseError(ErrorCode::UnknownVariable)),
+        (&["f = || nope"], Outcome::ParseError(ErrorCode::UnknownVariable)),
+        (&["f = |x| x\nx"], Outcome::ParseError(ErrorCode::UnknownVariable)),
+        (&["nope = 1", "f = || nope", "f()"], value("1")),
+    ]);
+}
+
+#[test]
+fn globals_across_evals() {
+    check(&[
+        (&["x = 1", "f = || x", "x = 2", "f()"], value("2")),
+        (&["n = 0", "inc = || {\n  n += 1\n}", "inc()\ninc()\nn"], value("2")),
+        // `y` is local to `set_y`, as there was no global `y` when it was resolved
+        (&["set_y = || {\n  y = 5\n}", "y = 0\nset_y()\ny"], value("0")),
+    ]);
+}
@@ -0,0 +1,170 @@
+//! Round trips through `Interpreter::serialize` and `deserialize`, also
+//! by way of a script.
+#![cfg(feature = "serde")]
+
+use std::collections::BTreeMap;
+
+use bluebat::Interpreter;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Player {
+    name: String,
+    level: u32,
+    score: f64,
+    alive: bool,
+    tags: Vec<String>,
+    position: (i32, i32),
+    guild: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Shape {
+    Empty,
+    Circle(f64),
+    Line(i64, i64),
+    Rect { width: u16, height: u16 },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Unit;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Meters(f64);
+
+fn player() -> Player {
+    Player {
+        name: "Ada".to_string(),
+        level: 7,
+        score: 12.5,
+        alive: true,
+        tags: vec!["mage".to_string(), "healer".to_string()],
+        position: (-3, 4),
+        guild: None,
+    }
+}
+
+fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> T {
+    let mut interp = Interpreter::new();
+    let converted = interp.serialize(value).unwrap();
+    interp.deserialize(&converted).unwrap()
+}
+
+/// Passes `value` through the identity function of a script, so that it
+/// comes back as a copy the script made.
+fn through_script<T: Serialize + DeserializeOwned>(value: &T) -> T {
+    let mut interp = Interpreter::new();
+    interp.eval("id = |x| x\n").unwrap();
+    let converted = interp.serialize(value).unwrap();
+    let returned = interp.call_function("id", vec![converted]).unwrap();
+    interp.deserialize(&returned).unwrap()
+}
+
+fn check<T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug>(value: T) {
+    assert_eq!(round_trip(&value), value);
+    assert_eq!(through_script(&value), value);
+}
+
+#[test]
+fn structs() {
+    check(player());
+    check(Player { guild: Some("Night Owls".to_string()), tags: Vec::new(), ..player() });
+    check(Unit);
+    check(Meters(1.5));
+}
+
+#[test]
+fn scripts_see_structs_as_dictionaries() {
+    let mut interp = Interpreter::new();
+    let value = interp.serialize(&player()).unwrap();
+    interp.set_global("p", value);
+    let seen = interp.eval("[p.name, p.level + 1, p.tags[1], p.position[0], p.guild]\n").unwrap();
+    assert_eq!(interp.display(&seen), "[Ada,8,healer,-3,Null]");
+
+    let changed = interp.eval("p.level = 8\np.guild = 'Owls'\np.tags.push('bard')\np\n").unwrap();
+    let player: Player = interp.deserialize(&changed).unwrap();
+    assert_eq!((player.level, player.guild.as_deref(), player.tags.len()), (8, Some("Owls"), 3));
+}
+
+#[test]
+fn enums() {
+    for shape in [Shape::Empty, Shape::Circle(2.0), Shape::Line(-1, 1), Shape::Rect { width: 3, height: 4 }] {
+        check(shape);
+    }
+    let mut interp = Interpreter::new();
+    let value = interp.serialize(&vec![Shape::Empty, Shape::Circle(0.5), Shape::Rect { width: 1, height: 2 }]).unwrap();
+    assert_eq!(interp.display(&value), "[Empty,{Circle: 0.5},{Rect: {height: 2, width: 1}}]");
+    let value = interp.eval("['Empty', {Line: [5, 6]}]\n").unwrap();
+    let parsed: Vec<Shape> = interp.deserialize(&value).unwrap();
+    assert_eq!(parsed, [Shape::Empty, Shape::Line(5, 6)]);
+}
+
+#[test]
+fn options() {
+    check(Some(3));
+    check(None::<i64>);
+    check(vec![Some("a".to_string()), None]);
+    check(Some(vec![1u8, 2, 3]));
+}
+
+#[test]
+fn nested_maps() {
+    let mut inner = BTreeMap::new();
+    inner.insert("x".to_string(), vec![1, 2]);
+    inner.insert("y".to_string(), Vec::new());
+    let mut outer = BTreeMap::new();
+    outer.insert("first".to_string(), inner.clone());
+    outer.insert("second".to_string(), BTreeMap::new());
+    check(outer);
+
+    let mut by_number: BTreeMap<i64, BTreeMap<String, bool>> = BTreeMap::new();
+    by_number.insert(-1, BTreeMap::from([("on".to_string(), true)]));
+    by_number.insert(10, BTreeMap::new());
+    check(by_number);
+}
+
+#[test]
+fn big_integers() {
+    check(u64::MAX);
+    check(i64::MIN);
+    check(i128::MIN);
+    check(u128::MAX);
+    check(vec![u64::MAX, 0, 1]);
+
+    let mut interp = Interpreter::new();
+    let value = interp.serialize(&u128::MAX).unwrap();
+    assert_eq!(interp.display(&value), "340282366920938463463374607431768211455");
+    let mut eval = |code: &str| interp.eval(&format!("{}\n", code)).unwrap();
+    let (negative, huge, byte, minus_one) = (eval("-(2 ^ 100)"), eval("2 ^ 200"), eval("256"), eval("-1"));
+    assert_eq!(interp.deserialize::<i128>(&negative).unwrap(), -(1i128 << 100));
+    let too_big = interp.deserialize::<u128>(&huge).unwrap_err();
+    assert!(too_big.to_string().contains("too large"), "{}", too_big);
+    assert!(interp.deserialize::<u8>(&byte).is_err());
+    assert!(interp.deserialize::<u64>(&minus_one).is_err());
+}
+
+#[test]
+fn type_mismatches() {
+    let mut interp = Interpreter::new();
+    let mut eval = |code: &str| interp.eval(&format!("{}\n", code)).unwrap();
+    let (text, number, list, record, shapes) = (
+        eval("'seven'"),
+        eval("7.5"),
+        eval("[1, 'two', 3]"),
+        eval("{name: 'Ada', level: 'high'}"),
+        eval("[{Circle: 1}, {Square: 2}]"),
+    );
+
+    assert!(interp.deserialize::<i64>(&text).is_err());
+    assert!(interp.deserialize::<String>(&number).is_err());
+    assert!(interp.deserialize::<i64>(&number).is_err());
+    assert!(interp.deserialize::<Player>(&list).is_err());
+
+    let err = interp.deserialize::<Vec<i64>>(&list).unwrap_err();
+    assert!(err.to_string().ends_with(" at [1]"), "{}", err);
+    let err = interp.deserialize::<Player>(&record).unwrap_err();
+    assert!(err.to_string().contains("level"), "{}", err);
+    let err = interp.deserialize::<Vec<Shape>>(&shapes).unwrap_err();
+    assert!(err.to_string().contains("Square"), "{}", err);
+}
@@ -0,0 +1,58 @@
+//! Tracebacks keep track of the frames that tail calls replaced.
+
+use std::process::Command;
+
+use bluebat::{Backend, Error, Interpreter};
+
+
+/// `h` calls `f`, which tail-calls `g`, which fails.
+const CODE: &str = "g = || 1 + Null\nf = || g()\nh = || {\n  x = f()\n  x\n}\nh()\n";
+
+const TRACEBACK: &str = "\
+traceback (most recent call last):
+  in h called at <eval>:7:1
+      h()
+  [1 frame(s) replaced by tail calls]
+  in g called at <eval>:2:8
+      f = || g()
+";
+
+fn traceback(rendered: &str) -> &str {
+    &rendered[rendered.find("traceback").expect(rendered)..]
+}
+
+#[test]
+fn eval_counts_tail_calls() {
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut interp = Interpreter::new();
+        interp.set_backend(backend);
+        let err = match interp.eval(CODE) {
+            Err(Error::Script(err)) => err,
+            other => panic!("{:?} didn't fail in the script: {:?}", backend, other.map(|_| ())),
+        };
+        assert_eq!(err.error.diagnostic().traceback.last().unwrap().tail_calls, 1);
+        assert_eq!(traceback(&err.render(false)), TRACEBACK, "on {:?}", backend);
+    }
+}
+
+#[test]
+fn deep_tail_recursion_is_one_frame() {
+    let code = "loop = |n| if (n == 0) 1 + Null else loop(n - 1)\nloop(10000)\n";
+    for backend in [Backend::TreeWalker, Backend::Vm] {
+        let mut interp = Interpreter::new();
+        interp.set_backend(backend);
+        let Err(Error::Script(err)) = interp.eval(code) else { panic!("{:?} didn't fail", backend) };
+        let rendered = err.render(false);
+        assert!(traceback(&rendered).contains("  [10000 frame(s) replaced by tail calls]\n  in loop called at"), "{}", rendered);
+    }
+}
+
+#[test]
+fn cli_prints_tail_calls() {
+    for args in [&["-e", CODE][..], &["--vm", "-e", CODE]] {
+        let output = Command::new(env!("CARGO_BIN_EXE_bluebat")).args(args).output().unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("  [1 frame(s) replaced by tail calls]\n  in g called at"), "{:?}: {}", args, stderr);
+    }
+}